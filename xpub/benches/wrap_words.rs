@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use xpub::html_parser::{wrap_words_in_paragraphs, LookupConfig};
+
+const PARAGRAPH: &str = "<p class=\"calibre1\">En un lugar de la <em>Mancha</em>, de cuyo nombre no \
+quiero acordarme, no ha mucho tiempo que vivía un hidalgo de los de lanza en astillero, adarga \
+antigua, <span class=\"smcap\">rocín</span> flaco y galgo corredor.</p>\n";
+
+/// Builds a single-file chapter of roughly `size` bytes, the way Project
+/// Gutenberg ships whole novels as one XHTML document.
+fn gutenberg_chapter(size: usize) -> String {
+    let mut html = String::with_capacity(size + 256);
+    html.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    html.push_str("<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>Chapter</title></head><body>\n");
+    while html.len() < size {
+        html.push_str(PARAGRAPH);
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn bench_wrap_words(c: &mut Criterion) {
+    let config = LookupConfig::new("es");
+    let mut group = c.benchmark_group("wrap_words_in_paragraphs");
+    group.sample_size(10);
+
+    // Throughput should stay flat as the chapter grows if the rewrite is linear.
+    for megabytes in [1, 2, 4, 8] {
+        let chapter = gutenberg_chapter(megabytes * 1024 * 1024);
+        group.throughput(Throughput::Bytes(chapter.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{megabytes}MB")), &chapter, |b, html| {
+            b.iter(|| wrap_words_in_paragraphs(html, &config).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_wrap_words);
+criterion_main!(benches);
@@ -0,0 +1,520 @@
+//! Lining up an existing audiobook with the text, so the narrator's recording
+//! plays as media overlays.
+//!
+//! Timings come from a transcript of each audio file, either a JSON file made
+//! by a speech recognizer or, with the `whisper` feature, one made here with
+//! whisper.cpp. Transcripts never match the book word for word, so the words
+//! are matched in order, first on phrases that occur only once in both, then
+//! word by word in between. Sentences without any matched word get the time
+//! between their neighbours, or are left out.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::epub::EpubDocument;
+use crate::error::Error;
+use crate::media_overlay::{self, Clip, Overlay};
+use crate::package::{self, ManifestItem};
+use crate::transform::{Document, Transform};
+
+/// Number of words in the phrases used as anchors.
+const ANCHOR_LENGTH: usize = 3;
+
+/// Largest stretch between anchors that is matched word by word, in table cells.
+const MAX_GAP_CELLS: usize = 4_000_000;
+
+/// A word heard in the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedWord {
+    pub text: String,
+    /// Start and end in seconds
+    pub start: f64,
+    pub end: f64,
+}
+
+/// An audio file of the narration and what is said in it.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub path: PathBuf,
+    pub words: Vec<TimedWord>,
+}
+
+impl Recording {
+    /// Reads the transcript of `path` from a JSON file.
+    pub fn from_transcript(path: impl Into<PathBuf>, transcript: impl AsRef<Path>) -> Result<Recording, Error> {
+        let transcript = transcript.as_ref();
+        let json = std::fs::read_to_string(transcript)?;
+        let words = parse_transcript(&json).map_err(|message| Error::Malformed {
+            entry: transcript.display().to_string(),
+            message,
+        })?;
+        Ok(Recording { path: path.into(), words })
+    }
+
+    /// Transcribes `path` with a whisper.cpp model.
+    #[cfg(feature = "whisper")]
+    pub fn transcribe(path: impl Into<PathBuf>, model: impl AsRef<Path>, language: &str) -> Result<Recording, Error> {
+        let path = path.into();
+        let words = transcribe(&path, model.as_ref(), language)?;
+        Ok(Recording { path, words })
+    }
+}
+
+/// Reads a transcript in one of the shapes speech recognizers write:
+///
+/// - faster-whisper segment lists, `[[id, seek, start, end, text, ...], ...]`
+/// - lists of `{"start", "end", "text"}` or `{"start", "end", "word"}` objects
+/// - whisper's JSON output, `{"segments": [...]}`, using the word timings of
+///   each segment when it has them
+///
+/// Segments of several words have their time shared out by word length.
+pub fn parse_transcript(json: &str) -> Result<Vec<TimedWord>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let segments = match &value {
+        Value::Array(segments) => segments,
+        Value::Object(fields) => match fields.get("segments").or_else(|| fields.get("words")) {
+            Some(Value::Array(segments)) => segments,
+            _ => return Err("expected a list of segments".to_string()),
+        },
+        _ => return Err("expected a list of segments".to_string()),
+    };
+
+    let mut words = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        add_segment(segment, &mut words).ok_or_else(|| format!("segment {} has no start, end and text", i))?;
+    }
+    Ok(words)
+}
+
+fn add_segment(segment: &Value, words: &mut Vec<TimedWord>) -> Option<()> {
+    match segment {
+        Value::Array(fields) => {
+            spread_words(fields.get(4)?.as_str()?, fields.get(2)?.as_f64()?, fields.get(3)?.as_f64()?, words);
+        }
+        Value::Object(fields) => {
+            if let Some(Value::Array(segment_words)) = fields.get("words") {
+                if !segment_words.is_empty() {
+                    return segment_words.iter().try_for_each(|word| add_segment(word, words));
+                }
+            }
+            let text = fields.get("text").or_else(|| fields.get("word"))?.as_str()?;
+            spread_words(text, fields.get("start")?.as_f64()?, fields.get("end")?.as_f64()?, words);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Splits the text of a segment into words, giving each a share of the
+/// segment's time in proportion to its length.
+fn spread_words(text: &str, start: f64, end: f64, words: &mut Vec<TimedWord>) {
+    let total: usize = text.split_whitespace().map(|word| word.chars().count()).sum();
+    let mut position = 0;
+    for word in text.split_whitespace() {
+        let length = word.chars().count();
+        let at = |position: usize| start + (end - start) * position as f64 / total as f64;
+        words.push(TimedWord {
+            text: word.to_string(),
+            start: at(position),
+            end: at(position + length),
+        });
+        position += length;
+    }
+}
+
+/// Transcribes an audio file with word timestamps. The audio is converted to
+/// 16 kHz mono with ffmpeg first, as whisper expects.
+#[cfg(feature = "whisper")]
+pub fn transcribe(audio: &Path, model: &Path, language: &str) -> Result<Vec<TimedWord>, Error> {
+    use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+    let whisper_error = |err: whisper_rs::WhisperError| Error::Command {
+        command: "whisper".to_string(),
+        message: err.to_string(),
+    };
+
+    let wav = crate::audio::decode_for_speech_recognition(audio)?;
+    let wav = crate::audio::Wav::parse(&wav).ok_or_else(|| Error::Command {
+        command: "ffmpeg".to_string(),
+        message: format!("did not produce a WAV file for {}", audio.display()),
+    })?;
+    let samples: Vec<i16> = wav.data.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect();
+    let mut samples_f32 = vec![0.0; samples.len()];
+    whisper_rs::convert_integer_to_float_audio(&samples, &mut samples_f32).map_err(whisper_error)?;
+
+    let context = WhisperContext::new_with_params(&model.to_string_lossy(), WhisperContextParameters::default())
+        .map_err(whisper_error)?;
+    let mut state = context.create_state().map_err(whisper_error)?;
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(Some(language));
+    // One segment per word, each with its own timestamps
+    params.set_token_timestamps(true);
+    params.set_max_len(1);
+    params.set_split_on_word(true);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_special(false);
+    params.set_print_timestamps(false);
+    state.full(params, &samples_f32).map_err(whisper_error)?;
+
+    let mut words = Vec::new();
+    for segment in 0..state.full_n_segments().map_err(whisper_error)? {
+        let text = String::from_utf8_lossy(&state.full_get_segment_bytes(segment).map_err(whisper_error)?).into_owned();
+        // Timestamps are in hundredths of a second
+        let start = state.full_get_segment_t0(segment).map_err(whisper_error)? as f64 / 100.0;
+        let end = state.full_get_segment_t1(segment).map_err(whisper_error)? as f64 / 100.0;
+        spread_words(&text, start, end, &mut words);
+    }
+    Ok(words)
+}
+
+/// Lowercased letters and digits of a word, so punctuation and case don't
+/// stop the book and the transcript from matching.
+fn normalize(word: &str) -> String {
+    word.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// For every word of the book, the index of the transcript word it was
+/// matched with, if any. Matches are in order in both.
+pub fn match_words(book: &[String], heard: &[String]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; book.len()];
+    let (mut i, mut j) = (0, 0);
+    for (anchor_i, anchor_j) in anchors(book, heard).into_iter().chain([(book.len(), heard.len())]) {
+        match_gap(&book[i..anchor_i], &heard[j..anchor_j], &mut matches[i..anchor_i], j);
+        if anchor_i < book.len() {
+            matches[anchor_i] = Some(anchor_j);
+        }
+        i = anchor_i + 1;
+        j = anchor_j + 1;
+    }
+    matches
+}
+
+/// Word pairs inside phrases that occur exactly once in both the book and the
+/// transcript, keeping the longest run of them that is in order in both.
+fn anchors(book: &[String], heard: &[String]) -> Vec<(usize, usize)> {
+    fn unique_phrases(words: &[String]) -> HashMap<&[String], Option<usize>> {
+        let mut phrases: HashMap<&[String], Option<usize>> = HashMap::new();
+        for (i, phrase) in words.windows(ANCHOR_LENGTH).enumerate() {
+            phrases.entry(phrase).and_modify(|found| *found = None).or_insert(Some(i));
+        }
+        phrases
+    }
+
+    let heard_phrases = unique_phrases(heard);
+    let mut pairs: Vec<(usize, usize)> = unique_phrases(book).into_iter()
+        .filter_map(|(phrase, i)| Some((i?, heard_phrases.get(phrase).copied()??)))
+        .flat_map(|(i, j)| (0..ANCHOR_LENGTH).map(move |k| (i + k, j + k)))
+        .collect();
+    // Highest j first within the same i, so a strictly increasing run takes at most one of them
+    pairs.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    pairs.dedup();
+
+    longest_increasing_run(&pairs)
+}
+
+/// Longest subsequence of `pairs` whose second elements strictly increase.
+fn longest_increasing_run(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // tails[k] is the index of the pair ending the best run of length k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![None; pairs.len()];
+    for (index, &(_, j)) in pairs.iter().enumerate() {
+        let length = tails.partition_point(|&tail| pairs[tail].1 < j);
+        if length > 0 {
+            previous[index] = Some(tails[length - 1]);
+        }
+        if length == tails.len() {
+            tails.push(index);
+        } else {
+            tails[length] = index;
+        }
+    }
+
+    let mut run = Vec::with_capacity(tails.len());
+    let mut index = tails.last().copied();
+    while let Some(i) = index {
+        run.push(pairs[i]);
+        index = previous[i];
+    }
+    run.reverse();
+    run
+}
+
+/// Matches the words between two anchors with a longest common subsequence.
+/// Gaps too big to compare word by word are left unmatched.
+fn match_gap(book: &[String], heard: &[String], matches: &mut [Option<usize>], offset: usize) {
+    if book.is_empty() || heard.is_empty() || (book.len() + 1) * (heard.len() + 1) > MAX_GAP_CELLS {
+        return;
+    }
+
+    // lengths[i][j] is the longest common subsequence of book[i..] and heard[j..]
+    let width = heard.len() + 1;
+    let mut lengths = vec![0u32; (book.len() + 1) * width];
+    for i in (0..book.len()).rev() {
+        for j in (0..heard.len()).rev() {
+            lengths[i * width + j] = if book[i] == heard[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < book.len() && j < heard.len() {
+        if book[i] == heard[j] {
+            matches[i] = Some(offset + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+}
+
+/// Sentences of one content document, waiting for the alignment.
+struct PendingDocument {
+    item_id: String,
+    name: String,
+    /// (sentence id, text)
+    sentences: Vec<(String, String)>,
+}
+
+/// Where a sentence is heard: (recording, begin, end).
+type Timing = (usize, f64, f64);
+
+/// Aligns the sentences of the book with the recordings of an audiobook and
+/// adds the recordings as media overlays.
+///
+/// Needs sentence spans, so it has to run after [`crate::WrapWords`] with
+/// `mark_sentences` switched on.
+pub struct AudiobookOverlays {
+    recordings: Vec<Recording>,
+    documents: Vec<PendingDocument>,
+}
+
+impl AudiobookOverlays {
+    /// `recordings` are the audio files in reading order.
+    pub fn new(recordings: Vec<Recording>) -> Self {
+        AudiobookOverlays {
+            recordings,
+            documents: Vec::new(),
+        }
+    }
+
+    /// Times of every sentence of every document, in the same order.
+    fn align(&self) -> Vec<Vec<Option<Timing>>> {
+        let heard: Vec<(usize, &TimedWord)> = self.recordings.iter()
+            .enumerate()
+            .flat_map(|(recording, words)| words.words.iter().map(move |word| (recording, word)))
+            .filter(|(_, word)| !normalize(&word.text).is_empty())
+            .collect();
+        let heard_words: Vec<String> = heard.iter().map(|(_, word)| normalize(&word.text)).collect();
+
+        // Every word of the book with the (document, sentence) it is in
+        let mut book_words = Vec::new();
+        let mut owners = Vec::new();
+        for (d, document) in self.documents.iter().enumerate() {
+            for (s, (_, text)) in document.sentences.iter().enumerate() {
+                for word in text.split_whitespace().map(normalize).filter(|word| !word.is_empty()) {
+                    book_words.push(word);
+                    owners.push((d, s));
+                }
+            }
+        }
+
+        let mut timings: Vec<Vec<Option<Timing>>> = self.documents.iter()
+            .map(|document| vec![None; document.sentences.len()])
+            .collect();
+        for (k, matched) in match_words(&book_words, &heard_words).into_iter().enumerate() {
+            let Some((recording, word)) = matched.map(|j| heard[j]) else {
+                continue;
+            };
+            let (d, s) = owners[k];
+            match &mut timings[d][s] {
+                Some((first, _, end)) if *first == recording => *end = end.max(word.end),
+                Some(_) => {}
+                timing @ None => *timing = Some((recording, word.start, word.end)),
+            }
+        }
+
+        fill_gaps(&mut timings);
+        timings
+    }
+}
+
+/// Keeps clips from overlapping, and gives sentences that were not heard the
+/// time between their neighbours when both are in the same recording.
+fn fill_gaps(timings: &mut [Vec<Option<Timing>>]) {
+    let mut flat: Vec<&mut Option<Timing>> = timings.iter_mut().flatten().collect();
+
+    let mut last: Option<(usize, f64)> = None;
+    for timing in flat.iter_mut() {
+        if let (Some((recording, begin, end)), Some((last_recording, last_end))) = (timing.as_mut(), last) {
+            if *recording == last_recording && *begin < last_end {
+                *begin = last_end;
+            }
+            if *end <= *begin {
+                **timing = None;
+            }
+        }
+        if let Some((recording, _, end)) = **timing {
+            last = Some((recording, end));
+        }
+    }
+
+    let mut previous: Option<(usize, f64)> = None;
+    let mut i = 0;
+    while i < flat.len() {
+        if let Some((recording, _, end)) = *flat[i] {
+            previous = Some((recording, end));
+            i += 1;
+            continue;
+        }
+
+        let count = flat[i..].iter().take_while(|timing| timing.is_none()).count();
+        let next = flat.get(i + count).and_then(|timing| **timing);
+        if let (Some((recording, previous_end)), Some((next_recording, next_begin, _))) = (previous, next) {
+            if recording == next_recording && next_begin > previous_end {
+                // Unheard sentences in a row share the gap equally
+                let step = (next_begin - previous_end) / count as f64;
+                for (k, timing) in flat[i..i + count].iter_mut().enumerate() {
+                    let begin = previous_end + step * k as f64;
+                    **timing = Some((recording, begin, begin + step));
+                }
+            }
+        }
+        i += count;
+    }
+}
+
+impl Transform for AudiobookOverlays {
+    fn begin(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        media_overlay::require_epub3(book)?;
+        for recording in &self.recordings {
+            let path = recording.path.to_string_lossy();
+            if media_overlay::audio_media_type(&path).is_none() {
+                return Err(Error::Malformed {
+                    entry: path.into_owned(),
+                    message: "not a known audio format".to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn transform(&mut self, _book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        let sentences = media_overlay::sentences(&document.content);
+        if sentences.is_empty() {
+            return Ok(());
+        }
+        self.documents.push(PendingDocument {
+            item_id: document.item.id.clone(),
+            name: document.name.clone(),
+            sentences,
+        });
+        media_overlay::highlight_active_sentence(&mut document.content);
+        Ok(())
+    }
+
+    fn finish(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        let timings = self.align();
+
+        let mut audio_paths = Vec::with_capacity(self.recordings.len());
+        for (i, recording) in self.recordings.iter().enumerate() {
+            let path = recording.path.to_string_lossy();
+            let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension).to_ascii_lowercase();
+            let href = format!("xpub/audio/track-{}.{}", i + 1, extension);
+            audio_paths.push(package::resolve_href(book.opf_path(), &href));
+
+            let id = book.package().unused_id(&format!("audiobook-{}", i + 1));
+            let media_type = media_overlay::audio_media_type(&path).unwrap_or("audio/mpeg");
+            book.add_resource_file(ManifestItem::new(&id, href, media_type), &recording.path)?;
+        }
+
+        let overlays: Vec<Overlay> = self.documents.iter()
+            .zip(timings)
+            .map(|(document, timings)| Overlay {
+                item_id: document.item_id.clone(),
+                document: document.name.clone(),
+                clips: document.sentences.iter()
+                    .zip(timings)
+                    .filter_map(|((sentence_id, _), timing)| {
+                        let (recording, begin, end) = timing?;
+                        Some(Clip {
+                            sentence_id: sentence_id.clone(),
+                            audio: audio_paths[recording].clone(),
+                            begin,
+                            end,
+                        })
+                    })
+                    .collect(),
+            })
+            .filter(|overlay| !overlay.clips.is_empty())
+            .collect();
+        media_overlay::add_overlays(book, &overlays)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(normalize).collect()
+    }
+
+    #[test]
+    fn matches_words_in_order() {
+        let book = words("It was a bright cold day in April, and the clocks were striking thirteen.");
+        let heard = words("it was a bright cold day in april and uh the clocks were striking thirteen");
+        let matches = match_words(&book, &heard);
+        assert_eq!(matches.len(), book.len());
+        // "uh" is skipped
+        assert_eq!(&matches[..9], &[Some(0), Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7), Some(8)]);
+        assert_eq!(&matches[9..], &[Some(10), Some(11), Some(12), Some(13), Some(14)]);
+    }
+
+    #[test]
+    fn leaves_unheard_words_unmatched() {
+        let book = words("one two three four five six seven");
+        let heard = words("one two three six seven");
+        assert_eq!(match_words(&book, &heard), [Some(0), Some(1), Some(2), None, None, Some(3), Some(4)]);
+        assert_eq!(match_words(&book, &[]), vec![None; 7]);
+    }
+
+    #[test]
+    fn repeated_phrases_keep_the_order() {
+        // "the end" occurs twice, so only the words around it anchor the match
+        let book = words("the end of the day is not the end");
+        let heard = words("the end of the day is not the end");
+        let matches = match_words(&book, &heard);
+        assert_eq!(matches, (0..book.len()).map(Some).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parses_transcript_shapes() {
+        let faster_whisper = r#"[[0, 0, 1.0, 2.0, " Hello there", []]]"#;
+        let objects = r#"[{"start": 1.0, "end": 2.0, "text": "Hello there"}]"#;
+        let whisper = r#"{"segments": [{"start": 0.0, "end": 9.0, "text": "ignored", "words": [
+            {"start": 1.0, "end": 1.5, "word": "Hello"}, {"start": 1.5, "end": 2.0, "word": "there"}]}]}"#;
+        for json in [faster_whisper, objects] {
+            let words = parse_transcript(json).unwrap();
+            assert_eq!(words.iter().map(|word| word.text.as_str()).collect::<Vec<_>>(), ["Hello", "there"]);
+            // Time is shared out by length: "Hello" has 5 of the 10 letters
+            assert_eq!((words[0].start, words[0].end, words[1].end), (1.0, 1.5, 2.0));
+        }
+        let words = parse_transcript(whisper).unwrap();
+        assert_eq!(words[1], TimedWord { text: "there".to_string(), start: 1.5, end: 2.0 });
+    }
+
+    #[test]
+    fn rejects_other_json() {
+        assert!(parse_transcript("{}").is_err());
+        assert!(parse_transcript(r#"[{"text": "no times"}]"#).is_err());
+        assert!(parse_transcript("not json").is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! Anki decks of a book's vocabulary.
+//!
+//! Words are counted over the paragraphs of the book and ranked by how often
+//! they occur, leaving out words the reader already knows if a list of them
+//! is given. Each word gets an example sentence from the book, a gloss from a
+//! [`GlossProvider`] and optionally recordings, and the notes are written as
+//! an `.apkg`: a zip of an SQLite Anki collection and its media.
+
+use rusqlite::{params, Connection};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::{SimpleFileOptions, ZipWriter};
+
+use crate::audio::TempFile;
+use crate::epub::EpubDocument;
+use crate::error::{Error, ParseError};
+use crate::html_parser;
+use crate::lookups::fnv1a;
+use crate::tokenizer;
+
+/// Sentences with fewer or more words than this are only used as examples
+/// if there is nothing better.
+const EXAMPLE_WORDS: std::ops::RangeInclusive<usize> = 4..=30;
+
+/// Example sentence candidates kept per word.
+const MAX_CANDIDATES: usize = 20;
+
+/// Id of the note type, the same in every deck so reimports update notes.
+const MODEL_ID: i64 = 1_683_901_217_204;
+
+const FIELDS: [&str; 5] = ["Word", "Sentence", "Gloss", "Audio", "SentenceAudio"];
+
+const CSS: &str = ".card { font-family: sans-serif; font-size: 24px; text-align: center; }\n\
+    .sentence { font-size: 18px; margin-top: 1em; }\n\
+    .gloss { color: #555; }\n";
+
+/// How the vocabulary is ranked.
+#[derive(Debug, Clone)]
+pub enum Ranking {
+    /// Most frequent words first
+    Frequency,
+    /// Most frequent words that are not in the known list first
+    Unknown(HashSet<String>),
+}
+
+impl Ranking {
+    fn is_known(&self, word: &str) -> bool {
+        match self {
+            Ranking::Frequency => false,
+            Ranking::Unknown(known) => known.contains(word),
+        }
+    }
+}
+
+/// Reads a list of known words, one per line. Anything after a tab is
+/// ignored, so exports from Anki and other tools work as they are.
+pub fn read_known_words(path: impl AsRef<Path>) -> Result<HashSet<String>, Error> {
+    let source = std::fs::read_to_string(path)?;
+    Ok(source.lines()
+        .map(|line| tokenizer::normalize_word(line.split('\t').next().unwrap_or_default()))
+        .filter(|word| !word.is_empty())
+        .collect())
+}
+
+/// A word of the book with an example of it in use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VocabularyWord {
+    /// Lowercased, without surrounding punctuation
+    pub word: String,
+    pub count: usize,
+    /// Plain text of the example sentence
+    pub sentence: String,
+    /// Plain text of the sentence the word first appears in
+    pub first_sentence: String,
+    /// Content document the word first appears in
+    pub document: String,
+}
+
+/// How often a word occurs and where.
+struct Tally {
+    count: usize,
+    first_seen: usize,
+    /// Indexes of sentences the word is in
+    sentences: Vec<usize>,
+}
+
+/// The `limit` highest ranked words of the book, in rank order.
+pub fn vocabulary(book: &mut EpubDocument, ranking: &Ranking, limit: usize) -> Result<Vec<VocabularyWord>, Error> {
+    let mut sentences: Vec<Vec<String>> = Vec::new();
+    let mut texts: Vec<String> = Vec::new();
+    // Index into `documents` of each sentence
+    let mut sentence_documents: Vec<usize> = Vec::new();
+    let mut documents: Vec<String> = Vec::new();
+    let mut words: HashMap<String, Tally> = HashMap::new();
+
+    // Words of the table of contents are all in the chapters too
+    for item in book.content_documents().into_iter().filter(|item| !item.has_property("nav")) {
+        let name = book.item_path(&item);
+        let html = book.read_content_document(&name)?;
+        documents.push(name);
+        for paragraph in html_parser::paragraphs(&html) {
+            let tokens = tokenizer::tokenize(paragraph);
+            for range in tokenizer::sentences(&tokens) {
+                let sentence_words: Vec<String> = tokens[range.clone()].iter()
+                    .filter(|token| token.is_word())
+                    .map(|token| tokenizer::normalize_word(&tokenizer::text_content(token.as_str())))
+                    .filter(|word| is_vocabulary(word))
+                    .collect();
+                let index = sentences.len();
+                for word in &sentence_words {
+                    let seen = words.len();
+                    let tally = words.entry(word.clone())
+                        .or_insert(Tally { count: 0, first_seen: seen, sentences: Vec::new() });
+                    tally.count += 1;
+                    if tally.sentences.len() < MAX_CANDIDATES && tally.sentences.last() != Some(&index) {
+                        tally.sentences.push(index);
+                    }
+                }
+                let text: String = tokens[range].iter().map(|token| token.as_str()).collect();
+                texts.push(tokenizer::text_content(&text).split_whitespace().collect::<Vec<_>>().join(" "));
+                sentences.push(sentence_words);
+                sentence_documents.push(documents.len() - 1);
+            }
+        }
+    }
+
+    let mut ranked: Vec<(&String, &Tally)> = words.iter()
+        .filter(|(word, _)| !ranking.is_known(word))
+        .collect();
+    ranked.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.1.first_seen.cmp(&b.1.first_seen)));
+    ranked.truncate(limit);
+
+    // The best example has few other words the reader may not know
+    let unfamiliar = |word: &String| match ranking {
+        Ranking::Frequency => words.get(word).is_some_and(|tally| tally.count == 1),
+        Ranking::Unknown(known) => !known.contains(word),
+    };
+    Ok(ranked.into_iter()
+        .map(|(word, tally)| {
+            let best = tally.sentences.iter().copied().min_by_key(|&i| {
+                let length = sentences[i].len();
+                let unfamiliar_words = sentences[i].iter().filter(|other| *other != word && unfamiliar(other)).count();
+                (!EXAMPLE_WORDS.contains(&length), unfamiliar_words, length)
+            });
+            // The first sentence a word is in is always kept as a candidate
+            let first = tally.sentences[0];
+            VocabularyWord {
+                word: word.clone(),
+                count: tally.count,
+                sentence: best.map(|i| texts[i].clone()).unwrap_or_default(),
+                first_sentence: texts[first].clone(),
+                document: documents[sentence_documents[first]].clone(),
+            }
+        })
+        .collect())
+}
+
+/// Words worth learning have at least one letter.
+fn is_vocabulary(word: &str) -> bool {
+    word.chars().any(char::is_alphabetic)
+}
+
+/// The sentence as HTML with every occurrence of `word` in bold.
+pub fn highlight(sentence: &str, word: &str) -> String {
+    let mut html = String::with_capacity(sentence.len() + 16);
+    for (i, piece) in sentence.split(' ').enumerate() {
+        if i > 0 {
+            html.push(' ');
+        }
+        let core = tokenizer::trim_punctuation(piece);
+        if !core.is_empty() && core.to_lowercase() == word {
+            let start = piece.len() - piece.trim_start_matches(tokenizer::PUNCTUATION).len();
+            html.push_str(&escape_html(&piece[..start]));
+            html.push_str(&format!("<b>{}</b>", escape_html(core)));
+            html.push_str(&escape_html(&piece[start + core.len()..]));
+        } else {
+            html.push_str(&escape_html(piece));
+        }
+    }
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Something that can say what a word means.
+pub trait GlossProvider {
+    fn gloss(&mut self, word: &str) -> Result<Option<String>, Error>;
+}
+
+/// Glosses from a tab separated `word<TAB>gloss` file.
+#[derive(Debug, Clone, Default)]
+pub struct TsvGlossary {
+    entries: HashMap<String, String>,
+}
+
+impl TsvGlossary {
+    pub fn open(path: impl AsRef<Path>) -> Result<TsvGlossary, Error> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        TsvGlossary::parse(&source).map_err(|source| Error::Parse {
+            entry: path.display().to_string(),
+            source,
+        })
+    }
+
+    pub fn parse(source: &str) -> Result<TsvGlossary, ParseError> {
+        let mut entries = HashMap::new();
+        let mut offset = 0;
+        for line in source.split_inclusive('\n') {
+            let entry = line.trim_end_matches(['\r', '\n']);
+            if !entry.trim().is_empty() && !entry.starts_with('#') {
+                let (word, gloss) = entry.split_once('\t')
+                    .ok_or_else(|| ParseError::new(offset, "expected word<TAB>gloss"))?;
+                entries.entry(tokenizer::normalize_word(word)).or_insert_with(|| gloss.trim().to_string());
+            }
+            offset += line.len();
+        }
+        Ok(TsvGlossary { entries })
+    }
+}
+
+impl GlossProvider for TsvGlossary {
+    fn gloss(&mut self, word: &str) -> Result<Option<String>, Error> {
+        Ok(self.entries.get(&tokenizer::normalize_word(word)).cloned())
+    }
+}
+
+/// Fields of one note. Audio fields hold `[sound:...]` references to media
+/// added with [`Deck::add_media`], or nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Note {
+    pub word: String,
+    /// HTML
+    pub sentence: String,
+    pub gloss: String,
+    pub audio: String,
+    pub sentence_audio: String,
+}
+
+/// A deck waiting to be written as an `.apkg`.
+#[derive(Debug, Clone)]
+pub struct Deck {
+    pub name: String,
+    /// Language of the words, used to tell notes of different decks apart
+    pub language: String,
+    notes: Vec<Note>,
+    /// (file name, contents)
+    media: Vec<(String, Vec<u8>)>,
+}
+
+impl Deck {
+    pub fn new(name: impl Into<String>, language: impl Into<String>) -> Self {
+        Deck {
+            name: name.into(),
+            language: language.into(),
+            notes: Vec::new(),
+            media: Vec::new(),
+        }
+    }
+
+    pub fn add_note(&mut self, note: Note) {
+        self.notes.push(note);
+    }
+
+    /// Adds a media file and returns the `[sound:...]` reference to put in a field.
+    pub fn add_media(&mut self, data: Vec<u8>, extension: &str) -> String {
+        let name = format!("xpub-{:016x}.{}", fnv1a(&[&self.name, &data.len().to_string(), &self.media.len().to_string()]), extension);
+        self.media.push((name.clone(), data));
+        format!("[sound:{}]", name)
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    /// Writes the deck as an Anki package.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let collection = TempFile::new("anki2");
+        self.write_collection(&collection.path)?;
+
+        let mut zip_writer = ZipWriter::new(File::create(path)?);
+        let options = SimpleFileOptions::default();
+        zip_writer.start_file("collection.anki2", options)?;
+        zip_writer.write_all(&std::fs::read(&collection.path)?)?;
+
+        let media_map: serde_json::Map<String, serde_json::Value> = self.media.iter()
+            .enumerate()
+            .map(|(i, (name, _))| (i.to_string(), json!(name)))
+            .collect();
+        zip_writer.start_file("media", options)?;
+        zip_writer.write_all(serde_json::Value::Object(media_map).to_string().as_bytes())?;
+        for (i, (_, data)) in self.media.iter().enumerate() {
+            zip_writer.start_file(i.to_string(), options)?;
+            zip_writer.write_all(data)?;
+        }
+
+        zip_writer.finish()?;
+        Ok(())
+    }
+
+    fn write_collection(&self, path: &Path) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (seconds, millis) = (now.as_secs() as i64, now.as_millis() as i64);
+        let deck_id = (fnv1a(&[&self.name]) >> 12) as i64;
+
+        let mut connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        connection.execute(
+            "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+            params![
+                seconds,
+                millis,
+                collection_config().to_string(),
+                self.models(deck_id, seconds).to_string(),
+                self.decks(deck_id, seconds).to_string(),
+                deck_config().to_string(),
+            ],
+        )?;
+
+        let transaction = connection.transaction()?;
+        for (i, note) in self.notes.iter().enumerate() {
+            let id = millis + i as i64;
+            let fields = [&note.word, &note.sentence, &note.gloss, &note.audio, &note.sentence_audio];
+            transaction.execute(
+                "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ' xpub ', ?5, ?6, ?7, 0, '')",
+                params![
+                    id,
+                    format!("{:016x}", fnv1a(&[&self.language, &note.word])),
+                    MODEL_ID,
+                    seconds,
+                    fields.map(String::as_str).join("\x1f"),
+                    note.word,
+                    checksum(&note.word),
+                ],
+            )?;
+            transaction.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![id, id, deck_id, seconds, i as i64 + 1],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn models(&self, deck_id: i64, modified: i64) -> serde_json::Value {
+        let fields: Vec<serde_json::Value> = FIELDS.iter()
+            .enumerate()
+            .map(|(i, name)| json!({
+                "name": name, "ord": i, "sticky": false, "rtl": false,
+                "font": "Arial", "size": 20, "media": [],
+            }))
+            .collect();
+        json!({
+            MODEL_ID.to_string(): {
+                "id": MODEL_ID,
+                "name": "xpub vocabulary",
+                "type": 0,
+                "mod": modified,
+                "usn": -1,
+                "sortf": 0,
+                "did": deck_id,
+                "tmpls": [{
+                    "name": "Recognition",
+                    "ord": 0,
+                    "qfmt": "<div class=\"word\">{{Word}}</div>{{Audio}}",
+                    "afmt": "{{FrontSide}}<hr id=\"answer\"><div class=\"gloss\">{{Gloss}}</div>\
+                             <div class=\"sentence\">{{Sentence}}</div>{{SentenceAudio}}",
+                    "did": null,
+                    "bqfmt": "",
+                    "bafmt": "",
+                }],
+                "flds": fields,
+                "css": CSS,
+                "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\begin{document}\n",
+                "latexPost": "\\end{document}",
+                "tags": [],
+                "vers": [],
+                "req": [[0, "any", [0]]],
+            }
+        })
+    }
+
+    fn decks(&self, deck_id: i64, modified: i64) -> serde_json::Value {
+        let deck = |id: i64, name: &str| json!({
+            "id": id, "name": name, "desc": "", "mod": modified, "usn": -1, "collapsed": false,
+            "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "timeToday": [0, 0],
+            "dyn": 0, "conf": 1, "extendNew": 10, "extendRev": 50,
+        });
+        json!({
+            "1": deck(1, "Default"),
+            deck_id.to_string(): deck(deck_id, &self.name),
+        })
+    }
+}
+
+fn collection_config() -> serde_json::Value {
+    json!({
+        "activeDecks": [1], "curDeck": 1, "newSpread": 0, "collapseTime": 1200, "timeLim": 0,
+        "estTimes": true, "dueCounts": true, "curModel": null, "nextPos": 1, "sortType": "noteFld",
+        "sortBackwards": false, "addToCur": true,
+    })
+}
+
+fn deck_config() -> serde_json::Value {
+    json!({
+        "1": {
+            "id": 1, "name": "Default", "mod": 0, "usn": 0, "maxTaken": 60, "autoplay": true,
+            "timer": 0, "replayq": true, "dyn": false,
+            "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "separate": true,
+                     "order": 1, "perDay": 20, "bury": true },
+            "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 },
+            "rev": { "perDay": 100, "ease4": 1.3, "fuzz": 0.05, "minSpace": 1, "ivlFct": 1,
+                     "maxIvl": 36500, "bury": true },
+        }
+    })
+}
+
+/// First 32 bits of the SHA-1 of a note's sort field, which Anki uses to
+/// find duplicates.
+fn checksum(field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(field).digest().bytes();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) as i64
+}
+
+/// Tables of an Anki 2.1 collection, schema version 11.
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+    conf text not null, models text not null, decks text not null, dconf text not null, tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null,
+    csum integer not null, flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null,
+    due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+    type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn ranks_words_by_count() {
+        let file = fixtures::book(&[
+            "<p>El gato come. El perro come pescado y el gato duerme.</p>",
+            "<p>El gato salta.</p>",
+        ]);
+        let mut book = EpubDocument::open(&file.path).unwrap();
+        let words = vocabulary(&mut book, &Ranking::Frequency, 3).unwrap();
+        let ranked: Vec<(&str, usize)> = words.iter().map(|word| (word.word.as_str(), word.count)).collect();
+        assert_eq!(ranked, [("el", 4), ("gato", 3), ("come", 2)]);
+
+        let gato = &words[1];
+        assert_eq!(gato.first_sentence, "El gato come.");
+        assert_eq!(gato.document, "OEBPS/c1.xhtml");
+        // Three word sentences are too short to be good examples
+        assert_eq!(gato.sentence, "El perro come pescado y el gato duerme.");
+
+        // The nav document's "Chapter 1" is not vocabulary
+        let all = vocabulary(&mut book, &Ranking::Frequency, 100).unwrap();
+        assert!(all.iter().all(|word| word.word != "chapter"));
+    }
+
+    #[test]
+    fn picks_examples_with_few_unknown_words() {
+        let file = fixtures::book(&["<p>El gato come pescado rico. El perro come pescado.</p>"]);
+        let mut book = EpubDocument::open(&file.path).unwrap();
+        let known: HashSet<String> = ["el", "gato", "come", "perro"].into_iter().map(String::from).collect();
+        let words = vocabulary(&mut book, &Ranking::Unknown(known), 10).unwrap();
+        let ranked: Vec<&str> = words.iter().map(|word| word.word.as_str()).collect();
+        assert_eq!(ranked, ["pescado", "rico"]);
+        assert_eq!(words[0].first_sentence, "El gato come pescado rico.");
+        assert_eq!(words[0].sentence, "El perro come pescado.");
+    }
+
+    #[test]
+    fn highlights_words() {
+        assert_eq!(highlight("¡Hola, mundo! Hola.", "hola"), "¡<b>Hola</b>, mundo! <b>Hola</b>.");
+        assert_eq!(highlight("Holanda no", "hola"), "Holanda no");
+        assert_eq!(highlight("<gato> & \"perro\"", "perro"), "&lt;gato&gt; &amp; \"<b>perro</b>\"");
+    }
+
+    #[test]
+    fn parses_glossaries() {
+        let mut glossary = TsvGlossary::parse("# Spanish\n\nGato\tcat \r\ngato\tfeline\nperro\tdog\n").unwrap();
+        assert_eq!(glossary.gloss("gato").unwrap().as_deref(), Some("cat"));
+        assert_eq!(glossary.gloss("¡Perro!").unwrap().as_deref(), Some("dog"));
+        assert_eq!(glossary.gloss("pez").unwrap(), None);
+
+        let error = TsvGlossary::parse("gato\tcat\nperro dog\n").unwrap_err();
+        assert_eq!(error, ParseError::new(9, "expected word<TAB>gloss"));
+    }
+
+    #[test]
+    fn checksums_sort_fields() {
+        // SHA-1 of "hello" starts aaf4c61d
+        assert_eq!(checksum("hello"), 0xaaf4c61d);
+    }
+}
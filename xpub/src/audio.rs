@@ -0,0 +1,234 @@
+//! Just enough WAV handling to join synthesized sentences into one track.
+
+use std::io;
+use std::process::Command;
+
+use crate::error::Error;
+
+/// PCM audio from a WAV file.
+#[derive(Debug, Clone)]
+pub struct Wav {
+    /// Contents of the `fmt ` chunk, kept as is
+    format: Vec<u8>,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub data: Vec<u8>,
+}
+
+impl Wav {
+    /// Parses a RIFF WAVE file. Returns `None` if it is not one.
+    pub fn parse(bytes: &[u8]) -> Option<Wav> {
+        if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return None;
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut index = 12;
+        while index + 8 <= bytes.len() {
+            let id = &bytes[index..index + 4];
+            let size = u32::from_le_bytes(bytes[index + 4..index + 8].try_into().ok()?) as usize;
+            let body_start = index + 8;
+            // Streaming encoders write 0 or 0xFFFFFFFF when they don't know the
+            // size yet, the samples then run to the end of the file
+            let unknown = id == b"data" && (size == 0 || size == u32::MAX as usize);
+            let body_end = if unknown { bytes.len() } else { body_start.saturating_add(size).min(bytes.len()) };
+            match id {
+                b"fmt " => format = Some(bytes[body_start..body_end].to_vec()),
+                b"data" => data = Some(bytes[body_start..body_end].to_vec()),
+                _ => {}
+            }
+            index = body_end + (size & 1);
+        }
+
+        let format = format.filter(|format| format.len() >= 16)?;
+        Some(Wav {
+            channels: u16::from_le_bytes([format[2], format[3]]),
+            sample_rate: u32::from_le_bytes([format[4], format[5], format[6], format[7]]),
+            bits_per_sample: u16::from_le_bytes([format[14], format[15]]),
+            format,
+            data: data?,
+        })
+    }
+
+    fn byte_rate(&self) -> usize {
+        self.sample_rate as usize * self.channels as usize * (self.bits_per_sample as usize / 8).max(1)
+    }
+
+    /// Length in seconds.
+    pub fn duration(&self) -> f64 {
+        self.data.len() as f64 / self.byte_rate() as f64
+    }
+
+    /// True if `other` can be appended to this without converting it.
+    pub fn same_format(&self, other: &Wav) -> bool {
+        self.channels == other.channels
+            && self.sample_rate == other.sample_rate
+            && self.bits_per_sample == other.bits_per_sample
+    }
+
+    /// An empty track in the same format.
+    pub fn empty_like(&self) -> Wav {
+        Wav { data: Vec::new(), ..self.clone() }
+    }
+
+    pub fn append(&mut self, other: &Wav) {
+        self.data.extend_from_slice(&other.data);
+    }
+
+    /// Appends `seconds` of silence.
+    pub fn append_silence(&mut self, seconds: f64) {
+        let frame = self.channels as usize * (self.bits_per_sample as usize / 8).max(1);
+        let frames = (seconds * self.sample_rate as f64) as usize;
+        // 8-bit PCM is unsigned, its silence is the midpoint
+        let sample = if self.bits_per_sample == 8 { 0x80 } else { 0 };
+        self.data.resize(self.data.len() + frames * frame, sample);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // "WAVE", then each chunk's header, body and pad byte
+        let riff_size = 20 + self.format.len() + (self.format.len() & 1) + self.data.len() + (self.data.len() & 1);
+        let mut bytes = Vec::with_capacity(8 + riff_size);
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(riff_size as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&(self.format.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.format);
+        if self.format.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        if self.data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+}
+
+/// Encodes WAV audio as MP3 with ffmpeg, since EPUB readers are only required
+/// to play MP3 and AAC.
+pub fn encode_mp3(wav: &[u8]) -> Result<Vec<u8>, Error> {
+    let output = TempFile::new("mp3");
+    encode_mp3_to(wav, &output.path)?;
+    Ok(std::fs::read(&output.path)?)
+}
+
+/// Encodes a WAV file as MP3 into the file at `output`.
+pub fn encode_mp3_to(wav: &[u8], output: &std::path::Path) -> Result<(), Error> {
+    let input = TempFile::new("wav");
+    std::fs::write(&input.path, wav)?;
+
+    let result = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(&input.path)
+        .args(["-codec:a", "libmp3lame", "-q:a", "4"])
+        .arg(output)
+        .output()
+        .map_err(|err| Error::Command { command: "ffmpeg".to_string(), message: err.to_string() })?;
+    if !result.status.success() {
+        return Err(Error::Command {
+            command: "ffmpeg".to_string(),
+            message: String::from_utf8_lossy(&result.stderr).trim().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Converts an audio file to 16 kHz mono 16-bit WAV with ffmpeg, the input
+/// speech recognizers expect.
+#[cfg(feature = "whisper")]
+pub fn decode_for_speech_recognition(path: &std::path::Path) -> Result<Vec<u8>, Error> {
+    let output = TempFile::new("wav");
+    let result = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-ar", "16000", "-ac", "1", "-codec:a", "pcm_s16le"])
+        .arg(&output.path)
+        .output()
+        .map_err(|err| Error::Command { command: "ffmpeg".to_string(), message: err.to_string() })?;
+    if !result.status.success() {
+        return Err(Error::Command {
+            command: "ffmpeg".to_string(),
+            message: String::from_utf8_lossy(&result.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(std::fs::read(&output.path)?)
+}
+
+/// A file in the temp directory that is removed again when dropped.
+pub(crate) struct TempFile {
+    pub path: std::path::PathBuf,
+}
+
+impl TempFile {
+    pub fn new(extension: &str) -> TempFile {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!(
+            "xpub-{}-{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            extension,
+        );
+        TempFile { path: std::env::temp_dir().join(name) }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                eprintln!("Warning: could not remove {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono 8 kHz 8-bit PCM with the given samples.
+    fn wav(samples: &[u8]) -> Wav {
+        let mut format = vec![1, 0, 1, 0];
+        format.extend_from_slice(&8000u32.to_le_bytes());
+        format.extend_from_slice(&8000u32.to_le_bytes());
+        format.extend_from_slice(&[1, 0, 8, 0]);
+        Wav::parse(&Wav { format, channels: 1, sample_rate: 8000, bits_per_sample: 8, data: samples.to_vec() }.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for samples in [&[][..], &[1, 2, 3], &[1, 2, 3, 4]] {
+            let wav = wav(samples);
+            let bytes = wav.to_bytes();
+            assert_eq!(wav.data, samples);
+            assert_eq!((wav.channels, wav.sample_rate, wav.bits_per_sample), (1, 8000, 8));
+            // The RIFF size covers everything after it, pad bytes included
+            assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+            assert_eq!(bytes.len() % 2, 0);
+        }
+    }
+
+    #[test]
+    fn data_of_unknown_size_runs_to_the_end() {
+        for size in [0, u32::MAX] {
+            let mut bytes = wav(&[10, 20, 30, 40]).to_bytes();
+            let data = bytes.len() - 4 - 4;
+            bytes[data..data + 4].copy_from_slice(&size.to_le_bytes());
+            assert_eq!(Wav::parse(&bytes).unwrap().data, [10, 20, 30, 40]);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Wav::parse(b"RIFF\0\0\0\0AVI ").is_none());
+        assert!(Wav::parse(b"ID3").is_none());
+    }
+}
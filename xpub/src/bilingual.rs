@@ -0,0 +1,458 @@
+//! Two editions of a book, the original and a translation, merged into one.
+//!
+//! The chapters of the two spines are matched up first, then the paragraphs
+//! of each pair of chapters, and for alternating sentences the sentences of
+//! each pair of paragraphs. Every level uses the length-based alignment of
+//! Gale and Church, which relies on translations being about as long as
+//! their source, helped by anchors that survive translation: numbers and
+//! names. The translation is then written into the original's chapters,
+//! next to or after the text it translates.
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::epub::EpubDocument;
+use crate::error::Error;
+use crate::html_parser;
+use crate::layout::Layout;
+use crate::package;
+use crate::tokenizer::{self, WordClass};
+
+/// Alignments bigger than this are split in two first.
+const MAX_CELLS: usize = 4_000_000;
+
+/// Variance of the length of a translation per character of the original.
+const VARIANCE: f64 = 6.8;
+
+/// How much a number or name found on both sides is worth, in the same
+/// units as the length cost.
+const ANCHOR_WEIGHT: f64 = 2.0;
+
+/// Anchors counted per bead, so long chapters full of names do not drown
+/// out their lengths.
+const MAX_ANCHORS: usize = 3;
+
+/// Shapes a bead can have, (original units, translated units, probability).
+const SHAPES: [(usize, usize, f64); 6] = [
+    (1, 1, 0.89),
+    (1, 0, 0.0099 / 2.0),
+    (0, 1, 0.0099 / 2.0),
+    (2, 1, 0.089 / 2.0),
+    (1, 2, 0.089 / 2.0),
+    (2, 2, 0.011),
+];
+
+const CSS: &str = "<style>\n\
+    .xpub-pair { display: flex; gap: 1.5em; }\n\
+    .xpub-pair > * { flex: 1; }\n\
+    .xpub-translation { color: #555; }\n\
+    </style>\n";
+
+/// How the translation is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrangement {
+    /// Each paragraph with its translation in a column next to it
+    SideBySide,
+    /// Each paragraph followed by its translation
+    Paragraphs,
+    /// Each sentence followed by its translation
+    Sentences,
+}
+
+/// Units of the original and the translation that translate each other.
+/// Either side may be empty when something was left out of one edition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bead {
+    pub original: Range<usize>,
+    pub translation: Range<usize>,
+}
+
+/// A text to align, with what it is measured by.
+struct Unit {
+    length: f64,
+    anchors: HashSet<String>,
+}
+
+impl Unit {
+    fn new(text: &str) -> Unit {
+        Unit { length: text.chars().count() as f64, anchors: anchors(text) }
+    }
+}
+
+/// Numbers, and capitalized words that do not start a sentence, which are
+/// most likely names.
+fn anchors(text: &str) -> HashSet<String> {
+    let mut anchors = HashSet::new();
+    let mut sentence_start = true;
+    for word in text.split_whitespace() {
+        let core = tokenizer::trim_punctuation(word);
+        if core.chars().any(|c| c.is_ascii_digit()) {
+            anchors.insert(core.chars().filter(char::is_ascii_digit).collect());
+        } else if !sentence_start && core.chars().count() > 1 && tokenizer::classify(core) == WordClass::Capitalized {
+            anchors.insert(core.to_string());
+        }
+        sentence_start = word.ends_with(['.', '!', '?', '…', ':', '"', '»', '”']);
+    }
+    anchors
+}
+
+/// Aligns two sequences of texts, in order.
+pub fn align(original: &[String], translation: &[String]) -> Vec<Bead> {
+    let original: Vec<Unit> = original.iter().map(|text| Unit::new(text)).collect();
+    let translation: Vec<Unit> = translation.iter().map(|text| Unit::new(text)).collect();
+    let original_length: f64 = original.iter().map(|unit| unit.length).sum();
+    let translation_length: f64 = translation.iter().map(|unit| unit.length).sum();
+    let ratio = if original_length > 0.0 && translation_length > 0.0 { translation_length / original_length } else { 1.0 };
+    align_units(&original, &translation, ratio)
+}
+
+fn align_units(original: &[Unit], translation: &[Unit], ratio: f64) -> Vec<Bead> {
+    let (n, m) = (original.len(), translation.len());
+    if (n + 1) * (m + 1) > MAX_CELLS && n > 1 && m > 1 {
+        // Cut both in two where the same share of the text has gone by
+        let i = n / 2;
+        let share = original[..i].iter().map(|unit| unit.length).sum::<f64>() * ratio;
+        let mut j = 0;
+        let mut length = 0.0;
+        while j < m - 1 && length + translation[j].length / 2.0 < share {
+            length += translation[j].length;
+            j += 1;
+        }
+        let j = j.max(1);
+        let mut beads = align_units(&original[..i], &translation[..j], ratio);
+        beads.extend(align_units(&original[i..], &translation[j..], ratio).into_iter().map(|bead| Bead {
+            original: bead.original.start + i..bead.original.end + i,
+            translation: bead.translation.start + j..bead.translation.end + j,
+        }));
+        return beads;
+    }
+
+    // cost[i * width + j] is the cheapest alignment of original[..i] and translation[..j]
+    let width = m + 1;
+    let mut cost = vec![f64::INFINITY; (n + 1) * width];
+    let mut shape = vec![0u8; (n + 1) * width];
+    cost[0] = 0.0;
+    for i in 0..=n {
+        for j in 0..=m {
+            for (k, &(di, dj, probability)) in SHAPES.iter().enumerate() {
+                if di > i || dj > j {
+                    continue;
+                }
+                let previous = cost[(i - di) * width + j - dj];
+                if !previous.is_finite() {
+                    continue;
+                }
+                let total = previous - probability.ln() + bead_cost(&original[i - di..i], &translation[j - dj..j], ratio);
+                if total < cost[i * width + j] {
+                    cost[i * width + j] = total;
+                    shape[i * width + j] = k as u8;
+                }
+            }
+        }
+    }
+
+    let mut beads = Vec::new();
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        let (di, dj, _) = SHAPES[shape[i * width + j] as usize];
+        beads.push(Bead { original: i - di..i, translation: j - dj..j });
+        i -= di;
+        j -= dj;
+    }
+    beads.reverse();
+    beads
+}
+
+fn bead_cost(original: &[Unit], translation: &[Unit], ratio: f64) -> f64 {
+    let original_length: f64 = original.iter().map(|unit| unit.length).sum();
+    let translation_length: f64 = translation.iter().map(|unit| unit.length).sum();
+    let mut cost = length_cost(original_length, translation_length, ratio);
+
+    let original_anchors: HashSet<&String> = original.iter().flat_map(|unit| &unit.anchors).collect();
+    let translation_anchors: HashSet<&String> = translation.iter().flat_map(|unit| &unit.anchors).collect();
+    let shared = original_anchors.intersection(&translation_anchors).count();
+    // Numbers hardly ever change in translation, names may be transliterated
+    let unmatched_numbers = original_anchors.symmetric_difference(&translation_anchors)
+        .filter(|anchor| anchor.starts_with(|c: char| c.is_ascii_digit()))
+        .count();
+    cost -= ANCHOR_WEIGHT * shared.min(MAX_ANCHORS) as f64;
+    cost += ANCHOR_WEIGHT / 2.0 * unmatched_numbers.min(MAX_ANCHORS) as f64;
+    cost
+}
+
+/// How unlikely it is for a text of `original` characters to translate into
+/// one of `translation` characters, as a negative log probability.
+fn length_cost(original: f64, translation: f64, ratio: f64) -> f64 {
+    if original == 0.0 && translation == 0.0 {
+        return 0.0;
+    }
+    let mean = (original + translation / ratio) / 2.0;
+    let delta = (original * ratio - translation) / (VARIANCE * mean).sqrt();
+    let probability = 2.0 * (1.0 - normal_cdf(delta.abs()));
+    -probability.max(1e-300).ln()
+}
+
+fn normal_cdf(x: f64) -> f64 {
+    0.5 * (1.0 + erf(x / std::f64::consts::SQRT_2))
+}
+
+/// Abramowitz and Stegun 7.1.26, good to 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let polynomial = ((((1.061405429 * t - 1.453152027) * t + 1.421413741) * t - 0.284496736) * t + 0.254829592) * t;
+    sign * (1.0 - polynomial * (-x * x).exp())
+}
+
+/// A content document and the paragraphs in it that have text.
+struct Chapter {
+    name: String,
+    html: String,
+    /// (start of the `<p>` tag, range of its contents)
+    paragraphs: Vec<(usize, Range<usize>)>,
+}
+
+impl Chapter {
+    fn read_all(book: &mut EpubDocument) -> Result<Vec<Chapter>, Error> {
+        let mut chapters = Vec::new();
+        for item in book.content_documents() {
+            if item.has_property("nav") {
+                continue;
+            }
+            let name = book.item_path(&item);
+            let html = book.read_content_document(&name)?;
+            let paragraphs = html_parser::paragraph_ranges(&html).into_iter()
+                .filter(|(_, content)| !plain_text(&html[content.clone()]).is_empty())
+                .collect();
+            chapters.push(Chapter { name, html, paragraphs });
+        }
+        Ok(chapters)
+    }
+
+    fn paragraph_text(&self, paragraph: usize) -> String {
+        plain_text(&self.html[self.paragraphs[paragraph].1.clone()])
+    }
+
+    fn text(&self) -> String {
+        (0..self.paragraphs.len()).map(|i| self.paragraph_text(i)).collect::<Vec<_>>().join("\n")
+    }
+
+    /// (end of the sentence in the chapter, its text) for each sentence of a paragraph.
+    fn sentences(&self, paragraph: usize) -> Vec<(usize, String)> {
+        let content = self.paragraphs[paragraph].1.clone();
+        let tokens = tokenizer::tokenize(&self.html[content.clone()]);
+        tokenizer::sentences(&tokens).into_iter()
+            .map(|sentence| {
+                let end = content.start + tokens[..sentence.end].iter().map(|token| token.as_str().len()).sum::<usize>();
+                let text: String = tokens[sentence].iter().map(|token| token.as_str()).collect();
+                (end, plain_text(&text))
+            })
+            .collect()
+    }
+}
+
+fn plain_text(html: &str) -> String {
+    tokenizer::text_content(html).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Writes the translation into the chapters of `book` and returns how many
+/// of the translation's paragraphs found a place. `language` is the
+/// translation's, which its package may get wrong or leave out.
+pub fn merge(book: &mut EpubDocument, translation: &mut EpubDocument, arrangement: Arrangement, language: &str) -> Result<usize, Error> {
+    let originals = Chapter::read_all(book)?;
+    let translations = Chapter::read_all(translation)?;
+
+    let mut spine = align(
+        &originals.iter().map(Chapter::text).collect::<Vec<_>>(),
+        &translations.iter().map(Chapter::text).collect::<Vec<_>>(),
+    );
+    // Chapters only in the translation go with the chapters before them
+    for i in 1..spine.len() {
+        if spine[i].original.is_empty() && !spine[i - 1].original.is_empty() {
+            spine[i - 1].translation.end = spine[i].translation.end;
+            spine[i].translation.start = spine[i].translation.end;
+        }
+    }
+
+    // (chapter, offset, markup) for everything to insert into the original
+    let mut insertions: Vec<(usize, usize, String)> = Vec::new();
+    let mut placed = 0;
+    for bead in spine.iter().filter(|bead| !bead.original.is_empty() && !bead.translation.is_empty()) {
+        let original: Vec<(usize, usize)> = bead.original.clone()
+            .flat_map(|chapter| (0..originals[chapter].paragraphs.len()).map(move |paragraph| (chapter, paragraph)))
+            .collect();
+        let translated: Vec<(usize, usize)> = bead.translation.clone()
+            .flat_map(|chapter| (0..translations[chapter].paragraphs.len()).map(move |paragraph| (chapter, paragraph)))
+            .collect();
+        if original.is_empty() || translated.is_empty() {
+            continue;
+        }
+        let paragraph_beads = align(
+            &original.iter().map(|&(chapter, paragraph)| originals[chapter].paragraph_text(paragraph)).collect::<Vec<_>>(),
+            &translated.iter().map(|&(chapter, paragraph)| translations[chapter].paragraph_text(paragraph)).collect::<Vec<_>>(),
+        );
+
+        let mut previous = 0;
+        for paragraph_bead in paragraph_beads {
+            // Paragraphs only in the translation go with the paragraph before them
+            let last = paragraph_bead.original.end.checked_sub(1).filter(|_| !paragraph_bead.original.is_empty()).unwrap_or(previous);
+            previous = last;
+            if paragraph_bead.translation.is_empty() {
+                continue;
+            }
+            placed += paragraph_bead.translation.len();
+            let texts: Vec<String> = paragraph_bead.translation.clone()
+                .map(|i| translations[translated[i].0].paragraph_text(translated[i].1))
+                .collect();
+            let attributes = translation_attributes(language);
+
+            if arrangement == Arrangement::Sentences {
+                let original_sentences: Vec<(usize, usize, String)> = paragraph_bead.original.clone()
+                    .flat_map(|i| {
+                        let (chapter, paragraph) = original[i];
+                        originals[chapter].sentences(paragraph).into_iter().map(move |(end, text)| (chapter, end, text))
+                    })
+                    .collect();
+                let translated_sentences: Vec<String> = paragraph_bead.translation.clone()
+                    .flat_map(|i| translations[translated[i].0].sentences(translated[i].1))
+                    .map(|(_, text)| text)
+                    .collect();
+                if !original_sentences.is_empty() {
+                    let texts: Vec<String> = original_sentences.iter().map(|(_, _, text)| text.clone()).collect();
+                    let mut previous_sentence = 0;
+                    for sentence_bead in align(&texts, &translated_sentences) {
+                        let last = sentence_bead.original.end.checked_sub(1).filter(|_| !sentence_bead.original.is_empty()).unwrap_or(previous_sentence);
+                        previous_sentence = last;
+                        let (chapter, end, _) = &original_sentences[last];
+                        for sentence in &translated_sentences[sentence_bead.translation] {
+                            insertions.push((*chapter, *end, format!(
+                                " <span class=\"xpub-translation\"{}>{}</span>",
+                                attributes,
+                                package::escape_attribute(sentence),
+                            )));
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let (chapter, paragraph) = original[last];
+            let (start, content) = originals[chapter].paragraphs[paragraph].clone();
+            let after = content.end + "</p>".len();
+            let paragraphs: String = texts.iter()
+                .map(|text| format!("<p>{}</p>", package::escape_attribute(text)))
+                .collect();
+            match arrangement {
+                Arrangement::SideBySide => {
+                    insertions.push((chapter, start, "<div class=\"xpub-pair\">".to_string()));
+                    insertions.push((chapter, after, format!("<div class=\"xpub-translation\"{}>{}</div></div>", attributes, paragraphs)));
+                }
+                Arrangement::Paragraphs | Arrangement::Sentences => {
+                    insertions.push((chapter, after, paragraphs.replace("<p>", &format!("<p class=\"xpub-translation\"{}>", attributes))));
+                }
+            }
+        }
+    }
+
+    // Stable, so insertions at the same place stay in order
+    insertions.sort_by_key(|&(chapter, offset, _)| (chapter, offset));
+    for (index, chapter) in originals.iter().enumerate() {
+        let chapter_insertions: Vec<&(usize, usize, String)> = insertions.iter().filter(|(i, _, _)| *i == index).collect();
+        if chapter_insertions.is_empty() {
+            continue;
+        }
+        let mut html = String::with_capacity(chapter.html.len() * 2);
+        let mut copied = 0;
+        for (_, offset, markup) in chapter_insertions {
+            html.push_str(&chapter.html[copied..*offset]);
+            html.push_str(markup);
+            copied = *offset;
+        }
+        html.push_str(&chapter.html[copied..]);
+        if let Some(head_end) = html.find("</head>") {
+            html.insert_str(head_end, CSS);
+        }
+        book.set_entry(&chapter.name, html);
+    }
+    Ok(placed)
+}
+
+/// `lang`, `xml:lang` and, for right-to-left languages, `dir`.
+fn translation_attributes(language: &str) -> String {
+    let language = package::escape_attribute(language);
+    let dir = if Layout::new(&language, None).rtl { " dir=\"rtl\"" } else { "" };
+    format!(" lang=\"{}\" xml:lang=\"{}\"{}", language, language, dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn bead(original: Range<usize>, translation: Range<usize>) -> Bead {
+        Bead { original, translation }
+    }
+
+    #[test]
+    fn aligns_one_to_one() {
+        let original = texts(&[
+            "Era un día frío y luminoso de abril.",
+            "Los relojes daban las trece cuando Winston Smith entró en casa, con la barbilla hundida en el pecho para escapar del viento.",
+            "El vestíbulo olía a col hervida.",
+        ]);
+        let translation = texts(&[
+            "It was a bright cold day in April.",
+            "The clocks were striking thirteen when Winston Smith went home, his chin nuzzled into his breast to escape the wind.",
+            "The hallway smelt of boiled cabbage.",
+        ]);
+        assert_eq!(align(&original, &translation), [bead(0..1, 0..1), bead(1..2, 1..2), bead(2..3, 2..3)]);
+    }
+
+    #[test]
+    fn joins_units() {
+        let original = texts(&[
+            "Llegó tarde.",
+            "Nadie lo esperaba ya en la estación, y el último tren había salido hacía una hora.",
+            "Se sentó en un banco a esperar que amaneciera, pensando en todo lo que había dejado atrás en la ciudad.",
+        ]);
+        let translation = texts(&[
+            "He arrived late. Nobody was waiting for him at the station any more, and the last train had left an hour before.",
+            "He sat on a bench to wait for dawn, thinking of everything he had left behind in the city.",
+        ]);
+        assert_eq!(align(&original, &translation), [bead(0..2, 0..1), bead(2..3, 1..2)]);
+
+        // A paragraph the translator left out goes with its neighbour, and the rest lines up again
+        let original = texts(&[
+            "El 3 de mayo de 1808 Goya pintó los fusilamientos de la montaña del Príncipe Pío.",
+            "Según Picasso es una de las primeras pinturas de la guerra moderna, sin héroes ni gloria, solo hombres que van a morir.",
+            "Durante años el cuadro estuvo guardado en los sótanos del museo, lejos de los visitantes.",
+            "Manet la vio en 1865 y pintó su propia versión, la ejecución de Maximiliano.",
+            "Hoy cuelga en la sala 64 del Prado.",
+        ]);
+        let translation = texts(&[
+            "On 3 May 1808 Goya painted the executions on Príncipe Pío hill.",
+            "According to Picasso it is one of the first paintings of modern war, with no heroes and no glory, only men about to die.",
+            "Manet saw it in 1865 and painted his own version, the execution of Maximilian.",
+            "Today it hangs in room 64 of the Prado.",
+        ]);
+        assert_eq!(align(&original, &translation), [bead(0..1, 0..1), bead(1..3, 1..2), bead(3..4, 2..3), bead(4..5, 3..4)]);
+    }
+
+    #[test]
+    fn anchors_are_numbers_and_names() {
+        let anchors = anchors("En 1984, Winston vio a O'Brien. Nadie habló.");
+        assert!(anchors.contains("1984") && anchors.contains("Winston") && anchors.contains("O'Brien"));
+        // Capitalized because it starts a sentence
+        assert!(!anchors.contains("Nadie") && !anchors.contains("En"));
+    }
+
+    #[test]
+    fn aligns_empty_sides() {
+        assert_eq!(align(&[], &[]), []);
+        assert_eq!(align(&texts(&["Uno.", "Dos."]), &[]), [bead(0..1, 0..0), bead(1..2, 0..0)]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Line diffs between the input and output of a run, to check a change on a
+//! long book before writing it out.
+
+use std::fmt::Write as _;
+
+/// Lines of context around each change.
+const CONTEXT: usize = 3;
+
+/// Above this many line pairs, the changed middle of two texts is shown as
+/// removed and added whole rather than compared line by line.
+const MAX_CELLS: usize = 16_000_000;
+
+/// One line of a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl Line<'_> {
+    fn is_change(&self) -> bool {
+        !matches!(self, Line::Same(_))
+    }
+}
+
+/// The lines of `old` and `new`, with what was removed and added in between.
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_middle, new_middle) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut diff: Vec<Line> = old[..prefix].iter().map(|line| Line::Same(line)).collect();
+    if (old_middle.len() + 1) * (new_middle.len() + 1) > MAX_CELLS {
+        diff.extend(old_middle.iter().map(|line| Line::Removed(line)));
+        diff.extend(new_middle.iter().map(|line| Line::Added(line)));
+    } else {
+        diff.extend(longest_common_subsequence(old_middle, new_middle));
+    }
+    diff.extend(old[old.len() - suffix..].iter().map(|line| Line::Same(line)));
+    diff
+}
+
+fn longest_common_subsequence<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    // common[i * width + j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[(i + 1) * width + j] >= common[i * width + j + 1]) {
+            diff.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Lines removed and added.
+pub fn stats(diff: &[Line]) -> (usize, usize) {
+    let removed = diff.iter().filter(|line| matches!(line, Line::Removed(_))).count();
+    let added = diff.iter().filter(|line| matches!(line, Line::Added(_))).count();
+    (removed, added)
+}
+
+/// Unified diff of the first `limit` changes, a change being a run of
+/// removed and added lines. Returns the diff and how many changes it shows.
+pub fn unified(name: &str, diff: &[Line], limit: usize) -> (String, usize) {
+    // Where each change starts and ends in `diff`
+    let mut changes: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in diff.iter().enumerate() {
+        if !line.is_change() {
+            continue;
+        }
+        if let Some((_, end)) = changes.last_mut().filter(|(_, end)| *end == i) {
+            *end = i + 1;
+        } else if changes.len() == limit {
+            break;
+        } else {
+            changes.push((i, i + 1));
+        }
+    }
+    if changes.is_empty() {
+        return (String::new(), 0);
+    }
+
+    // Line numbers in the old and new text before each line of `diff`
+    let mut numbers = Vec::with_capacity(diff.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for line in diff {
+        numbers.push((old_line, new_line));
+        match line {
+            Line::Same(_) => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Line::Removed(_) => old_line += 1,
+            Line::Added(_) => new_line += 1,
+        }
+    }
+    numbers.push((old_line, new_line));
+
+    let mut output = format!("--- a/{}\n+++ b/{}\n", name, name);
+    let mut index = 0;
+    while index < changes.len() {
+        // Changes close enough to share context go in one hunk
+        let mut last = index;
+        while last + 1 < changes.len() && changes[last + 1].0 - changes[last].1 <= 2 * CONTEXT {
+            last += 1;
+        }
+        let start = changes[index].0.saturating_sub(CONTEXT);
+        let end = (changes[last].1 + CONTEXT).min(diff.len());
+        let (old_start, new_start) = numbers[start];
+        let (old_end, new_end) = numbers[end];
+        let _ = writeln!(
+            output,
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start),
+        );
+        for line in &diff[start..end] {
+            let _ = match line {
+                Line::Same(text) => writeln!(output, " {}", text),
+                Line::Removed(text) => writeln!(output, "-{}", text),
+                Line::Added(text) => writeln!(output, "+{}", text),
+            };
+        }
+        index = last + 1;
+    }
+    (output, changes.len())
+}
+
+/// `start,count` as in a hunk header, with lines counted from 1.
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(range: std::ops::Range<usize>) -> String {
+        range.map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let diff = lines("a\nb\nc\n", "a\nB\nc\nd\n");
+        assert_eq!(diff, [Line::Same("a"), Line::Removed("b"), Line::Added("B"), Line::Same("c"), Line::Added("d")]);
+        assert_eq!(stats(&diff), (1, 2));
+    }
+
+    #[test]
+    fn hunk_headers() {
+        let old = numbered(1..21);
+        let new = old.replace("line 10\n", "line ten\n");
+        let (output, shown) = unified("c1.xhtml", &lines(&old, &new), 10);
+        assert_eq!(shown, 1);
+        assert_eq!(output, "--- a/c1.xhtml\n+++ b/c1.xhtml\n@@ -7,7 +7,7 @@\n \
+            line 7\n line 8\n line 9\n-line 10\n+line ten\n line 11\n line 12\n line 13\n");
+
+        // Added at the start, with the line after it as context
+        let (output, _) = unified("a", &lines("x\n", "new\nx\n"), 10);
+        assert!(output.contains("@@ -1 +1,2 @@\n+new\n x\n"), "{}", output);
+
+        // Added to nothing: the old side is empty before line 1
+        let (output, _) = unified("a", &lines("", "new\n"), 10);
+        assert!(output.contains("@@ -0,0 +1 @@\n+new\n"), "{}", output);
+
+        // Only removed at the end
+        let (output, _) = unified("a", &lines("x\ny\n", "x\n"), 10);
+        assert!(output.contains("@@ -1,2 +1 @@\n x\n-y\n"), "{}", output);
+    }
+
+    #[test]
+    fn nearby_changes_share_a_hunk() {
+        let old = numbered(1..41);
+        let new = old.replace("line 5\n", "five\n").replace("line 10\n", "ten\n").replace("line 30\n", "thirty\n");
+        let (output, shown) = unified("a", &lines(&old, &new), 10);
+        assert_eq!(shown, 3);
+        let headers: Vec<&str> = output.lines().filter(|line| line.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -2,12 +2,12 @@", "@@ -27,7 +27,7 @@"]);
+    }
+
+    #[test]
+    fn limits_the_changes_shown() {
+        let old = numbered(1..41);
+        let new = old.replace("line 5\n", "five\n").replace("line 30\n", "thirty\n");
+        let (output, shown) = unified("a", &lines(&old, &new), 1);
+        assert_eq!(shown, 1);
+        assert!(!output.contains("thirty"));
+        assert_eq!(unified("a", &lines(&old, &old), 10), (String::new(), 0));
+    }
+}
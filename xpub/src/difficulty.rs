@@ -0,0 +1,427 @@
+//! How hard each chapter is to read.
+//!
+//! A chapter is graded by how many of its words fall outside the most
+//! frequent 1000, 3000 and 5000 words of the language, how long its
+//! sentences are, and how many words the reader meets for the first time in
+//! it. The grades go into a plain text report and, if asked for, into the
+//! navigation document as a badge next to each chapter.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::epub::EpubDocument;
+use crate::error::Error;
+use crate::html_parser;
+use crate::package;
+use crate::tokenizer;
+use crate::transform::{Document, Transform};
+
+/// Upper bounds of the frequency bands.
+const BANDS: [usize; 3] = [1000, 3000, 5000];
+
+/// Words of a language from most to least frequent.
+#[derive(Debug, Clone, Default)]
+pub struct FrequencyList {
+    ranks: HashMap<String, usize>,
+}
+
+impl FrequencyList {
+    /// Reads a list with one word per line, most frequent first. Anything
+    /// after the word, such as a count, is ignored, and so are lines
+    /// starting with `#`.
+    pub fn open(path: impl AsRef<Path>) -> Result<FrequencyList, Error> {
+        Ok(FrequencyList::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(source: &str) -> FrequencyList {
+        let mut list = FrequencyList::default();
+        for line in source.lines().filter(|line| !line.starts_with('#')) {
+            if let Some(word) = line.split_whitespace().next() {
+                list.push(tokenizer::normalize_word(word));
+            }
+        }
+        list
+    }
+
+    /// Ranks the words of a book by how often they occur in it, for books in
+    /// languages there is no list for.
+    pub fn from_book(book: &mut EpubDocument) -> Result<FrequencyList, Error> {
+        let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+        for item in book.content_documents().into_iter().filter(|item| !item.has_property("nav")) {
+            let html = book.read_content_document(&book.item_path(&item))?;
+            for paragraph in html_parser::paragraphs(&html) {
+                for word in words(&tokenizer::tokenize(paragraph)) {
+                    let seen = counts.len();
+                    counts.entry(word).or_insert((0, seen)).0 += 1;
+                }
+            }
+        }
+        let mut words: Vec<(String, (usize, usize))> = counts.into_iter().collect();
+        words.sort_by(|a, b| b.1.0.cmp(&a.1.0).then(a.1.1.cmp(&b.1.1)));
+
+        let mut list = FrequencyList::default();
+        for (word, _) in words {
+            list.push(word);
+        }
+        Ok(list)
+    }
+
+    fn push(&mut self, word: String) {
+        let rank = self.ranks.len() + 1;
+        self.ranks.entry(word).or_insert(rank);
+    }
+
+    /// 1 for the most frequent word, `None` for words not in the list.
+    pub fn rank(&self, word: &str) -> Option<usize> {
+        self.ranks.get(&tokenizer::normalize_word(word)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranks.is_empty()
+    }
+}
+
+/// Difficulty estimate of one chapter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterDifficulty {
+    /// Name of the content document in the archive
+    pub name: String,
+    /// First heading of the chapter, or its `<title>`
+    pub title: Option<String>,
+    pub words: usize,
+    pub sentences: usize,
+    /// Share of words outside the most frequent 1000, 3000 and 5000
+    pub outside: [f64; 3],
+    /// Words per sentence
+    pub mean_sentence_length: f64,
+    /// Share of words not seen in earlier chapters, `None` for the first
+    /// chapter, where every word is new
+    pub new_words: Option<f64>,
+}
+
+impl ChapterDifficulty {
+    /// Level from 1 to 5. Each step is 5% more words outside the most
+    /// frequent 3000, and long sentences or many new words add a level.
+    pub fn level(&self) -> u8 {
+        let mut level = 1 + (self.outside[1] / 0.05).min(4.0) as u8;
+        if self.mean_sentence_length > 25.0 {
+            level += 1;
+        }
+        if self.new_words.is_some_and(|share| share > 0.2) {
+            level += 1;
+        }
+        level.min(5)
+    }
+}
+
+/// Grades every chapter as it goes through the pipeline. Add it before
+/// [`WrapWords`](crate::WrapWords) so it sees the book's own text.
+pub struct Difficulty {
+    frequencies: Option<FrequencyList>,
+    report: Option<PathBuf>,
+    badges: bool,
+    seen: HashSet<String>,
+    chapters: Vec<ChapterDifficulty>,
+}
+
+impl Difficulty {
+    /// Grades against `frequencies`, or against the book's own word counts
+    /// if there is no list for the language.
+    pub fn new(frequencies: Option<FrequencyList>) -> Self {
+        Difficulty {
+            frequencies,
+            report: None,
+            badges: false,
+            seen: HashSet::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Writes the report to `path` once the book is done.
+    pub fn report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.report = Some(path.into());
+        self
+    }
+
+    /// Adds a badge with each chapter's level to the navigation document.
+    pub fn badges(mut self, badges: bool) -> Self {
+        self.badges = badges;
+        self
+    }
+
+    fn grade(&mut self, document: &Document) -> Option<ChapterDifficulty> {
+        let frequencies = self.frequencies.as_ref()?;
+        let first = self.seen.is_empty();
+        let mut words = 0;
+        let mut sentences = 0;
+        let mut outside = [0; 3];
+        let mut new_words = 0;
+        for paragraph in html_parser::paragraphs(&document.content) {
+            let tokens = tokenizer::tokenize(paragraph);
+            sentences += tokenizer::sentences(&tokens).len();
+            for word in self::words(&tokens) {
+                words += 1;
+                let rank = frequencies.rank(&word).unwrap_or(usize::MAX);
+                for (band, &bound) in BANDS.iter().enumerate() {
+                    if rank > bound {
+                        outside[band] += 1;
+                    }
+                }
+                if self.seen.insert(word) {
+                    new_words += 1;
+                }
+            }
+        }
+        if words == 0 {
+            return None;
+        }
+
+        let share = |count: usize| count as f64 / words as f64;
+        Some(ChapterDifficulty {
+            name: document.name.clone(),
+            title: chapter_title(&document.content),
+            words,
+            sentences,
+            outside: outside.map(share),
+            mean_sentence_length: words as f64 / sentences.max(1) as f64,
+            new_words: (!first).then(|| share(new_words)),
+        })
+    }
+
+    fn add_badges(&self, book: &mut EpubDocument) -> Result<(), Error> {
+        let Some(nav) = book.package().nav_item().cloned() else {
+            return Ok(());
+        };
+        let nav_path = book.item_path(&nav);
+        let mut html = book.read_content_document(&nav_path)?;
+        let levels: HashMap<&str, &ChapterDifficulty> = self.chapters.iter()
+            .map(|chapter| (chapter.name.as_str(), chapter))
+            .collect();
+
+        let mut badged = HashSet::new();
+        let mut index = 0;
+        while let Some(start) = html[index..].find("<a").map(|i| i + index) {
+            index = start + 2;
+            if !tokenizer::is_tag_named(&html[start..], "a") {
+                continue;
+            }
+            let Some(tag_end) = html[start..].find('>').map(|i| i + start + 1) else {
+                break;
+            };
+            let Some(href) = tokenizer::attribute(&html[start..tag_end], "href") else {
+                continue;
+            };
+            let target = package::resolve_href(&nav_path, &tokenizer::decode_entities(href));
+            let Some(chapter) = levels.get(target.as_str()) else {
+                continue;
+            };
+            // Only the first link to a chapter, not every section in it
+            if !badged.insert(chapter.name.clone()) {
+                continue;
+            }
+            let Some(length) = tokenizer::find_element_end(&html[start..], "a") else {
+                break;
+            };
+            let badge = format!(
+                " <span class=\"xpub-difficulty\" title=\"{:.0}% of words outside the 3000 most common\">Level {}</span>",
+                chapter.outside[1] * 100.0,
+                chapter.level(),
+            );
+            html.insert_str(start + length, &badge);
+            index = start + length + badge.len();
+        }
+
+        book.set_entry(&nav_path, html);
+        Ok(())
+    }
+}
+
+impl Transform for Difficulty {
+    fn begin(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        if self.frequencies.is_none() {
+            self.frequencies = Some(FrequencyList::from_book(book)?);
+        }
+        Ok(())
+    }
+
+    fn transform(&mut self, _book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        if document.item.has_property("nav") {
+            return Ok(());
+        }
+        if let Some(chapter) = self.grade(document) {
+            self.chapters.push(chapter);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        if let Some(path) = &self.report {
+            std::fs::write(path, format_report(&self.chapters))?;
+        }
+        if self.badges {
+            self.add_badges(book)?;
+        }
+        Ok(())
+    }
+}
+
+/// The grades as a table, one chapter per line.
+pub fn format_report(chapters: &[ChapterDifficulty]) -> String {
+    let mut report = String::from("level\twords\t>1k\t>3k\t>5k\twords/sentence\tnew words\tchapter\n");
+    for chapter in chapters {
+        let _ = writeln!(
+            report,
+            "{}\t{}\t{:.1}%\t{:.1}%\t{:.1}%\t{:.1}\t{}\t{}",
+            chapter.level(),
+            chapter.words,
+            chapter.outside[0] * 100.0,
+            chapter.outside[1] * 100.0,
+            chapter.outside[2] * 100.0,
+            chapter.mean_sentence_length,
+            chapter.new_words.map_or("-".to_string(), |share| format!("{:.1}%", share * 100.0)),
+            chapter.title.as_deref().unwrap_or(&chapter.name),
+        );
+    }
+    report
+}
+
+/// The words of a paragraph that count towards its difficulty, normalized.
+fn words(tokens: &[tokenizer::Token]) -> Vec<String> {
+    tokens.iter()
+        .filter(|token| token.is_word())
+        .map(|token| tokenizer::normalize_word(&tokenizer::text_content(token.as_str())))
+        .filter(|word| word.chars().any(char::is_alphabetic))
+        .collect()
+}
+
+/// Text of the first `h1` to `h3`, or of the `<title>`.
+pub(crate) fn chapter_title(html: &str) -> Option<String> {
+    ["h1", "h2", "h3", "title"].into_iter().find_map(|name| {
+        let start = html.match_indices('<').map(|(i, _)| i).find(|&i| tokenizer::is_tag_named(&html[i..], name))?;
+        let length = tokenizer::find_element_end(&html[start..], name)?;
+        let text = tokenizer::text_content(&html[start..start + length]);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        (!text.is_empty()).then_some(text)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn chapter(outside: f64, mean_sentence_length: f64, new_words: Option<f64>) -> ChapterDifficulty {
+        ChapterDifficulty {
+            name: "OEBPS/c1.xhtml".to_string(),
+            title: None,
+            words: 100,
+            sentences: 10,
+            outside: [outside; 3],
+            mean_sentence_length,
+            new_words,
+        }
+    }
+
+    /// Grades the chapters of `book` the way the pipeline would.
+    fn grade(book: &mut EpubDocument, difficulty: &mut Difficulty) {
+        difficulty.begin(book).unwrap();
+        for item in book.content_documents() {
+            let name = book.item_path(&item);
+            let content = book.read_content_document(&name).unwrap();
+            let mut document = Document { name, item, spine_index: None, content };
+            difficulty.transform(book, &mut document).unwrap();
+        }
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(chapter(0.0, 10.0, None).level(), 1);
+        assert_eq!(chapter(0.049, 10.0, None).level(), 1);
+        assert_eq!(chapter(0.05, 10.0, None).level(), 2);
+        assert_eq!(chapter(0.12, 10.0, None).level(), 3);
+        assert_eq!(chapter(0.5, 10.0, None).level(), 5);
+        assert_eq!(chapter(0.0, 26.0, None).level(), 2);
+        assert_eq!(chapter(0.0, 10.0, Some(0.2)).level(), 1);
+        assert_eq!(chapter(0.0, 10.0, Some(0.3)).level(), 2);
+        assert_eq!(chapter(0.2, 26.0, Some(0.3)).level(), 5);
+    }
+
+    #[test]
+    fn parses_frequency_lists() {
+        let list = FrequencyList::parse("# Spanish\nde 1000\nLa\t900\n\nde 10\n¿que?\n");
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.rank("de"), Some(1));
+        assert_eq!(list.rank("la"), Some(2));
+        assert_eq!(list.rank("Que"), Some(3));
+        assert_eq!(list.rank("#"), None);
+        assert_eq!(list.rank("spanish"), None);
+    }
+
+    #[test]
+    fn ranks_words_of_the_book() {
+        let file = fixtures::book(&["<p>uno dos dos</p>", "<p>tres dos tres</p>"]);
+        let mut book = EpubDocument::open(&file.path).unwrap();
+        let list = FrequencyList::from_book(&mut book).unwrap();
+        assert_eq!([list.rank("dos"), list.rank("tres"), list.rank("uno")], [Some(1), Some(2), Some(3)]);
+        // The nav document's link text is not part of the book's text
+        assert_eq!(list.rank("chapter"), None);
+    }
+
+    #[test]
+    fn leaves_new_words_out_of_the_first_chapter() {
+        let file = fixtures::book(&["<h1>Uno</h1><p>Uno dos tres.</p>", "<p>Uno dos cuatro cinco.</p>"]);
+        let mut book = EpubDocument::open(&file.path).unwrap();
+        let mut difficulty = Difficulty::new(Some(FrequencyList::parse("uno\ndos\ntres\ncuatro\ncinco\n")));
+        grade(&mut book, &mut difficulty);
+
+        let chapters = &difficulty.chapters;
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[0].title.as_deref(), Some("Uno"));
+        assert_eq!(chapters[0].new_words, None);
+        assert_eq!(chapters[0].level(), 1);
+        assert_eq!(chapters[1].new_words, Some(0.5));
+        assert_eq!(chapters[1].level(), 2);
+    }
+
+    #[test]
+    fn formats_reports() {
+        let mut first = chapter(0.1, 12.0, None);
+        first.title = Some("Uno".to_string());
+        let second = ChapterDifficulty { name: "OEBPS/c2.xhtml".to_string(), ..chapter(0.025, 8.25, Some(0.125)) };
+        assert_eq!(
+            format_report(&[first, second]),
+            "level\twords\t>1k\t>3k\t>5k\twords/sentence\tnew words\tchapter\n\
+             3\t100\t10.0%\t10.0%\t10.0%\t12.0\t-\tUno\n\
+             1\t100\t2.5%\t2.5%\t2.5%\t8.2\t12.5%\tOEBPS/c2.xhtml\n",
+        );
+    }
+
+    #[test]
+    fn badges_the_first_link_to_each_chapter() {
+        let mut entries = fixtures::book_entries(&["<p>Uno</p>", "<p>Dos</p>"]);
+        let (_, nav) = entries.iter_mut().find(|(name, _)| name == "OEBPS/nav.xhtml").unwrap();
+        *nav = nav.replace("</ol>", "<li><a href=\"c1.xhtml#part\">Part</a></li></ol>");
+        let file = fixtures::archive(&entries);
+        let mut book = EpubDocument::open(&file.path).unwrap();
+        let mut difficulty = Difficulty::new(Some(FrequencyList::parse("uno\n"))).badges(true);
+        grade(&mut book, &mut difficulty);
+        difficulty.finish(&mut book).unwrap();
+
+        let nav = book.read_content_document("OEBPS/nav.xhtml").unwrap();
+        assert!(nav.contains(
+            "<a href=\"c1.xhtml\">Chapter 1</a> <span class=\"xpub-difficulty\" \
+             title=\"0% of words outside the 3000 most common\">Level 1</span>",
+        ));
+        assert!(nav.contains(
+            "<a href=\"c2.xhtml\">Chapter 2</a> <span class=\"xpub-difficulty\" \
+             title=\"100% of words outside the 3000 most common\">Level 5</span>",
+        ));
+        assert!(nav.contains("<a href=\"c1.xhtml#part\">Part</a></li>"));
+        assert_eq!(nav.matches("xpub-difficulty").count(), 2);
+    }
+}
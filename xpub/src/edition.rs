@@ -0,0 +1,291 @@
+//! Package metadata that marks the output as an edition of its own.
+//!
+//! Reading apps key reading positions and libraries on the book's unique
+//! identifier, so an interactive copy that keeps the original's would
+//! overwrite its positions or be taken for a duplicate. The copy gets a new
+//! identifier derived from the old one, which is kept as `dc:source`, a title
+//! that tells the two apart, a modification date and a note of what made it.
+
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::epub::EpubDocument;
+use crate::error::{Error, ParseError};
+use crate::package;
+use crate::tokenizer;
+use crate::transform::{Document, Transform};
+
+/// Namespace for name-based UUIDs of URLs, from RFC 4122.
+const URL_NAMESPACE: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x11, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+const CONTRIBUTOR_ID: &str = "xpub-contributor";
+/// Id of the `dc:source` that keeps the identifier the book had before xpub.
+const SOURCE_ID: &str = "xpub-source";
+
+/// Rewrites the package metadata once the book is done. Add it last so the
+/// new title and identifier do not show up in anything generated earlier.
+pub struct Edition {
+    new_identifier: bool,
+    title_suffix: Option<String>,
+}
+
+impl Edition {
+    /// Adds the modification date and contributor only.
+    pub fn new() -> Self {
+        Edition { new_identifier: false, title_suffix: None }
+    }
+
+    /// Replaces the unique identifier with one derived from it, and keeps the
+    /// old one as `dc:source`. The same book and options always get the same
+    /// identifier, so rebuilding a book does not make it a new one again.
+    pub fn new_identifier(mut self, new_identifier: bool) -> Self {
+        self.new_identifier = new_identifier;
+        self
+    }
+
+    /// Appends `suffix` to the title, e.g. "(interactive, es→en)".
+    pub fn title_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.title_suffix = Some(suffix.into());
+        self
+    }
+}
+
+impl Default for Edition {
+    fn default() -> Self {
+        Edition::new()
+    }
+}
+
+impl Transform for Edition {
+    fn transform(&mut self, _book: &EpubDocument, _document: &mut Document) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        let parse_error = |source: ParseError| Error::Parse { entry: book.opf_path().to_string(), source };
+        let mut source = book.opf_source().to_string();
+        let package = book.package().clone();
+        let epub3 = package.version.starts_with('3');
+
+        // A title that already ends with the suffix was marked by an earlier run
+        if let (Some(suffix), Some(title)) = (&self.title_suffix, package.title()) {
+            if !title.ends_with(suffix.as_str()) {
+                if let Some(range) = element_content(&source, b"title", |_| true).map_err(parse_error)? {
+                    source.replace_range(range, &package::escape_attribute(&format!("{} {}", title, suffix)));
+                }
+            }
+        }
+
+        let mut metadata = Vec::new();
+        if self.new_identifier {
+            if let (Some(id), Some(identifier)) = (&package.unique_identifier, package.unique_identifier_value()) {
+                let range = element_content(&source, b"identifier", |e| package::attribute(e, b"id").as_deref() == Some(id))
+                    .map_err(parse_error)?;
+                // A book that went through here before keeps deriving from its first identifier
+                let earlier = element_content(&source, b"source", |e| package::attribute(e, b"id").as_deref() == Some(SOURCE_ID))
+                    .map_err(parse_error)?
+                    .map(|range| tokenizer::decode_entities(&source[range]).into_owned());
+                if let Some(range) = range {
+                    let original = earlier.as_deref().unwrap_or(identifier);
+                    let name = format!("{}#xpub{}", original, self.title_suffix.as_deref().unwrap_or_default());
+                    source.replace_range(range, &derived_uuid(&name));
+                    if earlier.is_none() {
+                        metadata.push(format!("<dc:source id=\"{}\">{}</dc:source>", SOURCE_ID, package::escape_attribute(identifier)));
+                    }
+                }
+            }
+        }
+
+        let modified = timestamp(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        if epub3 {
+            let range = element_content(&source, b"meta", |e| package::attribute(e, b"property").as_deref() == Some("dcterms:modified"))
+                .map_err(parse_error)?;
+            match range {
+                Some(range) => source.replace_range(range, &modified),
+                None => metadata.push(format!("<meta property=\"dcterms:modified\">{}</meta>", modified)),
+            }
+        } else {
+            let tag = format!("<meta name=\"dcterms:modified\" content=\"{}\"/>", modified);
+            let range = element_range(&source, b"meta", |e| package::attribute(e, b"name").as_deref() == Some("dcterms:modified"))
+                .map_err(parse_error)?;
+            match range {
+                Some(range) => source.replace_range(range, &tag),
+                None => metadata.push(tag),
+            }
+        }
+
+        // A book that went through xpub before already says so
+        let noted = source.contains(&format!("id=\"{}\"", CONTRIBUTOR_ID)) || source.contains("content=\"xpub ");
+        if !noted {
+            if epub3 {
+                // Book producer, in the MARC relator codes the spec recommends
+                metadata.push(format!("<dc:contributor id=\"{}\">xpub</dc:contributor>", CONTRIBUTOR_ID));
+                metadata.push(format!(
+                    "<meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">bkp</meta>",
+                    CONTRIBUTOR_ID,
+                ));
+            } else {
+                metadata.push("<dc:contributor>xpub</dc:contributor>".to_string());
+            }
+            metadata.push(format!("<meta name=\"generator\" content=\"xpub {}\"/>", env!("CARGO_PKG_VERSION")));
+        }
+
+        book.set_opf_source(source)?;
+        for xml in metadata {
+            book.add_metadata(&xml)?;
+        }
+        Ok(())
+    }
+}
+
+/// Range of the text inside the first metadata element named `name` that
+/// `matches`.
+fn element_content(source: &str, name: &[u8], matches: impl Fn(&BytesStart) -> bool) -> Result<Option<Range<usize>>, ParseError> {
+    let mut reader = Reader::from_str(source);
+    let mut in_metadata = false;
+    let mut content_start = None;
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(e) if e.local_name().as_ref() == b"metadata" => in_metadata = true,
+            Event::Start(e) if in_metadata && content_start.is_none() && e.local_name().as_ref() == name && matches(&e) => {
+                content_start = Some(reader.buffer_position() as usize);
+            }
+            Event::End(e) if e.local_name().as_ref() == name => {
+                if let Some(content_start) = content_start {
+                    return Ok(Some(content_start..start));
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => return Ok(None),
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Range of the whole first metadata element named `name` that `matches`,
+/// tags included.
+fn element_range(source: &str, name: &[u8], matches: impl Fn(&BytesStart) -> bool) -> Result<Option<Range<usize>>, ParseError> {
+    let mut reader = Reader::from_str(source);
+    let mut in_metadata = false;
+    let mut element_start = None;
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(e) if e.local_name().as_ref() == b"metadata" => in_metadata = true,
+            Event::Empty(e) if in_metadata && element_start.is_none() && e.local_name().as_ref() == name && matches(&e) => {
+                return Ok(Some(start..reader.buffer_position() as usize));
+            }
+            Event::Start(e) if in_metadata && element_start.is_none() && e.local_name().as_ref() == name && matches(&e) => {
+                element_start = Some(start);
+            }
+            Event::End(e) if e.local_name().as_ref() == name => {
+                if let Some(element_start) = element_start {
+                    return Ok(Some(element_start..reader.buffer_position() as usize));
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => return Ok(None),
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Name-based UUID (version 5) for `name`.
+fn derived_uuid(name: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(&URL_NAMESPACE);
+    sha1.update(name.as_bytes());
+    let mut bytes = sha1.digest().bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("urn:uuid:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// `seconds` since the Unix epoch as `CCYY-MM-DDThh:mm:ssZ`.
+fn timestamp(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPF: &str = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+        <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:identifier id="isbn">9780000000000</dc:identifier>
+            <dc:identifier id="id">urn:uuid:1234</dc:identifier>
+            <dc:title>Niebla</dc:title>
+            <meta name="cover" content="img"/>
+            <meta name="dcterms:modified" content="2020-01-01T00:00:00Z"/>
+            <dc:source id="xpub-source">urn:uuid:0000</dc:source>
+        </metadata>
+        <manifest/>
+        <spine/>
+    </package>"#;
+
+    #[test]
+    fn derived_identifiers_are_stable_uuids() {
+        let uuid = derived_uuid("urn:uuid:1234#xpub(interactive, es)");
+        assert_eq!(uuid, derived_uuid("urn:uuid:1234#xpub(interactive, es)"));
+        assert_ne!(uuid, derived_uuid("urn:uuid:1234#xpub(interactive, es→en)"));
+        let hex = uuid.strip_prefix("urn:uuid:").unwrap();
+        let groups: Vec<usize> = hex.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        // Version 5, RFC 4122 variant
+        assert!(hex[14..].starts_with('5'));
+        assert!(hex[19..].starts_with(['8', '9', 'a', 'b']));
+        // As Python's uuid.uuid5(uuid.NAMESPACE_URL, ...) has it
+        assert_eq!(derived_uuid("https://www.example.com/"), "urn:uuid:3d3ed9d2-aa3d-5fa6-90e8-ed662e90f559");
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951_827_696), "2000-02-29T12:34:56Z");
+        assert_eq!(timestamp(1_767_225_599), "2025-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn finds_metadata_content() {
+        let range = element_content(OPF, b"identifier", |e| package::attribute(e, b"id").as_deref() == Some("id")).unwrap();
+        assert_eq!(&OPF[range.unwrap()], "urn:uuid:1234");
+        assert_eq!(&OPF[element_content(OPF, b"title", |_| true).unwrap().unwrap()], "Niebla");
+        assert_eq!(element_content(OPF, b"rights", |_| true).unwrap(), None);
+    }
+
+    #[test]
+    fn finds_whole_elements() {
+        let modified = |e: &BytesStart| package::attribute(e, b"name").as_deref() == Some("dcterms:modified");
+        let range = element_range(OPF, b"meta", modified).unwrap().unwrap();
+        assert_eq!(&OPF[range], "<meta name=\"dcterms:modified\" content=\"2020-01-01T00:00:00Z\"/>");
+        let range = element_range(OPF, b"source", |e| package::attribute(e, b"id").as_deref() == Some(SOURCE_ID)).unwrap();
+        assert_eq!(&OPF[range.unwrap()], "<dc:source id=\"xpub-source\">urn:uuid:0000</dc:source>");
+        assert_eq!(element_range("<package><metadata/></package>", b"meta", modified).unwrap(), None);
+    }
+}
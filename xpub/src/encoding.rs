@@ -0,0 +1,177 @@
+//! Decoding content documents that are not UTF-8.
+//!
+//! Older EPUBs declare legacy encodings such as Windows-1251, Shift_JIS or
+//! GB2312 in the XML declaration or a `<meta>` tag. Documents are decoded with
+//! that encoding, and the declarations are rewritten since xpub always writes
+//! UTF-8 back.
+
+use encoding_rs::{Encoding, UTF_8};
+
+/// How far into a document declarations are looked for.
+const SNIFF_LENGTH: usize = 4096;
+
+/// Works out the encoding of a document from its byte order mark, its XML
+/// declaration or a `<meta>` charset, in that order. Defaults to UTF-8.
+pub fn detect(data: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(data) {
+        return encoding;
+    }
+
+    let head = String::from_utf8_lossy(&data[..data.len().min(SNIFF_LENGTH)]).to_ascii_lowercase();
+    xml_declaration_encoding(&head)
+        .or_else(|| meta_charset(&head))
+        .and_then(|(start, end)| Encoding::for_label(head[start..end].trim().as_bytes()))
+        // A declaration can only claim UTF-16 if there was a byte order mark
+        .filter(|encoding| encoding.output_encoding() == *encoding)
+        .unwrap_or(UTF_8)
+}
+
+/// Decodes `data` into a UTF-8 string whose declarations say UTF-8.
+///
+/// Returns `None` if the bytes are not valid in the detected encoding.
+pub fn decode(data: &[u8]) -> Option<String> {
+    let encoding = detect(data);
+    let (text, _, had_errors) = encoding.decode(data);
+    if had_errors {
+        return None;
+    }
+    if encoding == UTF_8 {
+        return Some(text.into_owned());
+    }
+    Some(declare_utf8(&text))
+}
+
+/// Rewrites the XML declaration and any `<meta>` charset to say UTF-8.
+pub fn declare_utf8(document: &str) -> String {
+    let mut output = String::with_capacity(document.len());
+    let mut head_length = document.len().min(SNIFF_LENGTH);
+    while !document.is_char_boundary(head_length) {
+        head_length -= 1;
+    }
+    let lowercase = document[..head_length].to_ascii_lowercase();
+
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    if let Some(range) = xml_declaration_encoding(&lowercase) {
+        ranges.push(range);
+    }
+    let mut from = 0;
+    while let Some((start, end)) = meta_charset(&lowercase[from..]) {
+        ranges.push((start + from, end + from));
+        from += end;
+    }
+
+    let mut last = 0;
+    for (start, end) in ranges {
+        output.push_str(&document[last..start]);
+        output.push_str("UTF-8");
+        last = end;
+    }
+    output.push_str(&document[last..]);
+    output
+}
+
+/// Byte range of the `encoding` value in the XML declaration of a lowercased head.
+fn xml_declaration_encoding(head: &str) -> Option<(usize, usize)> {
+    let declaration_end = head.trim_start().starts_with("<?xml").then(|| head.find("?>"))??;
+    let declaration = &head[..declaration_end];
+    let name = declaration.find("encoding")?;
+    quoted_value(declaration, name + "encoding".len())
+}
+
+/// Byte range of the charset in the first `<meta charset>` or
+/// `<meta http-equiv="Content-Type">` tag of a lowercased head.
+fn meta_charset(head: &str) -> Option<(usize, usize)> {
+    let mut from = 0;
+    while let Some(meta) = head[from..].find("<meta").map(|i| i + from) {
+        let tag_end = head[meta..].find('>').map(|i| i + meta)?;
+        let tag = &head[meta..tag_end];
+        if let Some(charset) = tag.find("charset") {
+            if let Some((start, end)) = quoted_value(tag, charset + "charset".len()) {
+                return Some((meta + start, meta + end));
+            }
+        }
+        from = tag_end;
+    }
+    None
+}
+
+/// Range of the value after the `=` that follows `index`. The value may be
+/// quoted, as in `charset="..."`, or bare, as inside `content="text/html; charset=..."`.
+fn quoted_value(text: &str, index: usize) -> Option<(usize, usize)> {
+    let rest = &text[index..];
+    let equals = rest.find('=')?;
+    if !rest[..equals].trim().is_empty() {
+        return None;
+    }
+    let value_start = index + equals + 1 + (rest[equals + 1..].len() - rest[equals + 1..].trim_start().len());
+    match text[value_start..].chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = text[value_start + 1..].find(quote)? + value_start + 1;
+            Some((value_start + 1, end))
+        }
+        _ => unquoted_value(text, index),
+    }
+}
+
+/// Range of a charset inside an attribute like `content="text/html; charset=..."`.
+fn unquoted_value(text: &str, index: usize) -> Option<(usize, usize)> {
+    let rest = &text[index..];
+    let equals = rest.find('=')?;
+    if !rest[..equals].trim().is_empty() {
+        return None;
+    }
+    let start = index + equals + 1;
+    let start = start + (text[start..].len() - text[start..].trim_start().len());
+    let end = text[start..]
+        .find(|c: char| c == '"' || c == '\'' || c == ';' || c == '/' || c == '>' || c.is_whitespace())
+        .map_or(text.len(), |i| i + start);
+    (end > start).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{GBK, SHIFT_JIS, UTF_16LE, WINDOWS_1251};
+
+    #[test]
+    fn detects_declared_encodings() {
+        let cases: [(&[u8], &Encoding); 6] = [
+            (b"<?xml version='1.0' encoding='windows-1251'?><html/>", WINDOWS_1251),
+            (b"<?xml version=\"1.0\" encoding = \"Shift_JIS\"?>", SHIFT_JIS),
+            (b"<html><head><meta charset=\"gb2312\"/></head>", GBK),
+            (b"<html><head><meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1251\"/>", WINDOWS_1251),
+            // The XML declaration comes first
+            (b"<?xml version=\"1.0\" encoding=\"Shift_JIS\"?><meta charset=\"gb2312\"/>", SHIFT_JIS),
+            (b"\xff\xfe<\x00?\x00", UTF_16LE),
+        ];
+        for (data, encoding) in cases {
+            assert_eq!(detect(data), encoding, "{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn defaults_to_utf8() {
+        for data in [
+            &b"<html><p>text</p></html>"[..],
+            b"<?xml version=\"1.0\"?><html/>",
+            b"<?xml version=\"1.0\" encoding=\"no-such-encoding\"?>",
+            // UTF-16 without a byte order mark cannot have been read this far
+            b"<?xml version=\"1.0\" encoding=\"UTF-16\"?>",
+            // Not a declaration, just text
+            b"<p>encoding=\"windows-1251\"</p>",
+        ] {
+            assert_eq!(detect(data), UTF_8, "{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn decodes_and_declares_utf8() {
+        let (data, _, _) = WINDOWS_1251.encode("<?xml version=\"1.0\" encoding=\"windows-1251\"?><p>Привет</p>");
+        assert_eq!(decode(&data).as_deref(), Some("<?xml version=\"1.0\" encoding=\"UTF-8\"?><p>Привет</p>"));
+        assert_eq!(
+            declare_utf8("<meta charset='gb2312'><meta content=\"text/html; charset=GB2312\">"),
+            "<meta charset='UTF-8'><meta content=\"text/html; charset=UTF-8\">",
+        );
+        assert_eq!(decode(b"<p>\xff</p>"), None);
+    }
+}
@@ -0,0 +1,246 @@
+//! `META-INF/encryption.xml`: DRM and obfuscated fonts.
+//!
+//! Books with DRM have their content documents encrypted, and there is
+//! nothing to rewrite without the key, so such books are refused up front.
+//! Embedded fonts are often only obfuscated, by XORing their first bytes
+//! with a key derived from the book's identifier, which keeps them from
+//! being copied out of the book on their own. Those are copied across as
+//! they are, unless the identifier changes, in which case they are
+//! obfuscated again with the new key so reading systems can still use them.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::error::ParseError;
+use crate::package::{self, Package};
+
+/// Where the list of encrypted entries is kept.
+pub const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+
+/// Font obfuscation from the OCF spec.
+const IDPF_FONT: &str = "http://www.idpf.org/2008/embedding";
+/// Font obfuscation used by Adobe's reading systems before the IDPF one.
+const ADOBE_FONT: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// Bytes at the start of a font that each algorithm obfuscates.
+const IDPF_LENGTH: usize = 1040;
+const ADOBE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    IdpfFont,
+    AdobeFont,
+    /// Real encryption, by the URI of its algorithm
+    Other(String),
+}
+
+impl Algorithm {
+    fn from_uri(uri: &str) -> Algorithm {
+        match uri {
+            IDPF_FONT => Algorithm::IdpfFont,
+            ADOBE_FONT => Algorithm::AdobeFont,
+            _ => Algorithm::Other(uri.to_string()),
+        }
+    }
+
+    pub fn is_obfuscation(&self) -> bool {
+        !matches!(self, Algorithm::Other(_))
+    }
+}
+
+/// An entry listed in `encryption.xml`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedEntry {
+    /// Name of the entry in the archive
+    pub name: String,
+    pub algorithm: Algorithm,
+    /// Whether the entry was deflated before it was encrypted, as its
+    /// `Compression` element says
+    pub compressed: bool,
+}
+
+/// Reads the entries listed in `encryption.xml`.
+pub fn parse(source: &str) -> Result<Vec<EncryptedEntry>, ParseError> {
+    let mut reader = Reader::from_str(source);
+    let mut entries = Vec::new();
+    let mut algorithm = None;
+    let mut compressed = false;
+    let mut names = Vec::new();
+    loop {
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"EncryptedData" => {
+                    algorithm = None;
+                    compressed = false;
+                    names.clear();
+                }
+                // Only the first, a key's own EncryptionMethod can follow inside KeyInfo
+                b"EncryptionMethod" if algorithm.is_none() => {
+                    algorithm = package::attribute(&e, b"Algorithm");
+                }
+                // Method 8 is deflate, 0 is none
+                b"Compression" => {
+                    compressed = package::attribute(&e, b"Method").is_some_and(|method| method.trim() == "8");
+                }
+                b"CipherReference" => {
+                    if let Some(uri) = package::attribute(&e, b"URI") {
+                        names.push(package::resolve_href("", &uri));
+                    }
+                }
+                _ => {}
+            },
+            Event::End(e) if e.local_name().as_ref() == b"EncryptedData" => {
+                let algorithm = Algorithm::from_uri(algorithm.as_deref().unwrap_or_default());
+                entries.extend(names.drain(..).map(|name| EncryptedEntry { name, algorithm: algorithm.clone(), compressed }));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// Keys fonts are obfuscated with, derived from the package's identifiers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObfuscationKeys {
+    /// SHA-1 of the unique identifier with its whitespace removed
+    idpf: Option<[u8; 20]>,
+    /// Bytes of the first `urn:uuid:` identifier
+    adobe: Option<[u8; 16]>,
+}
+
+impl ObfuscationKeys {
+    pub fn for_package(package: &Package) -> ObfuscationKeys {
+        let idpf = package.unique_identifier_value().map(|identifier| {
+            let identifier: String = identifier.chars()
+                .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+                .collect();
+            sha1_smol::Sha1::from(identifier).digest().bytes()
+        });
+        let adobe = package.metadata.identifiers.iter()
+            .filter_map(|identifier| identifier.value.trim().strip_prefix("urn:uuid:"))
+            .find_map(uuid_bytes);
+        ObfuscationKeys { idpf, adobe }
+    }
+
+    /// Turns a font obfuscated with `self` into one obfuscated with `new`.
+    /// Returns false if either key is missing.
+    pub fn reobfuscate(&self, new: &ObfuscationKeys, algorithm: &Algorithm, font: &mut [u8]) -> bool {
+        match algorithm {
+            Algorithm::IdpfFont => match (self.idpf, new.idpf) {
+                (Some(old), Some(new)) => xor(font, &old, &new, IDPF_LENGTH),
+                _ => return false,
+            },
+            Algorithm::AdobeFont => match (self.adobe, new.adobe) {
+                (Some(old), Some(new)) => xor(font, &old, &new, ADOBE_LENGTH),
+                _ => return false,
+            },
+            Algorithm::Other(_) => return false,
+        }
+        true
+    }
+
+    /// Undoes the obfuscation of a font. Returns false if the key is missing.
+    pub fn deobfuscate(&self, algorithm: &Algorithm, font: &mut [u8]) -> bool {
+        let plain = ObfuscationKeys { idpf: Some([0; 20]), adobe: Some([0; 16]) };
+        self.reobfuscate(&plain, algorithm, font)
+    }
+}
+
+/// Whether `data` starts like an OpenType, TrueType or WOFF font.
+pub fn looks_like_font(data: &[u8]) -> bool {
+    [&b"\x00\x01\x00\x00"[..], b"OTTO", b"true", b"wOFF", b"wOF2", b"ttcf"]
+        .iter()
+        .any(|magic| data.starts_with(magic))
+}
+
+/// XORing with the old key undoes the obfuscation, XORing with the new key
+/// applies it again.
+fn xor(font: &mut [u8], old: &[u8], new: &[u8], length: usize) {
+    let length = length.min(font.len());
+    for (i, byte) in font[..length].iter_mut().enumerate() {
+        *byte ^= old[i % old.len()] ^ new[i % new.len()];
+    }
+}
+
+fn uuid_bytes(uuid: &str) -> Option<[u8; 16]> {
+    let hex: Vec<u8> = uuid.bytes().filter(|&byte| byte != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(std::str::from_utf8(&hex[i * 2..i * 2 + 2]).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(identifier: &str) -> ObfuscationKeys {
+        let opf = format!(
+            r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+                <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">{}</dc:identifier></metadata>
+                <manifest/><spine/>
+            </package>"#,
+            identifier,
+        );
+        ObfuscationKeys::for_package(&Package::parse(&opf).unwrap())
+    }
+
+    #[test]
+    fn parses_entries() {
+        let xml = r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+            <enc:EncryptedData>
+                <enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+                <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/a%20b.otf"/></enc:CipherData>
+                <enc:EncryptionProperties><enc:EncryptionProperty>
+                    <Compression xmlns="http://www.idpf.org/2016/encryption#compression" Method="8" OriginalLength="3004"/>
+                </enc:EncryptionProperty></enc:EncryptionProperties>
+            </enc:EncryptedData>
+            <enc:EncryptedData>
+                <enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+                <KeyInfo><enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#rsa-1_5"/></KeyInfo>
+                <enc:CipherData><enc:CipherReference URI="OEBPS/ch1.xhtml"/></enc:CipherData>
+            </enc:EncryptedData>
+        </encryption>"#;
+        assert_eq!(parse(xml).unwrap(), [
+            EncryptedEntry { name: "OEBPS/fonts/a b.otf".to_string(), algorithm: Algorithm::IdpfFont, compressed: true },
+            EncryptedEntry {
+                name: "OEBPS/ch1.xhtml".to_string(),
+                algorithm: Algorithm::Other("http://www.w3.org/2001/04/xmlenc#aes128-cbc".to_string()),
+                compressed: false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn obfuscation_round_trip() {
+        let font: Vec<u8> = b"OTTO".iter().copied().chain((0..2000u32).map(|i| (i * 7) as u8)).collect();
+        let old = keys("urn:uuid:12345678-1234-1234-1234-123456789abc");
+        let new = keys(" urn:uuid:87654321-4321-4321-4321-cba987654321\n");
+        for algorithm in [Algorithm::IdpfFont, Algorithm::AdobeFont] {
+            // Obfuscating is undoing the obfuscation with an all-zero key
+            let plain = ObfuscationKeys { idpf: Some([0; 20]), adobe: Some([0; 16]) };
+            let mut obfuscated = font.clone();
+            assert!(plain.reobfuscate(&old, &algorithm, &mut obfuscated));
+            assert!(!looks_like_font(&obfuscated));
+
+            let mut moved = obfuscated.clone();
+            assert!(old.reobfuscate(&new, &algorithm, &mut moved));
+            assert_ne!(moved, obfuscated);
+            assert!(new.deobfuscate(&algorithm, &mut moved));
+            assert_eq!(moved, font);
+
+            // Only the start of the font is obfuscated
+            let length = if algorithm == Algorithm::IdpfFont { IDPF_LENGTH } else { ADOBE_LENGTH };
+            assert_eq!(obfuscated[length..], font[length..]);
+        }
+        assert!(!ObfuscationKeys::default().deobfuscate(&Algorithm::IdpfFont, &mut font.clone()));
+        assert!(!old.deobfuscate(&Algorithm::Other("aes".to_string()), &mut font.clone()));
+    }
+}
@@ -0,0 +1,457 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::{result::ZipError, write::{SimpleFileOptions, ZipWriter}, CompressionMethod, ZipArchive};
+
+use crate::audio::TempFile;
+use crate::encoding;
+use crate::encryption::{self, EncryptedEntry, ObfuscationKeys, ENCRYPTION_PATH};
+use crate::error::{Error, ParseError};
+use crate::package::{self, ManifestItem, Package};
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const MIMETYPE: &str = "application/epub+zip";
+
+/// An EPUB opened for rewriting.
+///
+/// Entries are read from the input archive on demand. Entries that have been
+/// replaced or added are kept in memory until [`EpubDocument::save`], which
+/// copies everything else straight across without recompressing it.
+pub struct EpubDocument {
+    archive: ZipArchive<File>,
+    opf_path: String,
+    opf_source: String,
+    package: Package,
+    /// Replaced and added entries, by name
+    entries: HashMap<String, Vec<u8>>,
+    /// Names of entries that are not in the input archive, in the order they were added
+    added: Vec<String>,
+    /// Added entries that are copied from a file when the book is saved, by name
+    files: HashMap<String, PathBuf>,
+    /// Files made for the book, kept until it is dropped
+    temp_files: Vec<TempFile>,
+    /// Entries listed in `encryption.xml`
+    encrypted: Vec<EncryptedEntry>,
+    /// Keys the input's fonts are obfuscated with
+    obfuscation_keys: ObfuscationKeys,
+}
+
+impl EpubDocument {
+    pub fn open(path: impl AsRef<Path>) -> Result<EpubDocument, Error> {
+        let archive = ZipArchive::new(File::open(path)?)?;
+        let mut book = EpubDocument {
+            archive,
+            opf_path: String::new(),
+            opf_source: String::new(),
+            package: Package::default(),
+            entries: HashMap::new(),
+            added: Vec::new(),
+            files: HashMap::new(),
+            temp_files: Vec::new(),
+            encrypted: Vec::new(),
+            obfuscation_keys: ObfuscationKeys::default(),
+        };
+
+        let container = book.read_text(CONTAINER_PATH)?;
+        book.opf_path = package::parse_container(&container)
+            .map_err(|source| Error::Parse { entry: CONTAINER_PATH.to_string(), source })?
+            .ok_or_else(|| Error::Malformed {
+                entry: CONTAINER_PATH.to_string(),
+                message: "no rootfile for the package document".to_string(),
+            })?;
+        book.opf_source = book.read_text(&book.opf_path.clone())?;
+        book.package = Package::parse(&book.opf_source)
+            .map_err(|source| Error::Parse { entry: book.opf_path.clone(), source })?;
+        book.obfuscation_keys = ObfuscationKeys::for_package(&book.package);
+
+        if book.contains(ENCRYPTION_PATH) {
+            let xml = book.read_text(ENCRYPTION_PATH)?;
+            book.encrypted = encryption::parse(&xml)
+                .map_err(|source| Error::Parse { entry: ENCRYPTION_PATH.to_string(), source })?;
+            book.check_encryption()?;
+        }
+
+        Ok(book)
+    }
+
+    /// Refuses books whose package, content documents or NCX are encrypted,
+    /// as there is no rewriting them without the key.
+    fn check_encryption(&self) -> Result<(), Error> {
+        let ncx = self.package.spine.toc.as_deref().and_then(|id| self.package.item(id));
+        for entry in &self.encrypted {
+            let encryption::Algorithm::Other(algorithm) = &entry.algorithm else {
+                continue;
+            };
+            let item = self.item_for_path(&entry.name);
+            if entry.name == self.opf_path || item.is_some_and(|item| item.is_content_document() || Some(item) == ncx) {
+                return Err(Error::Encrypted { entry: entry.name.clone(), algorithm: algorithm.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn package(&self) -> &Package {
+        &self.package
+    }
+
+    /// Name of the OPF package document inside the archive.
+    pub fn opf_path(&self) -> &str {
+        &self.opf_path
+    }
+
+    pub fn opf_source(&self) -> &str {
+        &self.opf_source
+    }
+
+    /// Name of the archive entry a manifest item points at.
+    pub fn item_path(&self, item: &ManifestItem) -> String {
+        package::resolve_href(&self.opf_path, &item.href)
+    }
+
+    /// Manifest item for the archive entry `name`, if there is one.
+    pub fn item_for_path(&self, name: &str) -> Option<&ManifestItem> {
+        self.package.manifest.iter().find(|item| self.item_path(item) == name)
+    }
+
+    /// XHTML content documents, in spine order followed by any that are only
+    /// listed in the manifest.
+    pub fn content_documents(&self) -> Vec<ManifestItem> {
+        let mut documents: Vec<ManifestItem> = self.package.spine.items.iter()
+            .filter_map(|itemref| self.package.item(&itemref.idref))
+            .filter(|item| item.is_content_document())
+            .cloned()
+            .collect();
+        for item in &self.package.manifest {
+            if item.is_content_document() && !documents.contains(item) {
+                documents.push(item.clone());
+            }
+        }
+        documents
+    }
+
+    /// Position of the manifest item `id` in the spine.
+    pub fn spine_index(&self, id: &str) -> Option<usize> {
+        self.package.spine.items.iter().position(|itemref| itemref.idref == id)
+    }
+
+    /// Names of every entry in the output, input entries first.
+    pub fn entry_names(&self) -> Vec<String> {
+        self.archive.file_names()
+            .map(str::to_string)
+            .chain(self.added.iter().cloned())
+            .collect()
+    }
+
+    /// Names of the entries that have been replaced or added, input entries
+    /// first.
+    pub fn changed_entries(&self) -> Vec<String> {
+        self.archive.file_names()
+            .filter(|name| self.entries.contains_key(*name) || self.files.contains_key(*name))
+            .map(str::to_string)
+            .chain(self.added.iter().cloned())
+            .collect()
+    }
+
+    /// Reads an entry as it is in the input archive, ignoring any changes.
+    /// `None` if the entry was added.
+    pub fn read_original(&mut self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        if self.archive.index_for_name(name).is_none() {
+            return Ok(None);
+        }
+        let mut file = self.archive.by_name(name)?;
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)
+            .map_err(|source| Error::Entry { entry: name.to_string(), source })?;
+        Ok(Some(data))
+    }
+
+    /// Size in bytes of an entry as it will be saved, without reading it.
+    pub fn entry_size(&mut self, name: &str) -> Result<u64, Error> {
+        if let Some(data) = self.entries.get(name) {
+            return Ok(data.len() as u64);
+        }
+        if let Some(path) = self.files.get(name) {
+            return Ok(std::fs::metadata(path)?.len());
+        }
+        Ok(self.archive.by_name(name)?.size())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name) || self.files.contains_key(name) || self.archive.index_for_name(name).is_some()
+    }
+
+    /// Reads an entry, returning the replaced contents if it has been changed.
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        if let Some(data) = self.entries.get(name) {
+            return Ok(data.clone());
+        }
+        if let Some(path) = self.files.get(name) {
+            return Ok(std::fs::read(path)?);
+        }
+        self.read_original(name)?.ok_or_else(|| Error::Zip(ZipError::FileNotFound))
+    }
+
+    pub fn read_text(&mut self, name: &str) -> Result<String, Error> {
+        let data = self.read(name)?;
+        String::from_utf8(data).map_err(|err| Error::Entry {
+            entry: name.to_string(),
+            source: io::Error::new(io::ErrorKind::InvalidData, err.utf8_error()),
+        })
+    }
+
+    /// Reads a content document as UTF-8, decoding it first if it declares
+    /// another encoding.
+    pub fn read_content_document(&mut self, name: &str) -> Result<String, Error> {
+        let data = self.read(name)?;
+        encoding::decode(&data).ok_or_else(|| Error::Entry {
+            entry: name.to_string(),
+            source: io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not valid {}", encoding::detect(&data).name()),
+            ),
+        })
+    }
+
+    /// Replaces the entry `name`, or adds it if the archive has no such entry.
+    pub fn set_entry(&mut self, name: &str, data: impl Into<Vec<u8>>) {
+        if !self.contains(name) {
+            self.added.push(name.to_string());
+        }
+        self.files.remove(name);
+        self.entries.insert(name.to_string(), data.into());
+    }
+
+    /// Adds a resource to the archive and lists it in the manifest. The item's
+    /// href is taken relative to the package document as usual.
+    pub fn add_resource(&mut self, item: ManifestItem, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        let name = self.item_path(&item);
+        self.insert_into_package("manifest", &item.to_xml())?;
+        self.set_entry(&name, data);
+        Ok(())
+    }
+
+    /// Adds the file at `path` as a resource like [`add_resource`](Self::add_resource)
+    /// does, without reading it into memory. It is copied into the archive
+    /// when the book is saved, so it has to stay where it is until then.
+    pub fn add_resource_file(&mut self, item: ManifestItem, path: impl Into<PathBuf>) -> Result<(), Error> {
+        let name = self.item_path(&item);
+        self.insert_into_package("manifest", &item.to_xml())?;
+        if !self.contains(&name) {
+            self.added.push(name.clone());
+        }
+        self.entries.remove(&name);
+        self.files.insert(name, path.into());
+        Ok(())
+    }
+
+    /// Adds a temp file like [`add_resource_file`](Self::add_resource_file)
+    /// does, and keeps it until the book is dropped.
+    pub(crate) fn add_resource_temp_file(&mut self, item: ManifestItem, file: TempFile) -> Result<(), Error> {
+        self.add_resource_file(item, file.path.clone())?;
+        self.temp_files.push(file);
+        Ok(())
+    }
+
+    /// Appends the manifest item `idref` to the reading order.
+    pub fn add_spine_item(&mut self, idref: &str, linear: bool) -> Result<(), Error> {
+        let itemref = if linear {
+            format!("<itemref idref=\"{}\"/>", package::escape_attribute(idref))
+        } else {
+            format!("<itemref idref=\"{}\" linear=\"no\"/>", package::escape_attribute(idref))
+        };
+        self.insert_into_package("spine", &itemref)
+    }
+
+    /// Appends an element such as `<meta property="...">` to the package metadata.
+    pub fn add_metadata(&mut self, xml: &str) -> Result<(), Error> {
+        self.insert_into_package("metadata", xml)
+    }
+
+    /// Sets an attribute on the manifest item `id`, replacing any previous value.
+    pub fn set_item_attribute(&mut self, id: &str, name: &str, value: &str) -> Result<(), Error> {
+        let tag = find_item_tag(&self.opf_source, id)
+            .map_err(|source| Error::Parse { entry: self.opf_path.clone(), source })?
+            .ok_or_else(|| Error::Malformed {
+                entry: self.opf_path.clone(),
+                message: format!("no manifest item with id {}", id),
+            })?;
+
+        let mut new_tag = format!("<{}", tag.name);
+        for (key, raw_value) in tag.attributes.iter().filter(|(key, _)| key != name) {
+            new_tag.push_str(&format!(" {}=\"{}\"", key, raw_value.replace('"', "&quot;")));
+        }
+        new_tag.push_str(&format!(" {}=\"{}\"", name, package::escape_attribute(value)));
+        new_tag.push_str(if tag.self_closing { "/>" } else { ">" });
+
+        let mut source = self.opf_source.clone();
+        source.replace_range(tag.range, &new_tag);
+        self.set_opf_source(source)
+    }
+
+    /// Replaces the package document and parses it again.
+    pub fn set_opf_source(&mut self, source: String) -> Result<(), Error> {
+        self.package = Package::parse(&source)
+            .map_err(|source| Error::Parse { entry: self.opf_path.clone(), source })?;
+        let opf_path = self.opf_path.clone();
+        self.set_entry(&opf_path, source.as_bytes());
+        self.opf_source = source;
+        Ok(())
+    }
+
+    /// Inserts `xml` as the last child of the package element `element`.
+    fn insert_into_package(&mut self, element: &str, xml: &str) -> Result<(), Error> {
+        let index = find_closing_tag(&self.opf_source, element).ok_or_else(|| Error::Malformed {
+            entry: self.opf_path.clone(),
+            message: format!("no closing {} tag", element),
+        })?;
+        let mut source = String::with_capacity(self.opf_source.len() + xml.len() + 8);
+        source.push_str(&self.opf_source[..index]);
+        source.push_str("  ");
+        source.push_str(xml);
+        source.push_str("\n  ");
+        source.push_str(&self.opf_source[index..]);
+        self.set_opf_source(source)
+    }
+
+    /// Writes the book to `path`.
+    ///
+    /// The mimetype is written first and stored uncompressed as the OCF spec
+    /// requires. Entries that were replaced are written from memory, added
+    /// entries come last, and everything else is raw-copied from the input.
+    /// Resources added from files are streamed in from disk. Obfuscated fonts
+    /// are obfuscated again if the book's identifier changed.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut zip_writer = ZipWriter::new(File::create(path)?);
+
+        let mimetype = match self.read("mimetype") {
+            Ok(data) => data,
+            Err(_) => MIMETYPE.as_bytes().to_vec(),
+        };
+        zip_writer.start_file("mimetype", SimpleFileOptions::default().compression_method(CompressionMethod::Stored))?;
+        zip_writer.write_all(&mimetype)?;
+
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let fonts = self.reobfuscated_fonts()?;
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index_raw(i)?;
+            let name = file.name().to_string();
+            if name == "mimetype" {
+                continue;
+            }
+            if let Some(path) = self.files.get(&name) {
+                drop(file);
+                zip_writer.start_file(name, deflated)?;
+                io::copy(&mut File::open(path)?, &mut zip_writer)?;
+                continue;
+            }
+            match self.entries.get(&name).or_else(|| fonts.get(&name)) {
+                Some(data) => {
+                    drop(file);
+                    zip_writer.start_file(name, deflated)?;
+                    zip_writer.write_all(data)?;
+                }
+                None => zip_writer.raw_copy_file(file)?,
+            }
+        }
+
+        for name in &self.added {
+            zip_writer.start_file(name.as_str(), deflated)?;
+            match self.files.get(name) {
+                Some(path) => {
+                    io::copy(&mut File::open(path)?, &mut zip_writer)?;
+                }
+                None => zip_writer.write_all(&self.entries[name])?,
+            }
+        }
+
+        zip_writer.finish()?;
+        Ok(())
+    }
+
+    /// Whether `name` is an obfuscated font whose key changed with the
+    /// book's identifier and has not been replaced.
+    fn needs_reobfuscation(&self, name: &str) -> bool {
+        !self.entries.contains_key(name)
+            && !self.files.contains_key(name)
+            && self.encrypted.iter().any(|entry| entry.name == name && entry.algorithm.is_obfuscation())
+            && ObfuscationKeys::for_package(&self.package) != self.obfuscation_keys
+    }
+
+    /// Obfuscated fonts with the new identifier's key, by name.
+    fn reobfuscated_fonts(&mut self) -> Result<HashMap<String, Vec<u8>>, Error> {
+        let keys = ObfuscationKeys::for_package(&self.package);
+        let mut fonts = HashMap::new();
+        for entry in self.encrypted.clone() {
+            if !self.needs_reobfuscation(&entry.name) {
+                continue;
+            }
+            let Some(mut font) = self.read_original(&entry.name)? else {
+                continue;
+            };
+            if !self.obfuscation_keys.reobfuscate(&keys, &entry.algorithm, &mut font) {
+                return Err(Error::Malformed {
+                    entry: entry.name,
+                    message: "cannot obfuscate the font again without a key from the identifier".to_string(),
+                });
+            }
+            fonts.insert(entry.name, font);
+        }
+        Ok(fonts)
+    }
+}
+
+/// An `<item>` tag found in the package document.
+struct ItemTag {
+    range: std::ops::Range<usize>,
+    /// Qualified name, e.g. `item` or `opf:item`
+    name: String,
+    /// Attributes with their values still escaped
+    attributes: Vec<(String, String)>,
+    self_closing: bool,
+}
+
+fn find_item_tag(source: &str, id: &str) -> Result<Option<ItemTag>, ParseError> {
+    let mut reader = Reader::from_str(source);
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) if e.local_name().as_ref() == b"item"
+                && package::attribute(e, b"id").as_deref() == Some(id) =>
+            {
+                return Ok(Some(ItemTag {
+                    self_closing: matches!(event, Event::Empty(_)),
+                    range: start..reader.buffer_position() as usize,
+                    name: String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+                    attributes: e.attributes()
+                        .flatten()
+                        .map(|attr| (
+                            String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+                            String::from_utf8_lossy(&attr.value).into_owned(),
+                        ))
+                        .collect(),
+                }));
+            }
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Finds the start of the closing tag for `element`, whatever prefix it uses.
+fn find_closing_tag(source: &str, element: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(found) = source[from..].find("</").map(|i| i + from) {
+        let name_end = source[found..].find('>').map(|i| i + found)?;
+        let name = source[found + 2..name_end].trim();
+        if name.rsplit(':').next() == Some(element) {
+            return Some(found);
+        }
+        from = name_end;
+    }
+    None
+}
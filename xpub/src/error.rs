@@ -0,0 +1,90 @@
+use std::{fmt, io};
+use zip::result::ZipError;
+
+/// Where in a chapter the parser gave up, as a byte offset into the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(offset: usize, message: impl Into<String>) -> Self {
+        ParseError { offset, message: message.into() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Zip(ZipError),
+    /// An archive entry could not be read, e.g. because it is not valid UTF-8.
+    Entry { entry: String, source: io::Error },
+    /// A content document is malformed.
+    Parse { entry: String, source: ParseError },
+    /// The book's structure is broken, e.g. the package document is missing.
+    Malformed { entry: String, message: String },
+    /// An external tool such as a TTS engine failed.
+    Command { command: String, message: String },
+    /// The book has DRM: an entry xpub has to rewrite is encrypted.
+    Encrypted { entry: String, algorithm: String },
+    /// Writing an SQLite database, such as an Anki collection, failed.
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Zip(err) => write!(f, "{}", err),
+            Error::Entry { entry, source } => write!(f, "{}: {}", entry, source),
+            Error::Parse { entry, source } => write!(f, "{}: {}", entry, source),
+            Error::Malformed { entry, message } => write!(f, "{}: {}", entry, message),
+            Error::Command { command, message } => write!(f, "{} failed: {}", command, message),
+            Error::Encrypted { entry, algorithm } => write!(
+                f,
+                "{} is encrypted with {}; the book has DRM and cannot be changed",
+                entry, algorithm,
+            ),
+            Error::Sqlite(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) | Error::Entry { source: err, .. } => Some(err),
+            Error::Zip(err) => Some(err),
+            Error::Parse { source, .. } => Some(source),
+            Error::Sqlite(err) => Some(err),
+            Error::Malformed { .. } | Error::Command { .. } | Error::Encrypted { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(err: ZipError) -> Self {
+        Error::Zip(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
@@ -0,0 +1,75 @@
+//! Small books written to temp files for the tests.
+
+use std::io::Write;
+
+use zip::write::{SimpleFileOptions, ZipWriter};
+use zip::CompressionMethod;
+
+use crate::audio::TempFile;
+
+/// Entries of an EPUB 3 book whose chapters have `bodies` as the contents of
+/// their `<body>`, at `OEBPS/c1.xhtml` and on, with a nav document listing them.
+pub(crate) fn book_entries(bodies: &[&str]) -> Vec<(String, String)> {
+    let items: String = (1..=bodies.len())
+        .map(|i| format!("<item id=\"c{0}\" href=\"c{0}.xhtml\" media-type=\"application/xhtml+xml\"/>", i))
+        .collect();
+    let itemrefs: String = (1..=bodies.len()).map(|i| format!("<itemref idref=\"c{}\"/>", i)).collect();
+    let links: String = (1..=bodies.len()).map(|i| format!("<li><a href=\"c{0}.xhtml\">Chapter {0}</a></li>", i)).collect();
+
+    let mut entries = vec![
+        ("mimetype".to_string(), "application/epub+zip".to_string()),
+        (
+            "META-INF/container.xml".to_string(),
+            "<?xml version=\"1.0\"?><container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\
+             <rootfiles><rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/></rootfiles>\
+             </container>".to_string(),
+        ),
+        (
+            "OEBPS/content.opf".to_string(),
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\">\
+                 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><dc:identifier id=\"id\">urn:uuid:1234</dc:identifier>\
+                 <dc:title>Test</dc:title><dc:language>es</dc:language></metadata>\
+                 <manifest><item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>{}</manifest>\
+                 <spine>{}</spine></package>",
+                items, itemrefs,
+            ),
+        ),
+        ("OEBPS/nav.xhtml".to_string(), xhtml(&format!("<nav epub:type=\"toc\"><ol>{}</ol></nav>", links))),
+    ];
+    entries.extend(bodies.iter().enumerate().map(|(i, body)| (format!("OEBPS/c{}.xhtml", i + 1), xhtml(body))));
+    entries
+}
+
+/// An EPUB 3 book, as [`book_entries`] describes it.
+pub(crate) fn book(bodies: &[&str]) -> TempFile {
+    archive(&book_entries(bodies))
+}
+
+/// A zip archive of `entries` in order, with `mimetype` stored and the rest deflated.
+pub(crate) fn archive(entries: &[(String, String)]) -> TempFile {
+    archive_with(entries, |name| name == "mimetype")
+}
+
+/// A zip archive of `entries` in order, storing the entries `stored` picks.
+pub(crate) fn archive_with(entries: &[(String, String)], stored: impl Fn(&str) -> bool) -> TempFile {
+    let file = TempFile::new("epub");
+    let mut zip_writer = ZipWriter::new(std::fs::File::create(&file.path).unwrap());
+    for (name, contents) in entries {
+        let method = if stored(name) { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+        zip_writer.start_file(name.as_str(), SimpleFileOptions::default().compression_method(method)).unwrap();
+        zip_writer.write_all(contents.as_bytes()).unwrap();
+    }
+    zip_writer.finish().unwrap();
+    file
+}
+
+fn xhtml(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\
+         <head><title>Test</title></head><body>{}</body></html>",
+        body,
+    )
+}
@@ -0,0 +1,332 @@
+//! A vocabulary chapter at the end of the book.
+//!
+//! The book's most frequent words, or the ones the reader does not know yet,
+//! are listed by the chapter they first appear in, so a chapter's words can
+//! be reviewed before reading it. Each entry has a gloss, the sentence the
+//! word first appears in and links back to where it is used.
+
+use std::collections::{HashMap, HashSet};
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::anki::{self, GlossProvider, Ranking, VocabularyWord};
+use crate::difficulty;
+use crate::epub::EpubDocument;
+use crate::error::{Error, ParseError};
+use crate::layout::Layout;
+use crate::package::{self, ManifestItem};
+use crate::tokenizer::{self, Token};
+use crate::transform::{Document, Transform};
+
+/// Where the chapter goes, relative to the package document.
+const HREF: &str = "xpub/vocabulary.xhtml";
+
+/// Links back to the text kept per word.
+const MAX_BACKLINKS: usize = 10;
+
+const CSS: &str = "dt { font-weight: bold; margin-top: 1em; }\n\
+    .example { font-style: italic; }\n\
+    .seen { font-size: smaller; }\n";
+
+/// Adds the vocabulary chapter to the spine and the table of contents. Add
+/// it after [`WrapWords`](crate::WrapWords), as it links to the wrapped words.
+pub struct Glossary {
+    ranking: Ranking,
+    limit: usize,
+    glosses: Option<Box<dyn GlossProvider>>,
+    title: String,
+    language: String,
+    layout: Layout,
+    words: Vec<VocabularyWord>,
+    index: HashMap<String, usize>,
+    /// Ids of the spans each word was found in, by document
+    occurrences: Vec<Vec<(String, String)>>,
+    /// Content documents in reading order with their titles
+    chapters: Vec<(String, String)>,
+}
+
+impl Glossary {
+    /// Lists every word of the book not known according to `ranking`, in
+    /// `language` unless the package says otherwise.
+    pub fn new(ranking: Ranking, language: impl Into<String>) -> Self {
+        Glossary {
+            ranking,
+            limit: usize::MAX,
+            glosses: None,
+            title: "Vocabulary".to_string(),
+            language: language.into(),
+            layout: Layout::default(),
+            words: Vec::new(),
+            index: HashMap::new(),
+            occurrences: Vec::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Only lists the `limit` highest ranked words.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn glosses(mut self, glosses: impl GlossProvider + 'static) -> Self {
+        self.glosses = Some(Box::new(glosses));
+        self
+    }
+
+    /// Title of the chapter and its entry in the table of contents.
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    fn chapter_html(&mut self, path: &str) -> Result<String, Error> {
+        let mut by_chapter: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, word) in self.words.iter().enumerate() {
+            by_chapter.entry(word.document.as_str()).or_default().push(i);
+        }
+        let titles: HashMap<&str, &str> = self.chapters.iter()
+            .map(|(name, title)| (name.as_str(), title.as_str()))
+            .collect();
+        let title_of = |name: &str| titles.get(name).map_or_else(|| file_name(name).to_string(), |title| title.to_string());
+
+        let mut order: Vec<&str> = self.chapters.iter().map(|(name, _)| name.as_str()).collect();
+        order.extend(by_chapter.keys().filter(|name| !titles.contains_key(*name)));
+        order.dedup();
+
+        let language = package::escape_attribute(&self.language);
+        let dir = if self.layout.rtl { " dir=\"rtl\"" } else { "" };
+        let title = package::escape_attribute(&self.title);
+        let mut html = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE html>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" lang=\"{language}\" xml:lang=\"{language}\"{dir}>\n\
+             <head>\n<title>{title}</title>\n<style>\n{CSS}</style>\n</head>\n\
+             <body>\n<section epub:type=\"glossary\">\n<h1>{title}</h1>\n",
+        );
+        for chapter in order {
+            let Some(entries) = by_chapter.get_mut(chapter) else {
+                continue;
+            };
+            entries.sort_by(|&a, &b| self.words[a].word.cmp(&self.words[b].word));
+            html.push_str(&format!(
+                "<section>\n<h2><a href=\"{}\">{}</a></h2>\n<dl>\n",
+                package::escape_attribute(&package::relative_href(path, chapter)),
+                package::escape_attribute(&title_of(chapter)),
+            ));
+            for &i in entries.iter() {
+                let word = &self.words[i];
+                html.push_str(&format!("<dt epub:type=\"glossterm\">{}</dt>\n<dd epub:type=\"glossdef\">\n", package::escape_attribute(&word.word)));
+                let gloss = match &mut self.glosses {
+                    Some(glosses) => glosses.gloss(&word.word)?,
+                    None => None,
+                };
+                if let Some(gloss) = gloss {
+                    html.push_str(&format!("<p class=\"gloss\">{}</p>\n", package::escape_attribute(&gloss)));
+                }
+                html.push_str(&format!(
+                    "<p class=\"example\">{}</p>\n",
+                    anki::highlight(&word.first_sentence, &word.word),
+                ));
+                if !self.occurrences[i].is_empty() {
+                    html.push_str("<p class=\"seen\">");
+                    let mut previous: Option<&str> = None;
+                    let mut number = 0;
+                    for (document, id) in &self.occurrences[i] {
+                        if previous != Some(document) {
+                            if previous.is_some() {
+                                html.push_str("; ");
+                            }
+                            html.push_str(&package::escape_attribute(&title_of(document)));
+                            html.push(':');
+                            previous = Some(document);
+                            number = 0;
+                        }
+                        number += 1;
+                        html.push_str(&format!(
+                            " <a href=\"{}#{}\">{}</a>",
+                            package::escape_attribute(&package::relative_href(path, document)),
+                            id,
+                            number,
+                        ));
+                    }
+                    html.push_str("</p>\n");
+                }
+                html.push_str("</dd>\n");
+            }
+            html.push_str("</dl>\n</section>\n");
+        }
+        html.push_str("</section>\n</body>\n</html>\n");
+        self.layout.apply(&mut html);
+        Ok(html)
+    }
+}
+
+impl Transform for Glossary {
+    fn begin(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        if let Some(language) = book.package().language() {
+            self.language = language.to_string();
+        }
+        self.layout = Layout::for_package(book.package(), &self.language);
+        self.words = anki::vocabulary(book, &self.ranking, self.limit)?;
+        self.index = self.words.iter()
+            .enumerate()
+            .map(|(i, word)| (word.word.clone(), i))
+            .collect();
+        self.occurrences = vec![Vec::new(); self.words.len()];
+        Ok(())
+    }
+
+    fn transform(&mut self, _book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        // The table of contents is no chapter of the book
+        if document.item.has_property("nav") {
+            return Ok(());
+        }
+        let title = difficulty::chapter_title(&document.content)
+            .unwrap_or_else(|| file_name(&document.name).to_string());
+        self.chapters.push((document.name.clone(), title));
+
+        // Give the first few spans of each listed word an id to link to
+        let content = &document.content;
+        let mut output = String::with_capacity(content.len());
+        let mut copied = 0;
+        let mut from = 0;
+        while let Some(start) = content[from..].find("<span class=\"xpub-word\"").map(|i| i + from) {
+            let Some(tag_end) = content[start..].find('>').map(|i| i + start) else {
+                break;
+            };
+            let Some(length) = tokenizer::find_element_end(&content[start..], "span") else {
+                break;
+            };
+            from = tag_end;
+            let tag = &content[start..tag_end];
+            let word = match tokenizer::attribute(tag, "data-word") {
+                Some(word) => tokenizer::normalize_word(&tokenizer::decode_entities(word)),
+                None => word_text(&content[tag_end + 1..start + length - "</span>".len()]),
+            };
+            let Some(&i) = self.index.get(&word) else {
+                continue;
+            };
+            if self.occurrences[i].len() >= MAX_BACKLINKS || tag.contains(" id=") {
+                continue;
+            }
+            let id = format!("xpub-vocabulary-{}-{}", i + 1, self.occurrences[i].len() + 1);
+            output.push_str(&content[copied..tag_end]);
+            output.push_str(&format!(" id=\"{}\"", id));
+            copied = tag_end;
+            self.occurrences[i].push((document.name.clone(), id));
+        }
+        if copied > 0 {
+            output.push_str(&content[copied..]);
+            document.content = output;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        if self.words.is_empty() {
+            return Ok(());
+        }
+        let path = package::resolve_href(book.opf_path(), HREF);
+        let html = self.chapter_html(&path)?;
+        let id = book.package().unused_id("xpub-vocabulary");
+        book.add_resource(ManifestItem::new(&id, HREF, "application/xhtml+xml"), html)?;
+        book.add_spine_item(&id, true)?;
+        add_to_toc(book, &path, &self.title)
+    }
+}
+
+/// Normalized text of a wrapped word, without any ruby readings over it.
+fn word_text(inner: &str) -> String {
+    let text: String = tokenizer::tokenize(inner).iter()
+        .filter(|token| !matches!(token, Token::Annotation(_)))
+        .map(|token| token.as_str())
+        .collect();
+    tokenizer::normalize_word(&tokenizer::text_content(&text))
+}
+
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+/// Lists the chapter at the end of the navigation document's table of
+/// contents, or of the NCX for EPUB 2 books.
+fn add_to_toc(book: &mut EpubDocument, path: &str, title: &str) -> Result<(), Error> {
+    let title = package::escape_attribute(title);
+    if let Some(nav) = book.package().nav_item().cloned() {
+        let nav_path = book.item_path(&nav);
+        let mut html = book.read_content_document(&nav_path)?;
+        let toc_start = html.find("epub:type=\"toc\"").unwrap_or(0);
+        let toc_end = html[toc_start..].find("</nav>").map_or(html.len(), |i| i + toc_start);
+        if let Some(list_end) = html[toc_start..toc_end].rfind("</ol>").map(|i| i + toc_start) {
+            let href = package::escape_attribute(&package::relative_href(&nav_path, path));
+            html.insert_str(list_end, &format!("<li><a href=\"{}\">{}</a></li>", href, title));
+            book.set_entry(&nav_path, html);
+        }
+        return Ok(());
+    }
+
+    let Some(ncx) = book.package().spine.toc.as_deref().and_then(|id| book.package().item(id)).cloned() else {
+        return Ok(());
+    };
+    let ncx_path = book.item_path(&ncx);
+    let mut xml = book.read_text(&ncx_path)?;
+    if let Some(map_end) = xml.rfind("</navMap>") {
+        let (ids, last_play_order) = ncx_ids(&xml).map_err(|source| Error::Parse { entry: ncx_path.clone(), source })?;
+        let id = (0..)
+            .map(|n| if n == 0 { "xpub-vocabulary".to_string() } else { format!("xpub-vocabulary-{}", n) })
+            .find(|id| !ids.contains(id))
+            .unwrap();
+        let href = package::escape_attribute(&package::relative_href(&ncx_path, path));
+        xml.insert_str(map_end, &format!(
+            "<navPoint id=\"{}\" playOrder=\"{}\"><navLabel><text>{}</text></navLabel><content src=\"{}\"/></navPoint>",
+            id, last_play_order + 1, title, href,
+        ));
+        book.set_entry(&ncx_path, xml);
+    }
+    Ok(())
+}
+
+/// Ids used in an NCX document, and its highest `playOrder`. Page lists
+/// share the play order with the nav map, so every element counts.
+fn ncx_ids(xml: &str) -> Result<(HashSet<String>, usize), ParseError> {
+    let mut reader = Reader::from_str(xml);
+    let mut ids = HashSet::new();
+    let mut last_play_order = 0;
+    loop {
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(e) | Event::Empty(e) => {
+                ids.extend(package::attribute(&e, b"id"));
+                if let Some(play_order) = package::attribute(&e, b"playOrder").and_then(|value| value.trim().parse().ok()) {
+                    last_play_order = last_play_order.max(play_order);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((ids, last_play_order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_ncx_ids_and_play_order() {
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+            <navMap>
+                <navPoint id="np1" playOrder="1"><navLabel><text>One</text></navLabel><content src="c1.xhtml"/></navPoint>
+                <navPoint id="xpub-vocabulary" playOrder="2"><navLabel><text>Two</text></navLabel><content src="c2.xhtml"/></navPoint>
+            </navMap>
+            <pageList><pageTarget id="p7" type="normal" value="7" playOrder="9"><navLabel><text>7</text></navLabel></pageTarget></pageList>
+        </ncx>"#;
+        let (ids, last_play_order) = ncx_ids(ncx).unwrap();
+        assert_eq!(last_play_order, 9);
+        assert!(ids.contains("np1") && ids.contains("xpub-vocabulary") && ids.contains("p7"));
+        assert_eq!(ncx_ids("<ncx><navMap/></ncx>").unwrap(), (HashSet::new(), 0));
+    }
+}
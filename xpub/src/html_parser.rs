@@ -1,16 +1,57 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::epub::EpubDocument;
+use crate::layout::Layout;
+use crate::error::{Error, ParseError};
+use crate::package;
+use crate::phrases::PhraseList;
+use crate::ruby::{self, Piece, Ruby};
+use crate::tokenizer::{self, is_tag_named, tokenize, Token, WordClass};
+use crate::translit::Transliterator;
+use crate::transform::{Document, Transform};
+
 const SCRIPT: &str = r#"
-<div id="myModal" class="modal">
-    <div class="modal-content" id="modal-content">
+<div id="myModal" class="modal" dir="ltr">
+    <div class="modal-content" id="modal-content" role="dialog" aria-modal="true" aria-labelledby="original" aria-describedby="translation">
+        <button id="close-btn" class="close" aria-label="Close">×</button>
         <div id="container">
             <div class="word-box">
-                <h1 id="original"></h1>
+                <h1 id="original" dir="auto"></h1>
+                <span id="transliteration" class="transliteration"></span>
                 <button id="audio-btn">Play</button>
+                <button id="export-btn" title="Download every word looked up so far">Export</button>
+                <select id="target-lang" title="Translate into">
+                    <option value="">Auto</option>
+                    <option value="en">English</option>
+                    <option value="es">Español</option>
+                    <option value="fr">Français</option>
+                    <option value="de">Deutsch</option>
+                    <option value="it">Italiano</option>
+                    <option value="pt">Português</option>
+                    <option value="nl">Nederlands</option>
+                    <option value="pl">Polski</option>
+                    <option value="ru">Русский</option>
+                    <option value="uk">Українська</option>
+                    <option value="tr">Türkçe</option>
+                    <option value="ar">العربية</option>
+                    <option value="he">עברית</option>
+                    <option value="hi">हिन्दी</option>
+                    <option value="zh">中文</option>
+                    <option value="ja">日本語</option>
+                    <option value="ko">한국어</option>
+                </select>
             </div>
-            <div id="translation"></div>
+            <div id="translation" dir="auto" aria-live="polite"></div>
+        </div>
+        <div id="lookup-error" class="lookup-error" role="alert" hidden="hidden">
+            <span id="error-message"></span>
+            <button id="retry-btn">Retry</button>
         </div>
-        <div id="spinner"></div>
+        <div id="spinner" role="status" aria-label="Loading"></div>
     </div>
 </div>
+<button id="readings-toggle" class="readings-toggle" dir="ltr" title="Show or hide readings">Readings</button>
 
 <style>
     .word-box {
@@ -23,6 +64,7 @@ const SCRIPT: &str = r#"
     /* CSS for modal */
     .modal {
         display: none; /* Hidden by default */
+        writing-mode: horizontal-tb; /* Even in vertical books */
         position: fixed; /* Stay in place */
         z-index: 1; /* Sit on top */
         left: 0;
@@ -41,9 +83,47 @@ const SCRIPT: &str = r#"
         border: 1px solid #888;
     }
 
+    .lookup-error {
+        color: #b00020;
+    }
+
+    .transliteration {
+        direction: ltr;
+        unicode-bidi: isolate;
+        align-self: center;
+        color: #666;
+        font-style: italic;
+    }
+
+    .readings-toggle {
+        display: none;
+        position: fixed;
+        writing-mode: horizontal-tb;
+        inset-inline-end: 10px;
+        bottom: 10px;
+        z-index: 1;
+    }
+
+    .hide-readings rt,
+    .hide-readings rp {
+        display: none;
+    }
+
+    .xpub-word[data-phrase] {
+        text-decoration: underline dotted;
+    }
+
+    .xpub-word:focus-visible {
+        outline: 2px solid #3498db;
+        outline-offset: 1px;
+    }
+
     .close {
+        background: none;
+        border: none;
         color: #aaa;
         float: right;
+        float: inline-end;
         font-size: 28px;
         font-weight: bold;
     }
@@ -56,6 +136,7 @@ const SCRIPT: &str = r#"
     }
 </style>
 <script type="text/javascript">
+//<![CDATA[
     const CircularLoader = {
         loader: null,
         canvas: null,
@@ -131,51 +212,133 @@ const SCRIPT: &str = r#"
         }
     };
 
-    const getSpeechFromText = async (text, language) => {
+    // Requests give up after this long, so a backend that is down does not
+    // leave the popup loading forever
+    const REQUEST_TIMEOUT_MS = 10000;
+
+    const post = async (path, body) => {
+        const controller = new AbortController();
+        const timeout = setTimeout(() => controller.abort(), REQUEST_TIMEOUT_MS);
         try {
-            const response = await fetch('http://localhost:3000/speech', {
+            const response = await fetch(`http://localhost:3000${path}`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
                 },
-                body: JSON.stringify({ text, language }),
+                body: JSON.stringify(body),
+                signal: controller.signal,
             });
-
             if (!response.ok) {
                 throw new Error(`HTTP error! status: ${response.status}`);
             }
-
-            const audioBlob = await response.blob();
-            return audioBlob;
+            return response;
         } catch (error) {
-            console.error("Error:", error);
+            throw error.name === 'AbortError' ? new Error('The request timed out') : error;
+        } finally {
+            clearTimeout(timeout);
         }
     };
 
+    const getSpeechFromText = async (text, language) => {
+        const response = await post('/speech', { text, language });
+        return response.blob();
+    };
+
     const getTranslation = async (
         text, 
         language, 
+        target_language,
     ) => {
-        try {
-            const response = await fetch('http://localhost:3000/translate', {
-                method: 'POST',
-                headers: {
-                    'Content-Type': 'application/json',
-                },
-                body: JSON.stringify({ text, language }),
-            });
-            
-            if (!response.ok) {
-                throw new Error('Translation request failed');
+        const response = await post('/translate', { text, language, target_language });
+        const data = await response.json();
+        if (typeof data.translated_text !== 'string') {
+            throw new Error('Translation request failed');
+        }
+        return data.translated_text;
+    };
+
+    // Translations and speech already fetched are kept in IndexedDB, keyed by
+    // the text and its languages, so they also work offline. Without
+    // IndexedDB every lookup goes to the backend.
+    const cache = {
+        db: null,
+
+        open() {
+            if (!this.db) {
+                this.db = new Promise((resolve, reject) => {
+                    const request = window.indexedDB.open('xpub-cache', 1);
+                    request.onupgradeneeded = () => {
+                        request.result.createObjectStore('translations');
+                        request.result.createObjectStore('speech');
+                    };
+                    request.onsuccess = () => resolve(request.result);
+                    request.onerror = () => reject(request.error);
+                });
             }
-            
-            const data = await response.json();
-            return data.translated_text;
+            return this.db;
+        },
+
+        async get(store, key) {
+            try {
+                const db = await this.open();
+                return await new Promise((resolve, reject) => {
+                    const request = db.transaction(store).objectStore(store).get(key);
+                    request.onsuccess = () => resolve(request.result);
+                    request.onerror = () => reject(request.error);
+                });
+            } catch (error) {
+                return undefined;
+            }
+        },
+
+        async put(store, key, value) {
+            try {
+                const db = await this.open();
+                db.transaction(store, 'readwrite').objectStore(store).put(value, key);
+            } catch (error) {
+                console.error(error);
+            }
+        },
+    };
+
+    async function cachedTranslation(text, language, target_language) {
+        const key = JSON.stringify([language, target_language, text]);
+        const cached = await cache.get('translations', key);
+        if (cached !== undefined) {
+            return cached;
+        }
+        const translated = await getTranslation(text, language, target_language);
+        cache.put('translations', key, translated);
+        return translated;
+    }
+
+    // Resolves to undefined if the backend cannot speak the text
+    async function cachedSpeech(text, language) {
+        const key = JSON.stringify([language, text]);
+        const cached = await cache.get('speech', key);
+        if (cached !== undefined) {
+            return cached;
+        }
+        try {
+            const audioBlob = await getSpeechFromText(text, language);
+            cache.put('speech', key, audioBlob);
+            return audioBlob;
         } catch (error) {
             console.error(error);
-            throw error;
+            return undefined;
         }
-    };
+    }
+
+    // The browser's own voices, for when there is no recording
+    function speak(text) {
+        if (!('speechSynthesis' in window)) {
+            return;
+        }
+        const utterance = new SpeechSynthesisUtterance(text);
+        utterance.lang = language;
+        window.speechSynthesis.cancel();
+        window.speechSynthesis.speak(utterance);
+    }
 
     function stripPuncs(text) {
         return text
@@ -186,33 +349,69 @@ const SCRIPT: &str = r#"
             .replace('¿', '');
     }
 
-    const getSelectedText = () => {
-        // window.getSelection() method
-        if (window.getSelection) {
-            return window.getSelection().toString();
-        }
-        // document.selection for older IE versions
-        else if (document.selection && document.selection.type != "Control") {
-            return document.selection.createRange().text;
-        }
-        return '';
-    }
-
-    // Event listener for when text is selected
-    document.addEventListener('mouseup', function() {
-        const selectedText = getSelectedText();
-        if (selectedText) {
-            console.log('Selected text:', selectedText);
-        }
-    });
-
     const modal = document.getElementById('myModal');
     const modalContent = document.getElementById('modal-content');
     const original = document.getElementById('original');
+    const transliteration = document.getElementById('transliteration');
     const translation = document.getElementById('translation');
     const audioButton = document.getElementById('audio-btn');
+    const exportButton = document.getElementById('export-btn');
     const container = document.getElementById('container');
     const spinner = document.getElementById('spinner');
+    const targetLanguageSelect = document.getElementById('target-lang');
+    const readingsToggle = document.getElementById('readings-toggle');
+    const closeButton = document.getElementById('close-btn');
+    const errorBox = document.getElementById('lookup-error');
+    const errorMessage = document.getElementById('error-message');
+    const retryButton = document.getElementById('retry-btn');
+
+    // Some readers disable localStorage, the choice then lasts for the page only
+    const storage = {
+        get(key) {
+            try {
+                return window.localStorage.getItem(key);
+            } catch (error) {
+                return null;
+            }
+        },
+        set(key, value) {
+            try {
+                window.localStorage.setItem(key, value);
+            } catch (error) {
+                console.error(error);
+            }
+        },
+    };
+
+    const TARGET_LANGUAGE_KEY = 'xpub-target-language';
+    let targetLanguage = storage.get(TARGET_LANGUAGE_KEY) ?? defaultTargetLanguage;
+    if (targetLanguage && !targetLanguageSelect.querySelector(`option[value="${targetLanguage}"]`)) {
+        const option = document.createElement('option');
+        option.value = targetLanguage;
+        option.innerText = targetLanguage;
+        targetLanguageSelect.appendChild(option);
+    }
+    targetLanguageSelect.value = targetLanguage || '';
+
+    const READINGS_KEY = 'xpub-show-readings';
+    function showReadings(show) {
+        document.body.classList.toggle('hide-readings', !show);
+        storage.set(READINGS_KEY, show ? 'true' : 'false');
+    }
+    readingsToggle.onclick = function () {
+        showReadings(document.body.classList.contains('hide-readings'));
+    }
+    if (document.querySelector('rt')) {
+        readingsToggle.style.display = 'block';
+        showReadings(storage.get(READINGS_KEY) !== 'false');
+    }
+
+    // Readings in <rt> are not part of the word
+    function textWithoutReadings(element) {
+        const copy = element.cloneNode(true);
+        copy.querySelectorAll('rt, rp').forEach(annotation => annotation.remove());
+        return copy.textContent;
+    }
 
     const loader = Object.create(CircularLoader);
     loader.init(spinner, {
@@ -222,155 +421,885 @@ const SCRIPT: &str = r#"
         color: '#3498db'
     });
 
+    const isModalOpen = () => modal.style.display === 'block';
+
+    function openModal() {
+        if (!isModalOpen()) {
+            modal.style.display = 'block';
+            closeButton.focus();
+        }
+    }
+
+    // Focus goes back to the word that was looked up
+    function closeModal() {
+        modal.style.display = 'none';
+        if (lookedUpElement) {
+            lookedUpElement.focus();
+        }
+    }
+
+    closeButton.onclick = closeModal;
+
     window.onclick = function(event) {
         if (event.target == modal) {
-            modal.style.display = 'none';
+            closeModal();
+        }
+    }
+
+    // Tab and Shift+Tab cycle through the controls of the open popup
+    function trapFocus(event) {
+        const focusable = Array.from(modalContent.querySelectorAll('button, select'))
+            .filter(element => element.offsetParent !== null);
+        if (focusable.length === 0) {
+            return;
+        }
+        const first = focusable[0];
+        const last = focusable[focusable.length - 1];
+        if (!modalContent.contains(document.activeElement)) {
+            event.preventDefault();
+            first.focus();
+        } else if (event.shiftKey && document.activeElement === first) {
+            event.preventDefault();
+            last.focus();
+        } else if (!event.shiftKey && document.activeElement === last) {
+            event.preventDefault();
+            first.focus();
+        }
+    }
+
+    // Only one word is in the tab order at a time, the arrow keys move it
+    // along in reading order and Enter or Space looks the word up
+    function makeCurrentWord(word) {
+        document.querySelectorAll('.xpub-word[tabindex="0"]').forEach(current => current.setAttribute('tabindex', '-1'));
+        word.setAttribute('tabindex', '0');
+    }
+
+    function arrowStep(key, word) {
+        const style = getComputedStyle(word);
+        if (style.writingMode.startsWith('vertical')) {
+            return { ArrowDown: 1, ArrowLeft: 1, ArrowUp: -1, ArrowRight: -1 }[key] || 0;
         }
+        const forward = style.direction === 'rtl' ? 'ArrowLeft' : 'ArrowRight';
+        const backward = style.direction === 'rtl' ? 'ArrowRight' : 'ArrowLeft';
+        return { [forward]: 1, ArrowDown: 1, [backward]: -1, ArrowUp: -1 }[key] || 0;
     }
 
+    document.addEventListener('keydown', function (event) {
+        if (isModalOpen()) {
+            if (event.key === 'Escape') {
+                event.preventDefault();
+                closeModal();
+            } else if (event.key === 'Tab') {
+                trapFocus(event);
+            }
+            return;
+        }
+        const word = event.target.closest && event.target.closest('.xpub-word');
+        if (!word) {
+            return;
+        }
+        if (event.key === 'Enter' || event.key === ' ') {
+            event.preventDefault();
+            window.translate(word);
+            return;
+        }
+        const step = arrowStep(event.key, word);
+        if (step !== 0) {
+            event.preventDefault();
+            const words = Array.from(document.querySelectorAll('.xpub-word'));
+            const next = words[words.indexOf(word) + step];
+            if (next) {
+                makeCurrentWord(next);
+                next.focus();
+            }
+        }
+    });
+
     window.currentAudioBlob = undefined;
     audioButton.onclick = async function () {
         if (window.currentAudioBlob) {
             const audioUrl = URL.createObjectURL(window.currentAudioBlob);
             const audio = new Audio(audioUrl);
-            await audio.play();
+            try {
+                await audio.play();
+                return;
+            } catch (error) {
+                console.error(error);
+            }
+        }
+        speak(original.innerText);
+    }
+
+    // Lookups are kept in the shape of flashcards segment files, so an export
+    // can be fed straight to `xpub export-lookups`
+    const LOOKUPS_KEY = 'xpub-lookups';
+    let lookedUpElement = null;
+
+    function loadLookups() {
+        try {
+            return JSON.parse(storage.get(LOOKUPS_KEY)) || [];
+        } catch (error) {
+            return [];
+        }
+    }
+
+    function saveLookup(word, translated, element) {
+        const context = element && (element.closest('.xpub-sentence') || element.closest('p'));
+        const lookup = {
+            text: context ? textWithoutReadings(context).replace(/\s+/g, ' ').trim() : word,
+            media_path: '',
+            language,
+            word,
+            translation: translated,
+            chapter: document.title || location.pathname.split('/').pop(),
+        };
+        const lookups = loadLookups().filter(saved => saved.word !== word || saved.text !== lookup.text);
+        lookups.push(lookup);
+        storage.set(LOOKUPS_KEY, JSON.stringify(lookups));
+    }
+
+    exportButton.onclick = function () {
+        const blob = new Blob([JSON.stringify(loadLookups(), null, 2)], { type: 'application/json' });
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(blob);
+        link.download = 'xpub-lookups.json';
+        document.body.appendChild(link);
+        link.click();
+        link.remove();
+    }
+
+    // Text selected in the book, without ruby readings, or '' if there is
+    // no selection or it is in the popup
+    function selectedPhrase() {
+        const selection = window.getSelection();
+        if (!selection || selection.isCollapsed || selection.rangeCount === 0 || modal.contains(selection.anchorNode)) {
+            return '';
+        }
+        const fragment = selection.getRangeAt(0).cloneContents();
+        fragment.querySelectorAll('rt, rp').forEach(annotation => annotation.remove());
+        return fragment.textContent.replace(/\s+/g, ' ').trim();
+    }
+
+    function wordOf(node) {
+        const element = node && (node.nodeType === Node.ELEMENT_NODE ? node : node.parentElement);
+        return element && element.closest('.xpub-word');
+    }
+
+    // Selecting a phrase looks it up as a whole
+    function lookUpSelection() {
+        const phrase = selectedPhrase();
+        if (phrase) {
+            lookedUpElement = wordOf(window.getSelection().anchorNode);
+            lookUp(stripPuncs(phrase));
+        }
+    }
+
+    document.addEventListener('mouseup', function (event) {
+        if (!modal.contains(event.target)) {
+            lookUpSelection();
+        }
+    });
+
+    // On touch screens a long press on a word selects it, and a long press on
+    // another word extends the selection to there and looks up the phrase
+    const LONG_PRESS_MS = 500;
+    let longPressTimer = null;
+    let longPressed = false;
+    let phraseStart = null;
+
+    document.addEventListener('touchstart', function (event) {
+        const word = wordOf(event.target);
+        longPressed = false;
+        if (!word || event.touches.length !== 1) {
+            return;
+        }
+        longPressTimer = setTimeout(function () {
+            longPressed = true;
+            const selection = window.getSelection();
+            if (phraseStart && phraseStart !== word) {
+                const forward = phraseStart.compareDocumentPosition(word) & Node.DOCUMENT_POSITION_FOLLOWING;
+                const [first, last] = forward ? [phraseStart, word] : [word, phraseStart];
+                selection.setBaseAndExtent(first, 0, last, last.childNodes.length);
+                phraseStart = null;
+                lookUpSelection();
+            } else {
+                selection.selectAllChildren(word);
+                phraseStart = word;
+            }
+        }, LONG_PRESS_MS);
+    }, { passive: true });
+
+    function cancelLongPress(event) {
+        clearTimeout(longPressTimer);
+        // The tap that ends a long press is not a click on the word
+        if (longPressed && event.type === 'touchend' && event.cancelable) {
+            event.preventDefault();
         }
     }
+    document.addEventListener('touchend', cancelLongPress);
+    document.addEventListener('touchmove', cancelLongPress, { passive: true });
+    document.addEventListener('touchcancel', cancelLongPress);
+    document.addEventListener('contextmenu', function (event) {
+        if (wordOf(event.target)) {
+            event.preventDefault();
+        }
+    });
 
     window.addEventListener('DOMContentLoaded', function () {
+        const firstWord = document.querySelector('.xpub-word');
+        if (firstWord) {
+            makeCurrentWord(firstWord);
+        }
+
         window.translate = function(element) {
-            container.style.display = 'none';
-            loader.start();
-            loader.show();
-            modal.style.display = 'block';
-            window.currentAudioBlob = undefined;
-            const text = stripPuncs(element.innerText);
-            getTranslation(text, language).then(translated_text => {
-                original.innerText = text;
-                translation.innerText = translated_text;
-                window.currentAudioBlob = 
-                getSpeechFromText(text, language).then(audioBlob => {
-                    window.currentAudioBlob = audioBlob;
-                    loader.stop();
-                    loader.hide();
-                    container.style.display = 'block';
-                });
-            });
+            // Releasing the mouse over a selection already looked it up
+            if (selectedPhrase()) {
+                return;
+            }
+            phraseStart = null;
+            lookedUpElement = element;
+            makeCurrentWord(element);
+            lookUp(stripPuncs(element.dataset.word ?? textWithoutReadings(element)), element.dataset.translit);
         }
     });
 
+    targetLanguageSelect.onchange = function () {
+        targetLanguage = targetLanguageSelect.value || null;
+        storage.set(TARGET_LANGUAGE_KEY, targetLanguage || '');
+        if (original.innerText) {
+            lookUp(original.innerText, transliteration.innerText);
+        }
+    }
+
+    // Responses to lookups that have since been replaced by another are dropped
+    let currentLookup = 0;
+    let lastLookup = null;
+
+    retryButton.onclick = function () {
+        if (lastLookup) {
+            lookUp(...lastLookup);
+        }
+    }
+
+    async function lookUp(text, romanized) {
+        const lookup = ++currentLookup;
+        lastLookup = [text, romanized];
+        container.style.display = 'none';
+        errorBox.hidden = true;
+        loader.start();
+        loader.show();
+        openModal();
+        window.currentAudioBlob = undefined;
+
+        let translated_text;
+        try {
+            translated_text = await cachedTranslation(text, language, targetLanguage);
+        } catch (error) {
+            console.error(error);
+            if (lookup === currentLookup) {
+                loader.hide();
+                errorMessage.innerText = `Could not look up "${text}": ${error.message}`;
+                errorBox.hidden = false;
+                retryButton.focus();
+            }
+            return;
+        }
+        if (lookup !== currentLookup) {
+            return;
+        }
+
+        original.innerText = text;
+        transliteration.innerText = romanized || '';
+        translation.innerText = translated_text;
+        saveLookup(text, translated_text, lookedUpElement);
+        loader.hide();
+        container.style.display = 'block';
+
+        // Until the recording arrives, or if there is none, Play uses the
+        // browser's voices
+        const audioBlob = await cachedSpeech(text, language);
+        if (lookup === currentLookup) {
+            window.currentAudioBlob = audioBlob;
+        }
+    }
+
+//]]>
 </script>"#;
 
-fn add_language_to_script(script: &str, language: &str) -> String {
+/// Opening tag of a clickable word, without its closing `>`. Words are left
+/// out of the tab order; the script makes one of them focusable at a time
+/// and moves between them with the arrow keys.
+const WORD_SPAN: &str = "<span class=\"xpub-word\" role=\"button\" tabindex=\"-1\" onclick=\"window.translate(this)\"";
+
+/// Settings the injected lookup script is built with.
+#[derive(Debug, Clone)]
+pub struct LookupConfig {
+    /// Language of the book
+    pub language: String,
+    /// Language lookups are translated into. The reader can change it from
+    /// the popup; `None` leaves the choice to the backend.
+    pub target_language: Option<String>,
+}
+
+impl LookupConfig {
+    pub fn new(language: impl Into<String>) -> Self {
+        LookupConfig { language: language.into(), target_language: None }
+    }
+
+    pub fn target_language(mut self, target_language: impl Into<String>) -> Self {
+        self.target_language = Some(target_language.into());
+        self
+    }
+}
+
+/// Prefix of the ids given to sentence spans, followed by a counter that
+/// starts at 1 in every document.
+pub const SENTENCE_ID_PREFIX: &str = "xpub-s";
+
+/// What the word wrapping pass marks up besides the words themselves.
+#[derive(Debug, Clone, Default)]
+pub struct WrapOptions {
+    /// Wrap each sentence in a `<span class="xpub-sentence">` with an id, for
+    /// media overlays to point at.
+    pub mark_sentences: bool,
+    /// Split Chinese and Japanese text into dictionary words and put their
+    /// readings over them.
+    pub ruby: Option<Ruby>,
+    /// Romanize every word into a `data-translit` attribute.
+    pub transliterator: Option<Transliterator>,
+    /// Also show the romanization as ruby over the word.
+    pub transliteration_ruby: bool,
+    /// Make each listed multi-word expression one clickable unit.
+    pub phrases: Option<PhraseList>,
+    /// Leave capitalized words inside sentences unclickable, as they are
+    /// likely names, unless the document also has them in lower case.
+    pub skip_proper_nouns: bool,
+    /// Capitalized words to keep clickable anyway, in lower case.
+    pub allowed_capitalized: HashSet<String>,
+}
+
+/// Counters that run across the paragraphs of one document.
+#[derive(Debug, Default)]
+struct WrapState {
+    sentences: usize,
+    /// Words written in lower case somewhere in the document, which are not
+    /// names where they are capitalized
+    lowercase: HashSet<String>,
+}
+
+/// Wraps every word of every paragraph in a clickable span and injects the
+/// lookup popup into each content document.
+pub struct WrapWords {
+    config: LookupConfig,
+    options: WrapOptions,
+    layout: Layout,
+}
+
+impl WrapWords {
+    pub fn new(config: LookupConfig) -> Self {
+        WrapWords { config, options: WrapOptions::default(), layout: Layout::default() }
+    }
+
+    pub fn options(mut self, options: WrapOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl Transform for WrapWords {
+    fn begin(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        self.layout = Layout::for_package(book.package(), &self.config.language);
+        Ok(())
+    }
+
+    fn transform(&mut self, _book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        self.layout.apply(&mut document.content);
+        document.content = wrap_document(&document.content, &self.config, &self.options, self.layout)
+            .map_err(|source| document.parse_error(source))?;
+        Ok(())
+    }
+}
+
+fn add_config_to_script(script: &str, config: &LookupConfig) -> String {
     let mut result = String::new();
-    let mut lines = script.lines();
     
-    while let Some(line) = lines.next() {
+    for line in script.lines() {
         result.push_str(line);
         result.push('\n');
         
         if line.trim() == "<script type=\"text/javascript\">" {
-            result.push_str(format!("    const language = \"{}\";", language).as_str());
-            result.push('\n');
+            result.push_str(&format!("    const language = {};\n", js_string(&config.language)));
+            let target_language = config.target_language.as_deref().map_or("null".to_string(), js_string);
+            result.push_str(&format!("    const defaultTargetLanguage = {};\n", target_language));
         }
     }
     
     result
 }
 
-pub fn wrap_words_in_paragraphs(html: &str, language: &str) -> String {
-    let mut html_string = String::from(html);
-    let script_with_language = add_language_to_script(SCRIPT, language);
-    
-    if let Some(body_index) = html_string.rfind("</body>") {
-        html_string.insert_str(body_index, &script_with_language);
-    } else {
-        // If there's no closing body tag, we could just append it at the end
-        html_string.push_str(&script_with_language);
+/// Quotes `value` as a JavaScript string literal that is safe inside a script element.
+fn js_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '<' => quoted.push_str("\\u003c"),
+            '>' => quoted.push_str("\\u003e"),
+            '&' => quoted.push_str("\\u0026"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn wrap_words_in_paragraphs(html: &str, config: &LookupConfig) -> Result<String, ParseError> {
+    wrap_document(html, config, &WrapOptions::default(), Layout::default())
+}
+
+fn wrap_document(html: &str, config: &LookupConfig, options: &WrapOptions, layout: Layout) -> Result<String, ParseError> {
+    let mut state = WrapState::default();
+    if options.skip_proper_nouns {
+        for paragraph in paragraphs(html) {
+            for token in tokenize(paragraph).iter().filter(|token| token.is_word()) {
+                let plain = tokenizer::text_content(token.as_str());
+                if tokenizer::classify(&plain) == WordClass::Word {
+                    state.lowercase.insert(tokenizer::normalize_word(&plain));
+                }
+            }
+        }
+    }
+    let mut script_with_language = add_config_to_script(SCRIPT, config);
+    if layout.rtl {
+        script_with_language = script_with_language.replace("dir=\"ltr\"", "dir=\"rtl\"");
     }
+    // If there's no closing body tag the script is just appended at the end
+    let body_index = html.rfind("</body>").unwrap_or(html.len());
 
+    // Everything is written into a single output buffer in one pass over the
+    // input, so the cost stays linear in the size of the chapter.
+    let mut output = String::with_capacity(html.len() * 4 + script_with_language.len());
     let mut current_index = 0;
-    
-    while let Some(index) = find_substring_from_index(&html_string, "<p", current_index) {
-        if current_index > html_string.len() {
-            panic!("Current index beyond bounds of html string.");
-        }
-        if let Some(closing_p_index) = find_substring_from_index(&html_string, "</p>", index) {
-            let opening_tag_end = html_string[index..].find('>').map(|i| i + index + 1)
-                .unwrap_or_else(|| panic!("Malformed html. Could not find closing '>' for opening p tag."));
-            let paragraph_text = &html_string[opening_tag_end..closing_p_index];
-            let mut modified_p_text = String::new();
-            let mut inside_word = false;
-            let mut inside_span = false;
-            let mut inside_tag = false;
-            let mut current_span = String::new();
-            let mut span_buffer = String::new();
-            let mut tag_buffer = String::new();
-            
-            let mut chars = paragraph_text.chars().peekable();
-            while let Some(c) = chars.next() {
-                if inside_span {
-                    current_span.push(c);
-                    if current_span.ends_with("</span>") {
-                        // Wrap the existing span content
-                        span_buffer.push_str(&format!("<span onclick=\"window.translate(this)\">{}</span>", current_span));
-                        modified_p_text.push_str(&span_buffer);
-                        span_buffer.clear();
-                        inside_span = false;
-                        current_span.clear();
-                    }
-                } else if inside_tag {
-                    tag_buffer.push(c);
-                    if c == '>' {
-                        inside_tag = false;
-                        if inside_word {
-                            modified_p_text.push_str("</span>");
-                            inside_word = false;
-                        }
-                        modified_p_text.push_str(&tag_buffer);
-                        tag_buffer.clear();
-                    }
-                } else if c == '<' {
-                    if chars.peek() == Some(&'s') {
-                        // Start of a <span> tag
-                        current_span.push(c);
-                        inside_span = true;
-                    } else {
-                        // Start of another tag
-                        inside_tag = true;
-                        tag_buffer.push(c);
-                    }
-                } else if c.is_whitespace() {
-                    if inside_word {
-                        modified_p_text.push_str("</span>");
-                        inside_word = false;
-                    }
-                    modified_p_text.push(c);
-                } else {
-                    if !inside_word {
-                        modified_p_text.push_str("<span onclick=\"window.translate(this)\">");
-                        inside_word = true;
-                    }
-                    modified_p_text.push(c);
+
+    while let Some(index) = find_paragraph_from_index(&html[..body_index], current_index) {
+        let opening_tag_end = html[index..body_index].find('>').map(|i| i + index + 1)
+            .ok_or_else(|| ParseError::new(index, "could not find closing '>' for opening p tag"))?;
+        let closing_p_index = find_substring_from_index(&html[..body_index], "</p>", opening_tag_end)
+            .ok_or_else(|| ParseError::new(index, "could not find closing p tag"))?;
+
+        output.push_str(&html[current_index..opening_tag_end]);
+        let paragraph_language = element_language(&html[index..opening_tag_end]).unwrap_or(&config.language);
+        wrap_words(&html[opening_tag_end..closing_p_index], options, &config.language, paragraph_language, &mut state, &mut output);
+        output.push_str("</p>");
+        current_index = closing_p_index + 4;
+    }
+
+    output.push_str(&html[current_index..body_index]);
+    output.push_str(&script_with_language);
+    output.push_str(&html[body_index..]);
+    Ok(output)
+}
+
+/// Wraps the words of a paragraph in `paragraph_language`. Words in another
+/// language than the book's are left alone, and so are numbers, addresses
+/// and, if asked for, names.
+fn wrap_words(
+    paragraph_text: &str,
+    options: &WrapOptions,
+    language: &str,
+    paragraph_language: &str,
+    state: &mut WrapState,
+    output: &mut String,
+) {
+    let tokens = tokenize(paragraph_text);
+    let sentence_starts: HashSet<usize> = if options.skip_proper_nouns {
+        tokenizer::sentences(&tokens).into_iter().map(|sentence| sentence.start).collect()
+    } else {
+        HashSet::new()
+    };
+    // Elements open at the current token, and whether each is in another language
+    let mut open: Vec<(&str, bool)> = vec![("", is_other_language(paragraph_language, language))];
+
+    let mut sentences = if options.mark_sentences { tokenizer::sentences(&tokens) } else { Vec::new() };
+    // A sentence can only get its own span if its markup nests properly,
+    // otherwise the whole paragraph becomes one sentence.
+    if !sentences.iter().all(|sentence| tokenizer::is_balanced(&tokens[sentence.clone()])) {
+        sentences.clear();
+        sentences.push(0..tokens.len());
+    }
+    let mut sentences = sentences.into_iter().peekable();
+
+    let mut i = 0;
+    while i < tokens.len() {
+        if sentences.peek().is_some_and(|sentence| sentence.start == i) {
+            state.sentences += 1;
+            output.push_str(&format!("<span class=\"xpub-sentence\" id=\"{}{}\">", SENTENCE_ID_PREFIX, state.sentences));
+        }
+
+        let token = &tokens[i];
+        if let Token::Tag(tag) = token {
+            track_language(tag, language, &mut open);
+        }
+        let foreign = open.last().is_some_and(|&(_, foreign)| foreign) || matches!(token,
+            Token::Element(element) if element_language(element).is_some_and(|lang| is_other_language(lang, language)));
+
+        // A phrase may not run past the end of its sentence
+        let sentence_end = sentences.peek().map_or(tokens.len(), |sentence| sentence.end);
+        if foreign || !is_lookup(&tokens, i, &sentence_starts, options, state) {
+            output.push_str(token.as_str());
+            i += 1;
+        } else if let Some(length) = options.phrases.as_ref().and_then(|phrases| phrase_at(&tokens[i..sentence_end], phrases)) {
+            wrap_phrase(&tokens[i..i + length], options.transliterator.as_ref(), output);
+            i += length;
+        } else {
+            wrap_token(&tokens[i], options, output);
+            i += 1;
+        }
+
+        if sentences.peek().is_some_and(|sentence| sentence.end == i) {
+            output.push_str("</span>");
+            sentences.next();
+        }
+    }
+}
+
+fn wrap_token(token: &Token, options: &WrapOptions, output: &mut String) {
+    match token {
+        Token::Word(text) if options.ruby.is_some() && Ruby::applies_to(text) => {
+            wrap_with_readings(text, options.ruby.as_ref().unwrap(), output);
+        }
+        Token::Word(text) | Token::Element(text) => match &options.transliterator {
+            Some(transliterator) => wrap_with_transliteration(token, transliterator, options.transliteration_ruby, output),
+            None => {
+                open_word_span(text, output);
+                output.push('>');
+                output.push_str(text);
+                output.push_str("</span>");
+            }
+        },
+        Token::Tag(text) | Token::Annotation(text) | Token::Space(text) => output.push_str(text),
+    }
+}
+
+/// Starts the span of a clickable word, up to its closing `>`. Entity and
+/// character references stay as they are in the markup, so a word that has
+/// any gets its decoded text in `data-word` for the lookup.
+fn open_word_span(text: &str, output: &mut String) {
+    output.push_str(WORD_SPAN);
+    if text.contains('&') {
+        let word = tokenizer::text_content(text);
+        output.push_str(&format!(" data-word=\"{}\"", package::escape_attribute(tokenizer::trim_punctuation(&word))));
+    }
+}
+
+/// Whether the token at `index` should be clickable. Only words are ever
+/// left out.
+fn is_lookup(tokens: &[Token], index: usize, sentence_starts: &HashSet<usize>, options: &WrapOptions, state: &WrapState) -> bool {
+    let token = &tokens[index];
+    if !token.is_word() {
+        return true;
+    }
+    let plain = tokenizer::text_content(token.as_str());
+    let previous = tokens[..index].iter().rev()
+        .find(|token| token.is_word())
+        .map(|previous| tokenizer::text_content(previous.as_str()));
+    let alone = tokens.iter().filter(|token| token.is_word()).nth(1).is_none();
+    match tokenizer::classify_in_context(&plain, previous.as_deref(), alone) {
+        WordClass::Capitalized if options.skip_proper_nouns => {
+            let word = tokenizer::normalize_word(&plain);
+            // Quotes and dashes open speech, which starts with a capital too
+            let opens_speech = plain.starts_with(['"', '\'', '«', '“', '„', '‘', '¿', '¡', '—', '–', '(', '[']);
+            let after_colon = previous.is_some_and(|previous| previous.ends_with(':'));
+            sentence_starts.contains(&index)
+                || opens_speech
+                || after_colon
+                || options.allowed_capitalized.contains(&word)
+                || state.lowercase.contains(&word)
+        }
+        class => class.is_lookup(),
+    }
+}
+
+/// Language given by the `xml:lang` or `lang` attribute of the opening tag
+/// `markup` starts with.
+fn element_language(markup: &str) -> Option<&str> {
+    let tag = &markup[..markup.find('>').map_or(markup.len(), |end| end + 1)];
+    tokenizer::attribute(tag, "xml:lang").or_else(|| tokenizer::attribute(tag, "lang"))
+}
+
+/// True if `tag` is in a different language than `language`, going by the
+/// primary subtag. An empty tag means the language is unknown.
+fn is_other_language(tag: &str, language: &str) -> bool {
+    let primary = |tag: &str| tag.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+    !tag.is_empty() && primary(tag) != primary(language)
+}
+
+/// Keeps `open` up to date with the elements a tag opens or closes. The
+/// first entry is the paragraph itself and is never closed.
+fn track_language<'a>(tag: &'a str, language: &str, open: &mut Vec<(&'a str, bool)>) {
+    let Some((name, closing)) = tokenizer::tag_name(tag) else {
+        return;
+    };
+    if closing {
+        if let Some(position) = open.iter().rposition(|&(open_name, _)| open_name == name).filter(|&position| position > 0) {
+            open.truncate(position);
+        }
+    } else if !tag.ends_with("/>") {
+        let inherited = open.last().is_some_and(|&(_, foreign)| foreign);
+        let foreign = element_language(tag).map_or(inherited, |lang| is_other_language(lang, language));
+        open.push((name, foreign));
+    }
+}
+
+/// Number of tokens of the longest listed phrase `tokens` starts with. The
+/// words of a phrase are only separated by spaces, and punctuation may only
+/// come before its first word or after its last.
+fn phrase_at(tokens: &[Token], phrases: &PhraseList) -> Option<usize> {
+    let mut words = Vec::new();
+    let mut ends = Vec::new();
+    for (i, token) in tokens.iter().enumerate().take(phrases.longest() * 2) {
+        match token {
+            Token::Word(text) if i % 2 == 0 => {
+                let plain = tokenizer::text_content(text);
+                let core = tokenizer::trim_punctuation(&plain);
+                if i > 0 && !plain.starts_with(core) {
+                    break;
+                }
+                words.push(tokenizer::normalize_word(&plain));
+                ends.push(i + 1);
+                if !plain.ends_with(core) {
+                    break;
                 }
             }
-            if inside_word {
-                modified_p_text.push_str("</span>");
+            Token::Space(_) if i % 2 == 1 => {}
+            _ => break,
+        }
+    }
+    phrases.match_at(&words).map(|length| ends[length - 1])
+}
+
+/// Wraps the tokens of a phrase in one clickable span, with the phrase in
+/// `data-word` so it is looked up whole.
+fn wrap_phrase(tokens: &[Token], transliterator: Option<&Transliterator>, output: &mut String) {
+    let text: String = tokens.iter().map(Token::as_str).collect();
+    let phrase = tokenizer::trim_punctuation(&tokenizer::text_content(&text)).to_string();
+    output.push_str(&format!("{} data-word=\"{}\"", WORD_SPAN, package::escape_attribute(&phrase)));
+    if let Some(transliterator) = transliterator {
+        output.push_str(&format!(" data-translit=\"{}\"", package::escape_attribute(&transliterator.transliterate(&phrase))));
+    }
+    output.push_str(" data-phrase=\"true\">");
+    output.push_str(&text);
+    output.push_str("</span>");
+}
+
+/// Wraps each dictionary word of a run of Chinese or Japanese text on its
+/// own, with its reading if it has one. The word itself goes in `data-word`
+/// so lookups leave out the reading.
+fn wrap_with_readings(text: &str, ruby: &Ruby, output: &mut String) {
+    for piece in ruby.split(text) {
+        match piece {
+            Piece::Word { text, reading: Some(reading) } => {
+                output.push_str(&format!(
+                    "{} data-word=\"{}\">",
+                    WORD_SPAN,
+                    package::escape_attribute(&tokenizer::decode_entities(text)),
+                ));
+                ruby::annotate(text, reading, output);
+                output.push_str("</span>");
+            }
+            Piece::Word { text, reading: None } => {
+                open_word_span(text, output);
+                output.push('>');
+                output.push_str(text);
+                output.push_str("</span>");
             }
+            Piece::Other(text) => output.push_str(text),
+        }
+    }
+}
 
-            let opening_tag = &html_string[index..opening_tag_end];
-            let new_content = format!("{}{}</p>", opening_tag, modified_p_text);
-            
-            html_string.replace_range(index..closing_p_index + 4, &new_content);
-            current_index = index + new_content.len();
-        } else {
-            panic!("Malformed html. Could not find closing p tag.");
+/// Wraps a word with its romanization in `data-translit`, and as ruby over
+/// the word without its punctuation if `as_ruby` is set. Existing spans only
+/// get the attribute.
+fn wrap_with_transliteration(token: &Token, transliterator: &Transliterator, as_ruby: bool, output: &mut String) {
+    let text = token.as_str();
+    let word = match token {
+        Token::Word(text) => tokenizer::trim_punctuation_in_markup(text),
+        _ => text,
+    };
+    let plain = tokenizer::text_content(word);
+    let romanized = transliterator.transliterate(&plain);
+    open_word_span(text, output);
+    if romanized == plain || plain.is_empty() {
+        output.push('>');
+        output.push_str(text);
+        output.push_str("</span>");
+        return;
+    }
+
+    output.push_str(&format!(" data-translit=\"{}\">", package::escape_attribute(&romanized)));
+    if as_ruby && matches!(token, Token::Word(_)) {
+        let start = text.len() - text.trim_start_matches(tokenizer::PUNCTUATION).len();
+        output.push_str(&text[..start]);
+        ruby::annotate(word, &romanized, output);
+        output.push_str(&text[start + word.len()..]);
+    } else {
+        output.push_str(text);
+    }
+    output.push_str("</span>");
+}
+
+/// Contents of every paragraph the word wrapping pass would wrap, skipping
+/// any without a closing tag.
+pub(crate) fn paragraphs(html: &str) -> Vec<&str> {
+    paragraph_ranges(html).into_iter().map(|(_, content)| &html[content]).collect()
+}
+
+/// Where each paragraph of [`paragraphs`] starts, and the range of its
+/// contents. The closing tag follows right after the contents.
+pub(crate) fn paragraph_ranges(html: &str) -> Vec<(usize, Range<usize>)> {
+    let mut paragraphs = Vec::new();
+    let mut index = 0;
+    while let Some(start) = find_paragraph_from_index(html, index) {
+        let Some(content_start) = html[start..].find('>').map(|i| i + start + 1) else {
+            break;
+        };
+        let Some(end) = find_substring_from_index(html, "</p>", content_start) else {
+            break;
+        };
+        paragraphs.push((start, content_start..end));
+        index = end + 4;
+    }
+    paragraphs
+}
+
+fn find_paragraph_from_index(html: &str, start_index: usize) -> Option<usize> {
+    let mut index = start_index;
+    while let Some(found) = find_substring_from_index(html, "<p", index) {
+        if is_tag_named(&html[found..], "p") {
+            return Some(found);
         }
+        index = found + 2;
     }
-    
-    html_string
+    None
 }
 
 fn find_substring_from_index(string: &str, substring: &str, start_index: usize) -> Option<usize> {
     string[start_index..].find(substring).map(|index| index + start_index)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruby::{ReadingDictionary, RubyMode};
+
+    #[test]
+    fn words_with_and_without_readings() {
+        let ruby = Ruby::new(ReadingDictionary::parse("日本\tにほん\n").unwrap(), RubyMode::Words);
+        let mut output = String::new();
+        wrap_with_readings("日本の猫&amp;", &ruby, &mut output);
+        let spans: Vec<&str> = output.split(WORD_SPAN).skip(1).collect();
+        assert_eq!(spans.len(), 3, "{}", output);
+        assert!(spans[0].starts_with(" data-word=\"日本\"><ruby>"), "{}", spans[0]);
+        assert_eq!(spans[1], ">の</span>");
+        // The entity stays outside the last word
+        assert_eq!(spans[2], ">猫</span>&amp;");
+    }
+
+    #[test]
+    fn wraps_phrases_as_one_word() {
+        let tokens = tokenize("«give up»");
+        let mut output = String::new();
+        wrap_phrase(&tokens, None, &mut output);
+        assert_eq!(output, format!("{} data-word=\"give up\" data-phrase=\"true\">«give up»</span>", WORD_SPAN));
+
+        let tokens = tokenize("Tom &amp; Jerry");
+        let mut output = String::new();
+        wrap_phrase(&tokens, None, &mut output);
+        assert!(output.contains(" data-word=\"Tom &amp; Jerry\" "), "{}", output);
+
+        let transliterator = Transliterator::for_language("ru").unwrap();
+        let tokens = tokenize("да нет");
+        let mut output = String::new();
+        wrap_phrase(&tokens, Some(&transliterator), &mut output);
+        let romanized = package::escape_attribute(&transliterator.transliterate("да нет"));
+        assert!(output.contains(&format!(" data-word=\"да нет\" data-translit=\"{}\" data-phrase=\"true\">", romanized)), "{}", output);
+    }
+
+    #[test]
+    fn wraps_listed_phrases_in_paragraphs() {
+        let options = WrapOptions { phrases: Some(PhraseList::parse("give up\n")), ..WrapOptions::default() };
+        let html = "<html><head></head><body><p>Never give up, never give. Give</p><p>up.</p></body></html>";
+        let output = wrap_document(html, &LookupConfig::new("en"), &options, Layout::default()).unwrap();
+        assert_eq!(output.matches("data-phrase=\"true\"").count(), 1);
+        assert!(output.contains(" data-word=\"give up\" data-phrase=\"true\">give up,</span>"), "{}", output);
+    }
+
+    #[test]
+    fn decodes_words_with_references() {
+        let span = |text: &str| {
+            let mut output = String::new();
+            open_word_span(text, &mut output);
+            output[WORD_SPAN.len()..].to_string()
+        };
+        assert_eq!(span("gato,"), "");
+        assert_eq!(span("R&amp;D"), " data-word=\"R&amp;D\"");
+        assert_eq!(span("«caf&#233;»,"), " data-word=\"café\"");
+        assert_eq!(span("&quot;hola&quot;"), " data-word=\"hola\"");
+    }
+
+    #[test]
+    fn quotes_script_strings() {
+        assert_eq!(js_string("es"), "\"es\"");
+        assert_eq!(
+            js_string("</script>\"a\\b\n&"),
+            "\"\\u003c/script\\u003e\\\"a\\\\b\\n\\u0026\"",
+        );
+
+        let script = "<script type=\"text/javascript\">\n    go();\n</script>";
+        let config = LookupConfig::new("en</script><script>alert(\"x\")");
+        let output = add_config_to_script(script, &config);
+        assert!(output.contains(
+            "    const language = \"en\\u003c/script\\u003e\\u003cscript\\u003ealert(\\\"x\\\")\";\n",
+        ), "{}", output);
+        assert!(output.contains("    const defaultTargetLanguage = null;\n"));
+        assert_eq!(output.matches("</script>").count(), 1);
+
+        let output = add_config_to_script(script, &LookupConfig::new("es").target_language("en"));
+        assert!(output.contains("    const defaultTargetLanguage = \"en\";\n"));
+    }
+
+    /// The words of `paragraph` that would be clickable.
+    fn lookups<'a>(paragraph: &'a str, options: &WrapOptions, state: &WrapState) -> Vec<&'a str> {
+        let tokens = tokenize(paragraph);
+        let sentence_starts = tokenizer::sentences(&tokens).into_iter().map(|sentence| sentence.start).collect();
+        (0..tokens.len())
+            .filter(|&i| tokens[i].is_word() && is_lookup(&tokens, i, &sentence_starts, options, state))
+            .map(|i| tokens[i].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn leaves_names_numbers_and_addresses_out() {
+        let paragraph = "Ayer vi a Juan en Madrid. Luego: Hola. «Quién eres?» dijo Pedro, 42 veces, www.example.com";
+        let mut options = WrapOptions::default();
+        let mut state = WrapState::default();
+        assert_eq!(lookups(paragraph, &options, &state), [
+            "Ayer", "vi", "a", "Juan", "en", "Madrid.", "Luego:", "Hola.", "«Quién", "eres?»", "dijo", "Pedro,", "veces,",
+        ]);
+
+        options.skip_proper_nouns = true;
+        assert_eq!(lookups(paragraph, &options, &state), [
+            "Ayer", "vi", "a", "en", "Luego:", "Hola.", "«Quién", "eres?»", "dijo", "veces,",
+        ]);
+
+        options.allowed_capitalized.insert("madrid".to_string());
+        state.lowercase.insert("pedro".to_string());
+        assert_eq!(lookups(paragraph, &options, &state), [
+            "Ayer", "vi", "a", "en", "Madrid.", "Luego:", "Hola.", "«Quién", "eres?»", "dijo", "Pedro,", "veces,",
+        ]);
+    }
+}
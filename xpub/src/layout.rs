@@ -0,0 +1,162 @@
+//! Writing direction of a book: right-to-left scripts such as Arabic and
+//! Hebrew, and vertical Japanese or Chinese.
+//!
+//! The direction comes from the spine's `page-progression-direction` and the
+//! book's language. xpub uses it for the markup it generates, and to fill in
+//! `dir` and `writing-mode` on content documents that leave them out.
+
+use crate::package::Package;
+
+/// Languages written right to left.
+const RTL_LANGUAGES: &[&str] = &["ar", "ckb", "dv", "fa", "he", "iw", "ks", "ps", "sd", "ug", "ur", "yi"];
+
+/// Languages that are laid out vertically when pages turn right to left.
+const VERTICAL_LANGUAGES: &[&str] = &["ja", "zh"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Layout {
+    /// Lines run right to left
+    pub rtl: bool,
+    /// Lines run top to bottom, ordered right to left
+    pub vertical: bool,
+}
+
+impl Layout {
+    /// Works out the layout from a language tag and the spine's page
+    /// progression direction, if it has one.
+    pub fn new(language: &str, page_progression_direction: Option<&str>) -> Layout {
+        let primary = language.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+        let pages_rtl = page_progression_direction == Some("rtl");
+        let vertical = pages_rtl && VERTICAL_LANGUAGES.contains(&primary.as_str());
+        Layout {
+            rtl: RTL_LANGUAGES.contains(&primary.as_str()) || (pages_rtl && !vertical),
+            vertical,
+        }
+    }
+
+    /// Layout of a book, using `language` if the package does not give one.
+    pub fn for_package(package: &Package, language: &str) -> Layout {
+        Layout::new(
+            package.language().unwrap_or(language),
+            package.spine.page_progression_direction.as_deref(),
+        )
+    }
+
+    /// Value for a `dir` attribute.
+    pub fn dir(&self) -> &'static str {
+        if self.rtl { "rtl" } else { "ltr" }
+    }
+
+    /// Adds `dir="rtl"` to the root element of a content document that has no
+    /// `dir` of its own, and a vertical writing mode to the head of one that
+    /// has no writing mode in it. The style comes first in the head, so the
+    /// book's own stylesheets still win.
+    pub fn apply(&self, document: &mut String) {
+        if self.vertical && !document.contains("writing-mode") {
+            if let Some(head_end) = opening_tag_end(document, "head") {
+                document.insert_str(
+                    head_end,
+                    "<style>html { -epub-writing-mode: vertical-rl; writing-mode: vertical-rl; }</style>",
+                );
+            }
+        }
+        if self.rtl {
+            if let Some(html_end) = opening_tag_end(document, "html") {
+                let tag = &document[..html_end];
+                let tag_start = tag.rfind("<html").unwrap_or(0);
+                if !tag[tag_start..].contains(" dir=") {
+                    let insert_at = if tag.ends_with("/>") { html_end - 2 } else { html_end - 1 };
+                    document.insert_str(insert_at, " dir=\"rtl\"");
+                }
+            }
+        }
+    }
+}
+
+/// Index just past the opening tag of the first `name` element.
+fn opening_tag_end(document: &str, name: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(found) = document[from..].find('<').map(|i| i + from) {
+        if crate::tokenizer::is_tag_named(&document[found..], name) {
+            return document[found..].find('>').map(|i| i + found + 1);
+        }
+        from = found + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "<?xml version=\"1.0\"?><html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>T</title></head><body/></html>";
+
+    fn package(language: &str, spine: &str) -> Package {
+        Package::parse(&format!(
+            r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+                <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+                    <dc:identifier id="id">urn:uuid:1234</dc:identifier>{}
+                </metadata>
+                <manifest/>
+                <spine{}/>
+            </package>"#,
+            language, spine,
+        )).unwrap()
+    }
+
+    #[test]
+    fn works_out_the_direction() {
+        assert_eq!(Layout::new("en", None), Layout::default());
+        assert_eq!(Layout::new("ar", None), Layout { rtl: true, vertical: false });
+        assert_eq!(Layout::new("he-IL", Some("ltr")), Layout { rtl: true, vertical: false });
+        assert_eq!(Layout::new("FA_ir", None), Layout { rtl: true, vertical: false });
+        assert_eq!(Layout::new("ja", None), Layout::default());
+        assert_eq!(Layout::new("ja-JP", Some("rtl")), Layout { rtl: false, vertical: true });
+        assert_eq!(Layout::new("zh-Hant", Some("rtl")), Layout { rtl: false, vertical: true });
+        // Pages turning right to left in other languages means rtl lines
+        assert_eq!(Layout::new("en", Some("rtl")), Layout { rtl: true, vertical: false });
+    }
+
+    #[test]
+    fn reads_the_package() {
+        let arabic = package("<dc:language>ar</dc:language>", "");
+        assert_eq!(Layout::for_package(&arabic, "en"), Layout { rtl: true, vertical: false });
+
+        let japanese = package("<dc:language>ja</dc:language>", r#" page-progression-direction="rtl""#);
+        assert_eq!(Layout::for_package(&japanese, "en"), Layout { rtl: false, vertical: true });
+
+        // The language given is only used if the package has none
+        let unknown = package("", "");
+        assert_eq!(Layout::for_package(&unknown, "he"), Layout { rtl: true, vertical: false });
+        let english = package("<dc:language>en</dc:language>", "");
+        assert_eq!(Layout::for_package(&english, "he"), Layout::default());
+    }
+
+    #[test]
+    fn fills_in_dir_and_writing_mode() {
+        let mut rtl = HTML.to_string();
+        Layout { rtl: true, vertical: false }.apply(&mut rtl);
+        assert!(rtl.contains("<html xmlns=\"http://www.w3.org/1999/xhtml\" dir=\"rtl\"><head>"));
+        assert!(!rtl.contains("writing-mode"));
+
+        let mut own_dir = HTML.replace("<html ", "<html dir=\"ltr\" ");
+        Layout { rtl: true, vertical: false }.apply(&mut own_dir);
+        assert_eq!(own_dir, HTML.replace("<html ", "<html dir=\"ltr\" "));
+
+        let mut vertical = HTML.to_string();
+        Layout { rtl: false, vertical: true }.apply(&mut vertical);
+        assert!(vertical.contains(
+            "<head><style>html { -epub-writing-mode: vertical-rl; writing-mode: vertical-rl; }</style><title>",
+        ));
+        assert!(!vertical.contains("dir="));
+
+        let styled = HTML.replace("<title>", "<style>body { writing-mode: horizontal-tb; }</style><title>");
+        let mut own_mode = styled.clone();
+        Layout { rtl: false, vertical: true }.apply(&mut own_mode);
+        assert_eq!(own_mode, styled);
+
+        let mut ltr = HTML.to_string();
+        Layout::default().apply(&mut ltr);
+        assert_eq!(ltr, HTML);
+    }
+}
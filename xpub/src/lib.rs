@@ -0,0 +1,49 @@
+//! Tools for turning an EPUB into an interactive reader for language learners.
+//!
+//! A book is opened as an [`EpubDocument`] and rewritten by a [`Pipeline`] of
+//! [`Transform`] passes, such as [`WrapWords`] which makes every word clickable.
+//!
+//! ```no_run
+//! use xpub::{EpubDocument, LookupConfig, Pipeline, WrapWords};
+//!
+//! let mut book = EpubDocument::open("book.epub")?;
+//! let config = LookupConfig::new("es").target_language("en");
+//! Pipeline::new().with(WrapWords::new(config)).run(&mut book)?;
+//! book.save("modified.epub")?;
+//! # Ok::<(), xpub::Error>(())
+//! ```
+
+pub mod align;
+pub mod anki;
+pub mod audio;
+pub mod bilingual;
+pub mod diff;
+pub mod difficulty;
+pub mod edition;
+pub mod encoding;
+pub mod encryption;
+pub mod epub;
+pub mod error;
+#[cfg(test)]
+mod fixtures;
+pub mod glossary;
+pub mod html_parser;
+pub mod layout;
+pub mod lookups;
+pub mod media_overlay;
+pub mod package;
+pub mod phrases;
+pub mod pipeline;
+pub mod ruby;
+pub mod tokenizer;
+pub mod transform;
+pub mod translit;
+pub mod tts;
+pub mod validate;
+
+pub use epub::EpubDocument;
+pub use error::{Error, ParseError};
+pub use html_parser::{LookupConfig, WrapOptions, WrapWords};
+pub use package::{ManifestItem, Package};
+pub use pipeline::{ChapterRange, Pipeline, Report};
+pub use transform::{Document, Transform};
@@ -0,0 +1,175 @@
+//! Words looked up while reading, as saved by the injected script.
+//!
+//! The script keeps every lookup in `localStorage` in the shape of the
+//! segment files the flashcards tool writes, `{text, media_path}` plus the
+//! word, its translation and where it was found. `xpub export-lookups` turns
+//! a dump of that list into one segment file per lookup.
+
+use serde_json::{json, Value};
+use std::path::Path;
+
+use crate::error::Error;
+
+/// `localStorage` key the script saves lookups under.
+pub const STORAGE_KEY: &str = "xpub-lookups";
+
+/// A word looked up in the book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    /// Sentence or paragraph the word was found in
+    pub text: String,
+    /// Audio or video of the text, empty for lookups from a book
+    pub media_path: String,
+    pub language: String,
+    pub word: String,
+    pub translation: String,
+    /// Title of the chapter, or its file name if it has none
+    pub chapter: String,
+}
+
+impl Lookup {
+    fn from_json(value: &Value) -> Option<Lookup> {
+        let field = |name: &str| value.get(name).and_then(Value::as_str).unwrap_or_default().to_string();
+        let word = value.get("word")?.as_str()?.to_string();
+        Some(Lookup {
+            text: value.get("text").and_then(Value::as_str).map_or_else(|| word.clone(), str::to_string),
+            media_path: field("media_path"),
+            language: field("language"),
+            translation: field("translation"),
+            chapter: field("chapter"),
+            word,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "text": self.text,
+            "media_path": self.media_path,
+            "language": self.language,
+            "word": self.word,
+            "translation": self.translation,
+            "chapter": self.chapter,
+        })
+    }
+}
+
+/// Reads the lookups out of a dump. The dump is either the list the in-book
+/// export button downloads, or a JSON object of `localStorage` with the list
+/// as a string under [`STORAGE_KEY`].
+pub fn parse_dump(json: &str) -> Result<Vec<Lookup>, String> {
+    let mut value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    if let Some(saved) = value.get(STORAGE_KEY) {
+        value = match saved {
+            Value::String(list) => serde_json::from_str(list).map_err(|err| format!("{}: {}", STORAGE_KEY, err))?,
+            list => list.clone(),
+        };
+    }
+
+    let Value::Array(items) = value else {
+        return Err("expected a list of lookups".to_string());
+    };
+    items.iter()
+        .enumerate()
+        .map(|(i, item)| Lookup::from_json(item).ok_or_else(|| format!("lookup {} has no word", i)))
+        .collect()
+}
+
+/// Writes each lookup to `directory` as a segment file named after a hash of
+/// its word and text, so exporting the same lookups again does not make
+/// duplicates. Returns the number of files written.
+pub fn write_segments(lookups: &[Lookup], directory: &Path) -> Result<usize, Error> {
+    std::fs::create_dir_all(directory)?;
+    for lookup in lookups {
+        let name = format!("xpub_{:016x}.json", fnv1a(&[&lookup.word, &lookup.text]));
+        std::fs::write(directory.join(name), lookup.to_json().to_string())?;
+    }
+    Ok(lookups.len())
+}
+
+/// FNV-1a, which unlike the standard library's hasher stays the same across
+/// Rust releases, so the same input always gets the same name or id.
+pub(crate) fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &byte in part.as_bytes().iter().chain([&0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::TempFile;
+
+    const LOOKUP: &str = r#"{"text": "El gato come.", "media_path": "", "language": "es", "word": "gato",
+        "translation": "cat", "chapter": "Uno"}"#;
+
+    fn gato() -> Lookup {
+        Lookup {
+            text: "El gato come.".to_string(),
+            media_path: String::new(),
+            language: "es".to_string(),
+            word: "gato".to_string(),
+            translation: "cat".to_string(),
+            chapter: "Uno".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_exported_lists() {
+        assert_eq!(parse_dump(&format!("[{}]", LOOKUP)).unwrap(), [gato()]);
+
+        // Only the word is needed, the text falls back to it
+        let lookups = parse_dump(r#"[{"word": "perro"}]"#).unwrap();
+        assert_eq!((lookups[0].text.as_str(), lookups[0].language.as_str()), ("perro", ""));
+    }
+
+    #[test]
+    fn parses_local_storage_dumps() {
+        let saved = serde_json::to_string(&format!("[{}]", LOOKUP)).unwrap();
+        let dump = format!(r#"{{"other": "1", "{}": {}}}"#, STORAGE_KEY, saved);
+        assert_eq!(parse_dump(&dump).unwrap(), [gato()]);
+
+        let dump = format!(r#"{{"{}": [{}]}}"#, STORAGE_KEY, LOOKUP);
+        assert_eq!(parse_dump(&dump).unwrap(), [gato()]);
+    }
+
+    #[test]
+    fn rejects_other_dumps() {
+        assert_eq!(parse_dump(r#"{"word": "gato"}"#).unwrap_err(), "expected a list of lookups");
+        assert_eq!(parse_dump(r#"[{"word": "gato"}, {"text": "gato"}]"#).unwrap_err(), "lookup 1 has no word");
+        assert!(parse_dump(&format!(r#"{{"{}": "["}}"#, STORAGE_KEY)).unwrap_err().starts_with("xpub-lookups: "));
+        assert!(parse_dump("[").is_err());
+    }
+
+    #[test]
+    fn writes_one_segment_per_lookup() {
+        let directory = TempFile::new("d");
+        let perro = Lookup { word: "perro".to_string(), ..gato() };
+        assert_eq!(write_segments(&[gato(), perro.clone()], &directory.path).unwrap(), 2);
+        // Exporting again replaces the files rather than adding more
+        write_segments(&[gato(), perro], &directory.path).unwrap();
+
+        let names: Vec<String> = std::fs::read_dir(&directory.path).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        let name = format!("xpub_{:016x}.json", fnv1a(&["gato", "El gato come."]));
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&name));
+
+        let segment: Value = serde_json::from_str(&std::fs::read_to_string(directory.path.join(&name)).unwrap()).unwrap();
+        assert_eq!(segment, serde_json::from_str::<Value>(LOOKUP).unwrap());
+        std::fs::remove_dir_all(&directory.path).unwrap();
+    }
+
+    #[test]
+    fn hashes_parts_separately() {
+        assert_eq!(fnv1a(&["gato", "El gato"]), fnv1a(&["gato", "El gato"]));
+        assert_ne!(fnv1a(&["ab", "c"]), fnv1a(&["a", "bc"]));
+        // FNV-1a of a single zero byte
+        assert_eq!(fnv1a(&[""]), 0xaf63bd4c8601b7df);
+    }
+}
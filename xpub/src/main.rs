@@ -1,91 +1,486 @@
-mod html_parser;
+use clap::{Parser, Subcommand};
+use std::{collections::HashSet, io, path::{Path, PathBuf}, process::ExitCode};
 
-use clap::Parser;
-use std::{io::{self, Read, Write}, path::{Path, PathBuf}};
-use std::collections::HashMap;
-use zip::{write::{SimpleFileOptions, ZipWriter}, ZipArchive};
-
-use html_parser::wrap_words_in_paragraphs;
+use xpub::align::{AudiobookOverlays, Recording};
+use xpub::anki::{self, Deck, GlossProvider, Note, Ranking, TsvGlossary};
+use xpub::bilingual::{self, Arrangement};
+use xpub::difficulty::{Difficulty, FrequencyList};
+use xpub::edition::Edition;
+use xpub::glossary::Glossary;
+use xpub::media_overlay::{AudioFormat, SpeechOverlays};
+use xpub::phrases::PhraseList;
+use xpub::ruby::{ReadingDictionary, Ruby, RubyMode};
+use xpub::translit::Transliterator;
+use xpub::tts::{CommandSynthesizer, SpeechSynthesizer};
+use xpub::{audio, diff, encoding, lookups, tokenizer, validate::validate};
+use xpub::{ChapterRange, EpubDocument, Error, LookupConfig, Pipeline, WrapOptions, WrapWords};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(help = "Path of the file to change")]
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(help = "Path of the file to change", required = true)]
+    input: Option<String>,
     #[arg(short, long, help = "Path of the output file", default_value = "modified.epub")]
     output: String,
-    #[arg(short, long, help = "Language of epub file")]
-    lang: String,
+    #[arg(short, long, help = "Language of epub file", required = true)]
+    lang: Option<String>,
+    #[arg(long, help = "Language lookups are translated into, can be changed from the popup")]
+    target_lang: Option<String>,
+    #[arg(long, help = "Copy chapters that cannot be parsed unchanged instead of failing")]
+    keep_going: bool,
+    #[arg(long, help = "Check the output for structural problems after writing it")]
+    validate: bool,
+    #[arg(long, help = "Read every sentence aloud with this local TTS command and add media overlays, \
+        e.g. \"espeak-ng -v {lang} -w {output} {text}\"")]
+    tts_command: Option<String>,
+    #[arg(long, value_name = "FILE",
+        help = "Put readings over Chinese or Japanese words from this dictionary, one word<TAB>reading[<TAB>rank] per line")]
+    readings: Option<String>,
+    #[arg(long, value_name = "RANK", requires = "readings",
+        help = "Only show readings for kanji or hanzi words rarer than this frequency rank")]
+    ruby_rank: Option<usize>,
+    #[arg(long, help = "Romanize every word for the popup (ru, el, ar, he, ko and hi)")]
+    translit: bool,
+    #[arg(long, requires = "translit", help = "Also show the romanization over each word")]
+    translit_ruby: bool,
+    #[arg(long, value_name = "FILE",
+        help = "Make these multi-word expressions clickable as one unit, one phrase per line, e.g. \"give up\"")]
+    phrases: Option<String>,
+    #[arg(long, help = "Add a vocabulary chapter listing the book's words by the chapter they first appear in")]
+    vocabulary: bool,
+    #[arg(long, value_name = "N", requires = "vocabulary", help = "Only list the N most frequent words [default: all]")]
+    vocabulary_size: Option<usize>,
+    #[arg(long, value_name = "FILE", requires = "vocabulary",
+        help = "Words you already know, one per line, to leave out of the vocabulary")]
+    known: Option<String>,
+    #[arg(long, value_name = "FILE", requires = "vocabulary",
+        help = "Glosses for the vocabulary, one word<TAB>gloss per line")]
+    glossary: Option<String>,
+    #[arg(long, help = "Keep capitalized words inside sentences clickable instead of taking them for names")]
+    keep_proper_nouns: bool,
+    #[arg(long, value_name = "FILE", conflicts_with = "keep_proper_nouns",
+        help = "Capitalized words that are not names and should stay clickable, one per line")]
+    allow_capitalized: Option<String>,
+    #[arg(long, value_name = "FILE", conflicts_with = "tts_command",
+        help = "Audio file of the narrated book to sync with the text; repeat for each file, in reading order")]
+    audiobook: Vec<String>,
+    #[arg(long, value_name = "FILE", requires = "audiobook",
+        help = "JSON transcript with timestamps of each --audiobook file, in the same order")]
+    transcript: Vec<String>,
+    #[arg(long, value_name = "FILE",
+        help = "Write how hard each chapter is to this file, graded by word frequency and sentence length")]
+    difficulty_report: Option<String>,
+    #[arg(long, help = "Show each chapter's difficulty level in the table of contents")]
+    difficulty_badges: bool,
+    #[arg(long, value_name = "FILE",
+        help = "Words of the language from most to least frequent, one per line, to grade difficulty against \
+            [default: the book's own word counts]")]
+    frequency_list: Option<String>,
+    #[arg(long, help = "Mark the output as a new edition: give it a new identifier, keeping the old one as its source, \
+        and add to its title")]
+    new_edition: bool,
+    #[arg(long, value_name = "TEXT", requires = "new_edition",
+        help = "What to add to the title [default: (interactive, LANG→TARGET_LANG)]")]
+    title_suffix: Option<String>,
+    #[arg(long, value_name = "RANGE",
+        help = "Only rewrite these chapters, given as spine indexes from 0 or idrefs, e.g. 3..7, 3..=7, 5.. or intro..=c9")]
+    chapters: Option<ChapterRange>,
+    #[arg(long, value_name = "N", num_args = 0..=1, require_equals = true, default_missing_value = "10",
+        conflicts_with_all = ["validate", "difficulty_report", "tts_command"],
+        help = "Print what would change and a diff of the first N changes [default: 10] instead of writing the output")]
+    dry_run: Option<usize>,
+    #[cfg(feature = "whisper")]
+    #[arg(long, value_name = "FILE", requires = "audiobook", conflicts_with = "dry_run",
+        help = "whisper.cpp model to transcribe --audiobook files that have no --transcript")]
+    whisper_model: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check an epub for structural problems
+    Validate {
+        #[arg(help = "Path of the epub to check")]
+        input: String,
+    },
+    /// Turn words looked up in a book into flashcards segment files
+    ExportLookups {
+        #[arg(help = "JSON from the book's Export button, or a dump of its localStorage")]
+        dump: String,
+        #[arg(short, long, help = "Directory to write the segments to [default: ~/.flashcard/segments]")]
+        output: Option<String>,
+    },
+    /// Build an Anki deck of the book's most useful words
+    Anki {
+        #[arg(help = "Path of the epub to take the words from")]
+        input: String,
+        #[arg(short, long, help = "Path of the deck [default: the book's name with .apkg]")]
+        output: Option<String>,
+        #[arg(short, long, help = "Language of the book [default: the one its package gives]")]
+        lang: Option<String>,
+        #[arg(long, value_name = "FILE", help = "Words you already know, one per line, to leave out of the deck")]
+        known: Option<String>,
+        #[arg(long, value_name = "FILE", help = "Glosses for the words, one word<TAB>gloss per line")]
+        glossary: Option<String>,
+        #[arg(long, help = "Number of words in the deck", default_value_t = 200)]
+        limit: usize,
+        #[arg(long, help = "Name of the deck [default: the book's title]")]
+        deck: Option<String>,
+        #[arg(long, help = "Record each word and sentence with this local TTS command, \
+            e.g. \"espeak-ng -v {lang} -w {output} {text}\"")]
+        tts_command: Option<String>,
+        #[arg(long, help = "Format of the recordings", value_parser = ["mp3", "wav"], default_value = "mp3")]
+        audio_format: String,
+    },
+    /// Merge a book and its translation into one bilingual book
+    Bilingual {
+        #[arg(help = "Path of the book in the original language")]
+        original: String,
+        #[arg(help = "Path of the same book in translation")]
+        translation: String,
+        #[arg(short, long, help = "Path of the output file", default_value = "bilingual.epub")]
+        output: String,
+        #[arg(long, help = "Where the translation goes: next to each paragraph, after it, or after each sentence",
+            value_parser = ["side-by-side", "paragraphs", "sentences"], default_value = "side-by-side")]
+        layout: String,
+        #[arg(long, help = "Language of the translation [default: the one its package gives]")]
+        translation_lang: Option<String>,
+        #[arg(long, help = "Check the output for structural problems after writing it")]
+        validate: bool,
+    },
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();   
 
-    let input_path = args.input;
-    let input_zip_path = if Path::new(&input_path).is_absolute() {
-        PathBuf::from(input_path)
-    } else {
-        let current_dir = std::env::current_dir().expect("Failed to get current directory");
-        current_dir.join(input_path)
+    let result = match args.command {
+        Some(Command::Validate { ref input }) => run_validate(input),
+        Some(Command::ExportLookups { ref dump, ref output }) => run_export_lookups(dump, output.as_deref()),
+        Some(Command::Anki { .. }) => run_anki(&args),
+        Some(Command::Bilingual { .. }) => run_bilingual(&args),
+        None => run(&args),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<bool, Error> {
+    let (Some(input), Some(lang)) = (&args.input, &args.lang) else {
+        unreachable!("clap requires input and lang without a subcommand");
     };
- 
-    let output_path = args.output;
-    let output_zip_path = if Path::new(&output_path).is_absolute() {
-        PathBuf::from(output_path)
+    let input_zip_path = absolute_path(input)?;
+    let output_zip_path = absolute_path(&args.output)?;
+
+    let mut config = LookupConfig::new(lang);
+    if let Some(target_lang) = &args.target_lang {
+        config = config.target_language(target_lang);
+    }
+
+    let ruby = match &args.readings {
+        Some(path) => {
+            let mode = args.ruby_rank.map_or(RubyMode::Words, RubyMode::RarerThan);
+            Some(Ruby::new(ReadingDictionary::open(absolute_path(path)?)?, mode))
+        }
+        None => None,
+    };
+    let transliterator = if args.translit {
+        Some(Transliterator::for_language(lang).ok_or_else(|| Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no transliteration scheme for {}", lang),
+        )))?)
     } else {
-        let current_dir = std::env::current_dir().expect("Failed to get current directory");
-        current_dir.join(output_path)
+        None
     };
+    let options = WrapOptions {
+        mark_sentences: args.tts_command.is_some() || !args.audiobook.is_empty(),
+        ruby,
+        transliterator,
+        transliteration_ruby: args.translit_ruby,
+        phrases: match &args.phrases {
+            Some(path) => Some(PhraseList::open(absolute_path(path)?)?),
+            None => None,
+        },
+        skip_proper_nouns: !args.keep_proper_nouns && !tokenizer::capitalizes_nouns(lang),
+        allowed_capitalized: match &args.allow_capitalized {
+            Some(path) => std::fs::read_to_string(absolute_path(path)?)?
+                .lines()
+                .map(tokenizer::normalize_word)
+                .filter(|word| !word.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        },
+    };
+    let recordings = recordings(args)?;
 
-    let _ = modify_files_in_zip(
-        &input_zip_path, 
-        &output_zip_path,
-        wrap_words_in_paragraphs,
-        &args.lang,
-    );
+    let mut book = EpubDocument::open(&input_zip_path)?;
+    let mut pipeline = Pipeline::new().keep_going(args.keep_going);
+    if args.difficulty_report.is_some() || args.difficulty_badges {
+        let frequencies = match &args.frequency_list {
+            Some(path) => Some(FrequencyList::open(absolute_path(path)?)?),
+            None => None,
+        };
+        let mut difficulty = Difficulty::new(frequencies).badges(args.difficulty_badges);
+        if let Some(report) = &args.difficulty_report {
+            difficulty = difficulty.report(absolute_path(report)?);
+        }
+        pipeline = pipeline.with(difficulty);
+    }
+    pipeline = pipeline.with(WrapWords::new(config).options(options));
+    if args.vocabulary {
+        let ranking = match &args.known {
+            Some(path) => Ranking::Unknown(anki::read_known_words(absolute_path(path)?)?),
+            None => Ranking::Frequency,
+        };
+        let mut glossary = Glossary::new(ranking, lang).limit(args.vocabulary_size.unwrap_or(usize::MAX));
+        if let Some(path) = &args.glossary {
+            glossary = glossary.glosses(TsvGlossary::open(absolute_path(path)?)?);
+        }
+        pipeline = pipeline.with(glossary);
+    }
+    if let Some(tts_command) = &args.tts_command {
+        pipeline = pipeline.with(SpeechOverlays::new(CommandSynthesizer::new(tts_command)?, lang));
+    }
+    if !recordings.is_empty() {
+        pipeline = pipeline.with(AudiobookOverlays::new(recordings));
+    }
+    let mut edition = Edition::new();
+    if args.new_edition {
+        let suffix = match (&args.title_suffix, &args.target_lang) {
+            (Some(suffix), _) => suffix.clone(),
+            (None, Some(target_lang)) => format!("(interactive, {}→{})", lang, target_lang),
+            (None, None) => format!("(interactive, {})", lang),
+        };
+        edition = edition.new_identifier(true).title_suffix(suffix);
+    }
+    pipeline = pipeline.with(edition);
+    if let Some(chapters) = &args.chapters {
+        let range = chapters.resolve(book.package())
+            .map_err(|message| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message)))?;
+        pipeline = pipeline.chapters(range);
+    }
+    let report = pipeline.run(&mut book)?;
+    for err in &report.skipped {
+        eprintln!("Warning: copying unchanged, {}", err);
+    }
+
+    if let Some(limit) = args.dry_run {
+        print_changes(&mut book, limit)?;
+        return Ok(true);
+    }
 
+    book.save(&output_zip_path)?;
     println!("Epub modified successfully!");
+
+    if args.validate {
+        return run_validate(&args.output);
+    }
+    Ok(true)
 }
 
-fn modify_files_in_zip(
-    input_path: &PathBuf, 
-    output_path: &PathBuf,
-    modify_fn: fn(&str, &str) -> String,
-    language: &str,
-) -> io::Result<()> {
-    let input = std::fs::File::open(input_path).unwrap();
-    let output = std::fs::File::create(output_path).unwrap();
-    let mut archive = ZipArchive::new(input)?;
-
-    let mut modified_files: HashMap<String, Vec<u8>> = HashMap::new();
-
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let file_name = file.name().to_string();
- 
-        if file_name.ends_with(".xhtml") || file_name.ends_with(".html") {
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            let modified_contents = modify_fn(&contents, language);
-            modified_files.insert(file_name, modified_contents.into_bytes());
-        } else {
-            // Copy the file as is
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer)?;
-            modified_files.insert(file_name, buffer);
+/// Prints how each changed entry differs from the input, with a diff of
+/// the first `limit` changes across the book.
+fn print_changes(book: &mut EpubDocument, limit: usize) -> Result<(), Error> {
+    let mut diffs = String::new();
+    let mut remaining = limit;
+    let mut changed = 0;
+    for name in book.changed_entries() {
+        // Added audio can be large, so only its size is looked at
+        let Some(old) = book.read_original(&name)? else {
+            println!("{}: added, {} bytes", name, book.entry_size(&name)?);
+            changed += 1;
+            continue;
+        };
+        let new = book.read(&name)?;
+        if old == new {
+            continue;
+        }
+        changed += 1;
+        let texts = encoding::decode(&old).zip(encoding::decode(&new))
+            .filter(|(old, new)| !old.contains('\0') && !new.contains('\0'));
+        let Some((old_text, new_text)) = texts else {
+            println!("{}: {} -> {} bytes", name, old.len(), new.len());
+            continue;
+        };
+        let lines = diff::lines(&old_text, &new_text);
+        let (removed, added) = diff::stats(&lines);
+        println!("{}: {} -> {} bytes, -{} +{} lines", name, old.len(), new.len(), removed, added);
+        if remaining > 0 {
+            let (unified, shown) = diff::unified(&name, &lines, remaining);
+            diffs.push_str(&unified);
+            remaining -= shown;
         }
     }
+    println!("{} entr{} would change, nothing was written", changed, if changed == 1 { "y" } else { "ies" });
+    if !diffs.is_empty() {
+        println!();
+        print!("{}", diffs);
+    }
+    Ok(())
+}
 
-    let mut zip_writer = ZipWriter::new(output);
+/// Pairs each audiobook file with its transcript, transcribing the ones that
+/// have none if a whisper model was given.
+fn recordings(args: &Args) -> Result<Vec<Recording>, Error> {
+    let mut recordings = Vec::with_capacity(args.audiobook.len());
+    for (i, audio) in args.audiobook.iter().enumerate() {
+        let audio_path = absolute_path(audio)?;
+        if let Some(transcript) = args.transcript.get(i) {
+            recordings.push(Recording::from_transcript(audio_path, absolute_path(transcript)?)?);
+            continue;
+        }
+        #[cfg(feature = "whisper")]
+        if let Some(model) = &args.whisper_model {
+            println!("Transcribing {}...", audio);
+            recordings.push(Recording::transcribe(audio_path, absolute_path(model)?, args.lang.as_deref().unwrap_or("auto"))?);
+            continue;
+        }
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no --transcript for {}", audio),
+        )));
+    }
+    Ok(recordings)
+}
 
-    for (name, data) in modified_files {
-        let options = SimpleFileOptions::default()
-            .compression_method(zip::CompressionMethod::Deflated);
-        zip_writer.start_file(name, options).unwrap();
-        zip_writer.write_all(&data).unwrap();
+/// Prints every problem found in the epub and returns whether it was clean.
+fn run_validate(input: &str) -> Result<bool, Error> {
+    let issues = validate(absolute_path(input)?)?;
+    for issue in &issues {
+        eprintln!("{}", issue);
+    }
+    if issues.is_empty() {
+        println!("{} is valid", input);
+    } else {
+        eprintln!("{} problem(s) found in {}", issues.len(), input);
     }
+    Ok(issues.is_empty())
+}
 
-    zip_writer.finish().unwrap();
-    Ok(())
+/// Writes every lookup in the dump as a segment file.
+fn run_export_lookups(dump: &str, output: Option<&str>) -> Result<bool, Error> {
+    let json = std::fs::read_to_string(absolute_path(dump)?)?;
+    let lookups = lookups::parse_dump(&json)
+        .map_err(|message| Error::Malformed { entry: dump.to_string(), message })?;
+
+    let directory = match output {
+        Some(output) => absolute_path(output)?,
+        None => {
+            let home = std::env::var_os("HOME").ok_or_else(|| Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                "HOME is not set, pass --output",
+            )))?;
+            PathBuf::from(home).join(".flashcard").join("segments")
+        }
+    };
+    let written = lookups::write_segments(&lookups, &directory)?;
+    println!("Wrote {} segment(s) to {}", written, directory.display());
+    Ok(true)
+}
+
+/// Builds a vocabulary deck from the book.
+fn run_anki(args: &Args) -> Result<bool, Error> {
+    let Some(Command::Anki { input, output, lang, known, glossary, limit, deck, tts_command, audio_format }) = &args.command else {
+        unreachable!("run_anki is only called for the anki subcommand");
+    };
+    let mut book = EpubDocument::open(absolute_path(input)?)?;
+    let language = lang.as_deref().or(book.package().language()).map(str::to_string).ok_or_else(|| {
+        Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "the book has no language, pass --lang"))
+    })?;
+    let name = deck.clone()
+        .or_else(|| book.package().title().map(str::to_string))
+        .unwrap_or_else(|| file_stem(input));
+    let output = match output {
+        Some(output) => absolute_path(output)?,
+        None => absolute_path(&format!("{}.apkg", file_stem(input)))?,
+    };
+
+    let ranking = match known {
+        Some(path) => Ranking::Unknown(anki::read_known_words(absolute_path(path)?)?),
+        None => Ranking::Frequency,
+    };
+    let mut glossary = match glossary {
+        Some(path) => Some(TsvGlossary::open(absolute_path(path)?)?),
+        None => None,
+    };
+    let mut synthesizer = match tts_command {
+        Some(command) => Some(CommandSynthesizer::new(command)?),
+        None => None,
+    };
+    let format = if audio_format == "wav" { AudioFormat::Wav } else { AudioFormat::Mp3 };
+
+    let words = anki::vocabulary(&mut book, &ranking, *limit)?;
+    let mut deck = Deck::new(name, &language);
+    for word in &words {
+        let mut note = Note {
+            word: word.word.clone(),
+            sentence: anki::highlight(&word.sentence, &word.word),
+            ..Note::default()
+        };
+        if let Some(glossary) = &mut glossary {
+            note.gloss = glossary.gloss(&word.word)?.unwrap_or_default();
+        }
+        if let Some(synthesizer) = &mut synthesizer {
+            note.audio = deck.add_media(record(synthesizer, &word.word, &language, format)?, format.extension());
+            if !word.sentence.is_empty() {
+                note.sentence_audio = deck.add_media(record(synthesizer, &word.sentence, &language, format)?, format.extension());
+            }
+        }
+        deck.add_note(note);
+    }
+    deck.write(&output)?;
+    println!("Wrote {} note(s) to {}", deck.notes().len(), output.display());
+    Ok(true)
+}
+
+/// Writes the translation into the original book.
+fn run_bilingual(args: &Args) -> Result<bool, Error> {
+    let Some(Command::Bilingual { original, translation, output, layout, translation_lang, validate }) = &args.command else {
+        unreachable!("run_bilingual is only called for the bilingual subcommand");
+    };
+    let mut book = EpubDocument::open(absolute_path(original)?)?;
+    let mut translated = EpubDocument::open(absolute_path(translation)?)?;
+    let language = translation_lang.as_deref().or(translated.package().language()).map(str::to_string).ok_or_else(|| {
+        Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "the translation has no language, pass --translation-lang"))
+    })?;
+    let arrangement = match layout.as_str() {
+        "paragraphs" => Arrangement::Paragraphs,
+        "sentences" => Arrangement::Sentences,
+        _ => Arrangement::SideBySide,
+    };
+
+    let placed = bilingual::merge(&mut book, &mut translated, arrangement, &language)?;
+    book.save(absolute_path(output)?)?;
+    println!("Placed {} translated paragraph(s) in {}", placed, output);
+    if *validate {
+        return run_validate(output);
+    }
+    Ok(true)
 }
 
+fn record(synthesizer: &mut CommandSynthesizer, text: &str, language: &str, format: AudioFormat) -> Result<Vec<u8>, Error> {
+    let wav = synthesizer.synthesize(text, language)?;
+    match format {
+        AudioFormat::Mp3 => audio::encode_mp3(&wav),
+        AudioFormat::Wav => Ok(wav),
+    }
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map_or_else(|| "deck".to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+fn absolute_path(path: &str) -> io::Result<PathBuf> {
+    if Path::new(path).is_absolute() {
+        Ok(PathBuf::from(path))
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
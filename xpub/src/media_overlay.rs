@@ -0,0 +1,319 @@
+//! EPUB 3 Media Overlays: SMIL documents that tie each sentence span to a
+//! clip of audio, so compliant readers can read the book aloud and highlight
+//! the sentence being spoken.
+
+use crate::audio::{self, TempFile, Wav};
+use crate::epub::EpubDocument;
+use crate::error::Error;
+use crate::html_parser::SENTENCE_ID_PREFIX;
+use crate::package::{self, ManifestItem};
+use crate::tokenizer;
+use crate::transform::{Document, Transform};
+use crate::tts::SpeechSynthesizer;
+
+/// Class readers put on the sentence that is being read.
+pub const ACTIVE_CLASS: &str = "-epub-media-overlay-active";
+
+/// Pause left after every synthesized sentence, in seconds.
+const SENTENCE_GAP: f64 = 0.25;
+
+/// Format of recordings outside the book. Media overlays are always MP3,
+/// as WAV is not one of the audio types EPUB 3 readers have to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Mp3,
+    Wav,
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Wav => "wav",
+        }
+    }
+}
+
+/// Media type of an audio file, from its extension.
+pub fn audio_media_type(path: &str) -> Option<&'static str> {
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "mp3" => Some("audio/mpeg"),
+        "m4a" | "m4b" | "mp4" | "aac" => Some("audio/mp4"),
+        "ogg" | "oga" | "opus" => Some("audio/ogg"),
+        "wav" => Some("audio/wav"),
+        _ => None,
+    }
+}
+
+/// A stretch of audio that reads one sentence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clip {
+    /// Id of the sentence span in the content document
+    pub sentence_id: String,
+    /// Archive entry of the audio file
+    pub audio: String,
+    /// Start and end in seconds
+    pub begin: f64,
+    pub end: f64,
+}
+
+/// Clips for one content document. The audio they point at has to be added
+/// to the book separately.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    /// Manifest id of the content document
+    pub item_id: String,
+    /// Archive entry of the content document
+    pub document: String,
+    pub clips: Vec<Clip>,
+}
+
+impl Overlay {
+    /// Playing time of the clips, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.clips.iter().map(|clip| clip.end - clip.begin).sum()
+    }
+}
+
+/// Ids and text of the sentence spans the word wrapping pass added.
+pub fn sentences(html: &str) -> Vec<(String, String)> {
+    let marker = format!("<span class=\"xpub-sentence\" id=\"{}", SENTENCE_ID_PREFIX);
+    let mut sentences = Vec::new();
+    let mut from = 0;
+
+    while let Some(start) = html[from..].find(&marker).map(|i| i + from) {
+        let id_start = start + marker.len() - SENTENCE_ID_PREFIX.len();
+        let Some(id_end) = html[id_start..].find('"').map(|i| i + id_start) else {
+            break;
+        };
+        let Some(length) = tokenizer::find_element_end(&html[start..], "span") else {
+            break;
+        };
+        let text = tokenizer::text_content(&html[start..start + length]);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            sentences.push((html[id_start..id_end].to_string(), text));
+        }
+        from = start + length;
+    }
+
+    sentences
+}
+
+/// Adds a style rule so the sentence being read aloud is highlighted.
+pub fn highlight_active_sentence(content: &mut String) {
+    let style = format!("<style>.{} {{ background-color: #ffe08a; }}</style>\n", ACTIVE_CLASS);
+    match content.find("</head>") {
+        Some(index) => content.insert_str(index, &style),
+        None => content.insert_str(0, &style),
+    }
+}
+
+/// Writes the SMIL documents into the book, points the content documents at
+/// them and records the durations in the package metadata.
+pub fn add_overlays(book: &mut EpubDocument, overlays: &[Overlay]) -> Result<(), Error> {
+    if overlays.is_empty() {
+        return Ok(());
+    }
+
+    let mut total = 0.0;
+    for overlay in overlays {
+        let smil_id = book.package().unused_id(&format!("{}-overlay", overlay.item_id));
+        let smil_href = format!("xpub/overlays/{}.smil", overlay.item_id);
+        let smil_path = package::resolve_href(book.opf_path(), &smil_href);
+        let smil = smil_document(&smil_path, &overlay.document, &overlay.clips);
+
+        book.add_resource(ManifestItem::new(&smil_id, smil_href, "application/smil+xml"), smil)?;
+        book.set_item_attribute(&overlay.item_id, "media-overlay", &smil_id)?;
+        book.add_metadata(&format!(
+            "<meta property=\"media:duration\" refines=\"#{}\">{}</meta>",
+            smil_id,
+            clock_value(overlay.duration()),
+        ))?;
+        total += overlay.duration();
+    }
+
+    book.add_metadata(&format!("<meta property=\"media:duration\">{}</meta>", clock_value(total)))?;
+    book.add_metadata(&format!("<meta property=\"media:active-class\">{}</meta>", ACTIVE_CLASS))
+}
+
+fn smil_document(smil_path: &str, document: &str, clips: &[Clip]) -> String {
+    let text_href = package::escape_attribute(&package::relative_href(smil_path, document));
+    let mut smil = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <smil xmlns=\"http://www.w3.org/ns/SMIL\" xmlns:epub=\"http://www.idpf.org/2007/ops\" version=\"3.0\">\n\
+         <body>\n",
+    );
+    smil.push_str(&format!("<seq id=\"seq-1\" epub:textref=\"{}\">\n", text_href));
+    for (i, clip) in clips.iter().enumerate() {
+        smil.push_str(&format!(
+            "<par id=\"par-{}\"><text src=\"{}#{}\"/><audio src=\"{}\" clipBegin=\"{}\" clipEnd=\"{}\"/></par>\n",
+            i + 1,
+            text_href,
+            clip.sentence_id,
+            package::escape_attribute(&package::relative_href(smil_path, &clip.audio)),
+            clock_value(clip.begin),
+            clock_value(clip.end),
+        ));
+    }
+    smil.push_str("</seq>\n</body>\n</smil>\n");
+    smil
+}
+
+/// Formats seconds as a SMIL clock value, e.g. `0:01:02.345`.
+pub fn clock_value(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000,
+    )
+}
+
+/// Reads every sentence aloud with a speech synthesizer and adds the
+/// recordings to the book as media overlays.
+///
+/// Needs sentence spans, so it has to run after [`crate::WrapWords`] with
+/// `mark_sentences` switched on.
+pub struct SpeechOverlays<S> {
+    synthesizer: S,
+    language: String,
+    /// Recordings waiting to be added: (document manifest id, href, MP3 file)
+    audio: Vec<(String, String, TempFile)>,
+    overlays: Vec<Overlay>,
+}
+
+impl<S: SpeechSynthesizer> SpeechOverlays<S> {
+    pub fn new(synthesizer: S, language: impl Into<String>) -> Self {
+        SpeechOverlays {
+            synthesizer,
+            language: language.into(),
+            audio: Vec::new(),
+            overlays: Vec::new(),
+        }
+    }
+}
+
+impl<S: SpeechSynthesizer> Transform for SpeechOverlays<S> {
+    fn begin(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        require_epub3(book)
+    }
+
+    fn transform(&mut self, book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        let sentences = sentences(&document.content);
+        if sentences.is_empty() {
+            return Ok(());
+        }
+        let audio_href = format!("xpub/audio/{}.mp3", document.item.id);
+        let audio_path = package::resolve_href(book.opf_path(), &audio_href);
+
+        let mut track: Option<Wav> = None;
+        let mut clips = Vec::with_capacity(sentences.len());
+        for (sentence_id, text) in sentences {
+            let speech = self.synthesizer.synthesize(&text, &self.language)?;
+            let speech = Wav::parse(&speech).ok_or_else(|| Error::Command {
+                command: "TTS".to_string(),
+                message: format!("did not produce a WAV file for \"{}\"", text),
+            })?;
+            let track = track.get_or_insert_with(|| speech.empty_like());
+            if !track.same_format(&speech) {
+                return Err(Error::Command {
+                    command: "TTS".to_string(),
+                    message: "produced WAV files in different formats".to_string(),
+                });
+            }
+
+            let begin = track.duration();
+            track.append(&speech);
+            clips.push(Clip {
+                sentence_id,
+                audio: audio_path.clone(),
+                begin,
+                end: track.duration(),
+            });
+            track.append_silence(SENTENCE_GAP);
+        }
+
+        // Each chapter's audio waits on disk, so a long book is never all in memory
+        let wav = track.map(|track| track.to_bytes()).unwrap_or_default();
+        let mp3 = TempFile::new("mp3");
+        audio::encode_mp3_to(&wav, &mp3.path)?;
+        self.audio.push((document.item.id.clone(), audio_href, mp3));
+        self.overlays.push(Overlay {
+            item_id: document.item.id.clone(),
+            document: document.name.clone(),
+            clips,
+        });
+        highlight_active_sentence(&mut document.content);
+        Ok(())
+    }
+
+    fn finish(&mut self, book: &mut EpubDocument) -> Result<(), Error> {
+        for (item_id, href, mp3) in std::mem::take(&mut self.audio) {
+            let id = book.package().unused_id(&format!("{}-audio", item_id));
+            book.add_resource_temp_file(ManifestItem::new(&id, href, "audio/mpeg"), mp3)?;
+        }
+        add_overlays(book, &std::mem::take(&mut self.overlays))
+    }
+}
+
+/// Media overlays only exist in EPUB 3.
+pub fn require_epub3(book: &EpubDocument) -> Result<(), Error> {
+    if book.package().version.starts_with('3') {
+        Ok(())
+    } else {
+        Err(Error::Malformed {
+            entry: book.opf_path().to_string(),
+            message: format!("media overlays need an EPUB 3 package, this is version {}", book.package().version),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_values() {
+        assert_eq!(clock_value(0.0), "0:00:00.000");
+        assert_eq!(clock_value(62.345), "0:01:02.345");
+        assert_eq!(clock_value(3599.9996), "1:00:00.000");
+        assert_eq!(clock_value(36_000.25), "10:00:00.250");
+    }
+
+    #[test]
+    fn smil_documents() {
+        let clips = [
+            Clip { sentence_id: "s1".to_string(), audio: "OEBPS/xpub/audio/c1.mp3".to_string(), begin: 0.0, end: 1.5 },
+            Clip { sentence_id: "s2".to_string(), audio: "OEBPS/xpub/audio/c1.mp3".to_string(), begin: 1.75, end: 3.0 },
+        ];
+        let smil = smil_document("OEBPS/xpub/overlays/c1.smil", "OEBPS/text/c1.xhtml", &clips);
+        assert_eq!(smil, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <smil xmlns=\"http://www.w3.org/ns/SMIL\" xmlns:epub=\"http://www.idpf.org/2007/ops\" version=\"3.0\">\n\
+            <body>\n\
+            <seq id=\"seq-1\" epub:textref=\"../../text/c1.xhtml\">\n\
+            <par id=\"par-1\"><text src=\"../../text/c1.xhtml#s1\"/>\
+            <audio src=\"../audio/c1.mp3\" clipBegin=\"0:00:00.000\" clipEnd=\"0:00:01.500\"/></par>\n\
+            <par id=\"par-2\"><text src=\"../../text/c1.xhtml#s2\"/>\
+            <audio src=\"../audio/c1.mp3\" clipBegin=\"0:00:01.750\" clipEnd=\"0:00:03.000\"/></par>\n\
+            </seq>\n</body>\n</smil>\n");
+        let overlay = Overlay { item_id: "c1".to_string(), document: "OEBPS/text/c1.xhtml".to_string(), clips: clips.to_vec() };
+        assert_eq!(overlay.duration(), 2.75);
+    }
+
+    #[test]
+    fn finds_sentences() {
+        let html = format!(
+            "<p><span class=\"xpub-sentence\" id=\"{0}1\">Hola, <b>mundo</b>.</span> \
+             <span class=\"xpub-sentence\" id=\"{0}2\"> </span><span class=\"xpub-sentence\" id=\"{0}3\">Adiós\n  ya.</span></p>",
+            SENTENCE_ID_PREFIX,
+        );
+        assert_eq!(sentences(&html), [
+            (format!("{}1", SENTENCE_ID_PREFIX), "Hola, mundo.".to_string()),
+            (format!("{}3", SENTENCE_ID_PREFIX), "Adiós ya.".to_string()),
+        ]);
+    }
+}
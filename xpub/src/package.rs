@@ -0,0 +1,299 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::error::ParseError;
+
+/// The parts of the OPF package document that transforms need to look at.
+#[derive(Debug, Clone, Default)]
+pub struct Package {
+    pub version: String,
+    /// Id of the `dc:identifier` element that uniquely identifies the book.
+    pub unique_identifier: Option<String>,
+    pub metadata: Metadata,
+    pub manifest: Vec<ManifestItem>,
+    pub spine: Spine,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub identifiers: Vec<Identifier>,
+    pub titles: Vec<String>,
+    pub languages: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identifier {
+    pub id: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestItem {
+    pub id: String,
+    /// Location of the resource relative to the package document.
+    pub href: String,
+    pub media_type: String,
+    pub properties: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Spine {
+    pub items: Vec<SpineItem>,
+    pub page_progression_direction: Option<String>,
+    /// Id of the NCX, for EPUB 2 books.
+    pub toc: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpineItem {
+    pub idref: String,
+    pub linear: bool,
+}
+
+impl ManifestItem {
+    pub fn new(id: impl Into<String>, href: impl Into<String>, media_type: impl Into<String>) -> Self {
+        ManifestItem {
+            id: id.into(),
+            href: href.into(),
+            media_type: media_type.into(),
+            properties: None,
+        }
+    }
+
+    pub fn with_properties(mut self, properties: impl Into<String>) -> Self {
+        self.properties = Some(properties.into());
+        self
+    }
+
+    pub fn has_property(&self, property: &str) -> bool {
+        self.properties.as_deref()
+            .is_some_and(|properties| properties.split_whitespace().any(|p| p == property))
+    }
+
+    /// True for XHTML and HTML content documents, the ones words get wrapped in.
+    pub fn is_content_document(&self) -> bool {
+        self.media_type == "application/xhtml+xml" || self.media_type == "text/html"
+    }
+
+    /// Serializes the item as an OPF `<item/>` element.
+    pub fn to_xml(&self) -> String {
+        let mut xml = format!(
+            "<item id=\"{}\" href=\"{}\" media-type=\"{}\"",
+            escape_attribute(&self.id),
+            escape_attribute(&self.href),
+            escape_attribute(&self.media_type),
+        );
+        if let Some(properties) = &self.properties {
+            xml.push_str(&format!(" properties=\"{}\"", escape_attribute(properties)));
+        }
+        xml.push_str("/>");
+        xml
+    }
+}
+
+impl Package {
+    pub fn parse(source: &str) -> Result<Package, ParseError> {
+        let mut reader = Reader::from_str(source);
+        let mut package = Package::default();
+        let mut in_metadata = false;
+        // Metadata element whose text is being collected, with its id
+        let mut current: Option<(Vec<u8>, Option<String>)> = None;
+        let mut text = String::new();
+
+        loop {
+            let event = reader.read_event()
+                .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+            match event {
+                Event::Start(e) | Event::Empty(e) if !in_metadata || current.is_none() => {
+                    let name = e.local_name().as_ref().to_vec();
+                    match name.as_slice() {
+                        b"package" => {
+                            package.version = attribute(&e, b"version").unwrap_or_default();
+                            package.unique_identifier = attribute(&e, b"unique-identifier");
+                        }
+                        b"metadata" => in_metadata = true,
+                        b"identifier" | b"title" | b"language" if in_metadata => {
+                            current = Some((name, attribute(&e, b"id")));
+                            text.clear();
+                        }
+                        b"item" => package.manifest.push(ManifestItem {
+                            id: attribute(&e, b"id").unwrap_or_default(),
+                            href: attribute(&e, b"href").unwrap_or_default(),
+                            media_type: attribute(&e, b"media-type").unwrap_or_default(),
+                            properties: attribute(&e, b"properties"),
+                        }),
+                        b"spine" => {
+                            package.spine.page_progression_direction = attribute(&e, b"page-progression-direction");
+                            package.spine.toc = attribute(&e, b"toc");
+                        }
+                        b"itemref" => package.spine.items.push(SpineItem {
+                            idref: attribute(&e, b"idref").unwrap_or_default(),
+                            linear: attribute(&e, b"linear").as_deref() != Some("no"),
+                        }),
+                        _ => {}
+                    }
+                }
+                Event::Text(e) if current.is_some() => {
+                    let unescaped = e.unescape()
+                        .map_err(|err| ParseError::new(reader.buffer_position() as usize, err.to_string()))?;
+                    text.push_str(&unescaped);
+                }
+                Event::CData(e) if current.is_some() => {
+                    text.push_str(&String::from_utf8_lossy(&e));
+                }
+                Event::End(e) => {
+                    let name = e.local_name();
+                    if name.as_ref() == b"metadata" {
+                        in_metadata = false;
+                    } else if current.as_ref().is_some_and(|(element, _)| element.as_slice() == name.as_ref()) {
+                        let (element, id) = current.take().unwrap();
+                        let value = text.trim().to_string();
+                        match element.as_slice() {
+                            b"identifier" => package.metadata.identifiers.push(Identifier { id, value }),
+                            b"title" => package.metadata.titles.push(value),
+                            _ => package.metadata.languages.push(value),
+                        }
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        Ok(package)
+    }
+
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    /// The EPUB 3 navigation document, if the book has one.
+    pub fn nav_item(&self) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.has_property("nav"))
+    }
+
+    /// Value of the identifier the package's `unique-identifier` points at.
+    pub fn unique_identifier_value(&self) -> Option<&str> {
+        let identifiers = &self.metadata.identifiers;
+        self.unique_identifier.as_deref()
+            .and_then(|id| identifiers.iter().find(|identifier| identifier.id.as_deref() == Some(id)))
+            .or_else(|| identifiers.first())
+            .map(|identifier| identifier.value.as_str())
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.metadata.languages.first().map(String::as_str)
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.metadata.titles.first().map(String::as_str)
+    }
+
+    /// Returns an id that is not used by any manifest item yet.
+    pub fn unused_id(&self, prefix: &str) -> String {
+        (0..)
+            .map(|n| if n == 0 { prefix.to_string() } else { format!("{}-{}", prefix, n) })
+            .find(|id| self.item(id).is_none())
+            .unwrap()
+    }
+}
+
+/// Finds the `full-path` of the package document in `META-INF/container.xml`.
+pub fn parse_container(source: &str) -> Result<Option<String>, ParseError> {
+    let mut reader = Reader::from_str(source);
+    loop {
+        let event = reader.read_event()
+            .map_err(|err| ParseError::new(reader.error_position() as usize, err.to_string()))?;
+        match event {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                let media_type = attribute(&e, b"media-type");
+                if media_type.is_none() || media_type.as_deref() == Some("application/oebps-package+xml") {
+                    return Ok(attribute(&e, b"full-path"));
+                }
+            }
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Returns the unescaped value of the attribute with the given local name.
+pub(crate) fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element.attributes()
+        .flatten()
+        .find(|attr| attr.key.local_name().as_ref() == name)
+        .map(|attr| match attr.unescape_value() {
+            Ok(value) => value.into_owned(),
+            Err(_) => String::from_utf8_lossy(&attr.value).into_owned(),
+        })
+}
+
+pub(crate) fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Resolves `href`, as written in the entry `base`, to the name of an archive
+/// entry. Fragments are dropped and percent-escapes decoded.
+pub fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = percent_decode(href);
+    if let Some(absolute) = href.strip_prefix('/') {
+        return normalize(absolute.split('/').collect());
+    }
+    let mut segments: Vec<&str> = base.split('/').collect();
+    // Drop the file name of the base entry
+    segments.pop();
+    segments.extend(href.split('/'));
+    normalize(segments)
+}
+
+/// Returns the href that points from the entry `from` to the entry `to`.
+pub fn relative_href(from: &str, to: &str) -> String {
+    let from_dirs: Vec<&str> = from.split('/').collect();
+    let from_dirs = &from_dirs[..from_dirs.len() - 1];
+    let to_segments: Vec<&str> = to.split('/').collect();
+
+    let common = from_dirs.iter()
+        .zip(&to_segments[..to_segments.len() - 1])
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut segments: Vec<&str> = vec![".."; from_dirs.len() - common];
+    segments.extend(&to_segments[common..]);
+    segments.join("/")
+}
+
+fn normalize(segments: Vec<&str>) -> String {
+    let mut normalized: Vec<&str> = Vec::new();
+    for segment in segments {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                normalized.pop();
+            }
+            _ => normalized.push(segment),
+        }
+    }
+    normalized.join("/")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(Ok(byte)) = value.get(i + 1..i + 3).map(|hex| u8::from_str_radix(hex, 16)) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
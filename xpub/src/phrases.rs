@@ -0,0 +1,98 @@
+//! Multi-word expressions that are looked up as one unit.
+//!
+//! Idioms and phrasal verbs like "give up" or "tener que" mean something
+//! other than their words do on their own. Given a list of them, the word
+//! wrapping pass makes each occurrence a single clickable span.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::tokenizer;
+
+#[derive(Debug, Clone, Default)]
+pub struct PhraseList {
+    /// Phrases by their first word, longest first
+    phrases: HashMap<String, Vec<Vec<String>>>,
+    longest: usize,
+}
+
+impl PhraseList {
+    /// Reads a list with one phrase per line. Anything after a tab is
+    /// ignored, and so are lines starting with `#` and single words.
+    pub fn open(path: impl AsRef<Path>) -> Result<PhraseList, Error> {
+        Ok(PhraseList::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(source: &str) -> PhraseList {
+        let mut list = PhraseList::default();
+        for line in source.lines().filter(|line| !line.starts_with('#')) {
+            list.insert(line.split('\t').next().unwrap_or_default());
+        }
+        list
+    }
+
+    pub fn insert(&mut self, phrase: &str) {
+        let words: Vec<String> = phrase.split_whitespace().map(tokenizer::normalize_word).collect();
+        if words.len() < 2 || words.iter().any(String::is_empty) {
+            return;
+        }
+        self.longest = self.longest.max(words.len());
+        let phrases = self.phrases.entry(words[0].clone()).or_default();
+        if !phrases.contains(&words) {
+            phrases.push(words);
+            phrases.sort_by_key(|phrase| std::cmp::Reverse(phrase.len()));
+        }
+    }
+
+    /// Number of words of the longest phrase `words` starts with. The words
+    /// must already be normalized with [`tokenizer::normalize_word`].
+    pub fn match_at(&self, words: &[String]) -> Option<usize> {
+        let phrases = self.phrases.get(words.first()?)?;
+        phrases.iter()
+            .find(|phrase| words.starts_with(phrase))
+            .map(Vec::len)
+    }
+
+    /// Words in the longest phrase.
+    pub fn longest(&self) -> usize {
+        self.longest
+    }
+
+    pub fn len(&self) -> usize {
+        self.phrases.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phrases.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(tokenizer::normalize_word).collect()
+    }
+
+    #[test]
+    fn parses_lists() {
+        let list = PhraseList::parse("# phrasal verbs\ngive up\trendirse\nGive  up\nup\n\ngive up on\n");
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.longest(), 3);
+        assert!(PhraseList::parse("# nothing\nword\n").is_empty());
+    }
+
+    #[test]
+    fn matches_the_longest_phrase() {
+        let list = PhraseList::parse("give up\ngive up on\ntener que\n");
+        assert_eq!(list.match_at(&words("give up on her")), Some(3));
+        assert_eq!(list.match_at(&words("give up, then")), Some(2));
+        assert_eq!(list.match_at(&words("¡Tenemos que irnos!")), None);
+        assert_eq!(list.match_at(&words("Tener que irse")), Some(2));
+        assert_eq!(list.match_at(&words("give")), None);
+        assert_eq!(list.match_at(&words("up give up")), None);
+        assert_eq!(list.match_at(&[]), None);
+    }
+}
@@ -0,0 +1,229 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::epub::EpubDocument;
+use crate::error::Error;
+use crate::package::{ManifestItem, Package};
+use crate::transform::{Document, Transform};
+
+/// Runs a chain of transforms over every content document of a book.
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+    keep_going: bool,
+    chapters: Option<Range<usize>>,
+}
+
+/// What happened during a run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of documents that were rewritten
+    pub documents: usize,
+    /// Documents left unchanged because they could not be read or parsed
+    pub skipped: Vec<Error>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Adds a pass. Passes run in the order they were added.
+    pub fn with(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Leave documents that cannot be read or parsed unchanged instead of
+    /// failing the whole run.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Only rewrites the documents at these positions in the spine. Passes
+    /// still see the whole book in `begin` and `finish`.
+    pub fn chapters(mut self, chapters: Range<usize>) -> Self {
+        self.chapters = Some(chapters);
+        self
+    }
+
+    pub fn run(&mut self, book: &mut EpubDocument) -> Result<Report, Error> {
+        let mut report = Report::default();
+
+        for transform in &mut self.transforms {
+            transform.begin(book)?;
+        }
+
+        for item in book.content_documents() {
+            if let Some(chapters) = &self.chapters {
+                if !book.spine_index(&item.id).is_some_and(|index| chapters.contains(&index)) {
+                    continue;
+                }
+            }
+            let name = book.item_path(&item);
+            match self.transform_document(book, item, &name) {
+                Ok(content) => {
+                    book.set_entry(&name, content);
+                    report.documents += 1;
+                }
+                Err(err @ (Error::Entry { .. } | Error::Parse { .. })) if self.keep_going => {
+                    report.skipped.push(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        for transform in &mut self.transforms {
+            transform.finish(book)?;
+        }
+
+        Ok(report)
+    }
+
+    fn transform_document(&mut self, book: &mut EpubDocument, item: ManifestItem, name: &str) -> Result<String, Error> {
+        let content = book.read_content_document(name)?;
+        let mut document = Document {
+            name: name.to_string(),
+            spine_index: book.spine_index(&item.id),
+            item,
+            content,
+        };
+        for transform in &mut self.transforms {
+            transform.transform(book, &mut document)?;
+        }
+        Ok(document.content)
+    }
+}
+
+/// One end of a [`ChapterRange`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpineBound {
+    /// Position in the spine, from 0
+    Index(usize),
+    /// `idref` of a spine item
+    Id(String),
+}
+
+/// Part of a book's reading order, written like a Rust range: `3..7`,
+/// `3..=7`, `3..`, `..7` or a single `5`. Either end can be a spine index
+/// or the idref of a spine item, as in `chapter3..=chapter7`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChapterRange {
+    pub start: Option<SpineBound>,
+    pub end: Option<SpineBound>,
+    /// Whether `end` is part of the range
+    pub inclusive: bool,
+}
+
+impl ChapterRange {
+    /// Spine positions the range covers in `package`.
+    pub fn resolve(&self, package: &Package) -> Result<Range<usize>, String> {
+        let index = |bound: &SpineBound| match bound {
+            SpineBound::Index(index) => Ok(*index),
+            SpineBound::Id(id) => package.spine.items.iter()
+                .position(|itemref| itemref.idref == *id)
+                .ok_or_else(|| format!("no spine item {}", id)),
+        };
+        let start = self.start.as_ref().map(index).transpose()?.unwrap_or(0);
+        let end = match &self.end {
+            Some(end) => index(end)? + usize::from(self.inclusive),
+            None => package.spine.items.len(),
+        };
+        if start >= end || start >= package.spine.items.len() {
+            return Err(format!("no chapters in {}..{} of a spine of {}", start, end, package.spine.items.len()));
+        }
+        Ok(start..end)
+    }
+}
+
+impl FromStr for ChapterRange {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let bound = |text: &str| -> Option<SpineBound> {
+            let text = text.trim();
+            if text.is_empty() {
+                None
+            } else if let Ok(index) = text.parse() {
+                Some(SpineBound::Index(index))
+            } else {
+                Some(SpineBound::Id(text.to_string()))
+            }
+        };
+        let Some((start, end)) = source.split_once("..") else {
+            let Some(only) = bound(source) else {
+                return Err("empty chapter range".to_string());
+            };
+            return Ok(ChapterRange { start: Some(only.clone()), end: Some(only), inclusive: true });
+        };
+        let (end, inclusive) = match end.strip_prefix('=') {
+            Some(end) => (end, true),
+            None => (end, false),
+        };
+        let range = ChapterRange { start: bound(start), end: bound(end), inclusive };
+        if inclusive && range.end.is_none() {
+            return Err(format!("{} has no end after ..=", source));
+        }
+        Ok(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package() -> Package {
+        Package::parse(r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+            <metadata/>
+            <manifest>
+                <item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>
+                <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+                <item id="c2" href="c2.xhtml" media-type="application/xhtml+xml"/>
+                <item id="c3" href="c3.xhtml" media-type="application/xhtml+xml"/>
+            </manifest>
+            <spine><itemref idref="cover"/><itemref idref="c1"/><itemref idref="c2"/><itemref idref="c3"/></spine>
+        </package>"#).unwrap()
+    }
+
+    fn resolve(range: &str) -> Result<Range<usize>, String> {
+        range.parse::<ChapterRange>()?.resolve(&package())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!("1..3".parse(), Ok(ChapterRange {
+            start: Some(SpineBound::Index(1)),
+            end: Some(SpineBound::Index(3)),
+            inclusive: false,
+        }));
+        assert_eq!("c1..=c3".parse(), Ok(ChapterRange {
+            start: Some(SpineBound::Id("c1".to_string())),
+            end: Some(SpineBound::Id("c3".to_string())),
+            inclusive: true,
+        }));
+        assert_eq!("..".parse(), Ok(ChapterRange { start: None, end: None, inclusive: false }));
+        assert!("".parse::<ChapterRange>().is_err());
+        assert!("2..=".parse::<ChapterRange>().is_err());
+    }
+
+    #[test]
+    fn resolves_against_the_spine() {
+        assert_eq!(resolve("1..3"), Ok(1..3));
+        assert_eq!(resolve("1..=3"), Ok(1..4));
+        assert_eq!(resolve("2.."), Ok(2..4));
+        assert_eq!(resolve("..c2"), Ok(0..2));
+        assert_eq!(resolve("c1..=c2"), Ok(1..3));
+        // A single chapter, by index or idref
+        assert_eq!(resolve("2"), Ok(2..3));
+        assert_eq!(resolve("c3"), Ok(3..4));
+    }
+
+    #[test]
+    fn rejects_empty_and_unknown_ranges() {
+        assert!(resolve("3..1").is_err());
+        assert!(resolve("4..").is_err());
+        assert!(resolve("c1..c1").is_err());
+        assert_eq!(resolve("c9.."), Err("no spine item c9".to_string()));
+    }
+}
@@ -0,0 +1,232 @@
+//! Ruby annotations: readings over words, such as furigana for Japanese kanji
+//! or pinyin for Chinese hanzi, taken from a local reading dictionary.
+//!
+//! Chinese and Japanese are written without spaces, so the words of a run of
+//! text are found by looking up the longest dictionary entry at each point.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::error::{Error, ParseError};
+use crate::package;
+
+/// Reading and frequency rank of a dictionary word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reading {
+    pub reading: String,
+    /// Position in a frequency list, 1 being the most common word
+    pub rank: Option<usize>,
+}
+
+/// Readings of words, loaded from a tab separated file with one
+/// `word<TAB>reading[<TAB>rank]` entry per line. Empty lines and lines
+/// starting with `#` are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ReadingDictionary {
+    entries: HashMap<String, Reading>,
+    /// Length of the longest word, in characters
+    longest: usize,
+}
+
+impl ReadingDictionary {
+    pub fn open(path: impl AsRef<Path>) -> Result<ReadingDictionary, Error> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        ReadingDictionary::parse(&source).map_err(|source| Error::Parse {
+            entry: path.display().to_string(),
+            source,
+        })
+    }
+
+    pub fn parse(source: &str) -> Result<ReadingDictionary, ParseError> {
+        let mut dictionary = ReadingDictionary::default();
+        let mut offset = 0;
+        for line in source.split_inclusive('\n') {
+            let entry = line.trim_end_matches(['\r', '\n']);
+            if !entry.trim().is_empty() && !entry.starts_with('#') {
+                let mut fields = entry.split('\t');
+                let (Some(word), Some(reading)) = (fields.next(), fields.next()) else {
+                    return Err(ParseError::new(offset, "expected word<TAB>reading"));
+                };
+                let rank = match fields.next().map(str::trim).filter(|rank| !rank.is_empty()) {
+                    Some(rank) => Some(rank.parse().map_err(|_| ParseError::new(offset, format!("rank {} is not a number", rank)))?),
+                    None => None,
+                };
+                dictionary.insert(word.trim(), Reading { reading: reading.trim().to_string(), rank });
+            }
+            offset += line.len();
+        }
+        Ok(dictionary)
+    }
+
+    /// Adds a word, keeping the first reading if it is listed more than once.
+    pub fn insert(&mut self, word: &str, reading: Reading) {
+        if word.is_empty() || reading.reading.is_empty() {
+            return;
+        }
+        self.longest = self.longest.max(word.chars().count());
+        self.entries.entry(word.to_string()).or_insert(reading);
+    }
+
+    pub fn get(&self, word: &str) -> Option<&Reading> {
+        self.entries.get(word)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Which words get a reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RubyMode {
+    /// Every word found in the dictionary
+    Words,
+    /// Only words written with kanji or hanzi that are rarer than this rank,
+    /// or that have no rank at all
+    RarerThan(usize),
+}
+
+/// A piece of a word token after splitting it into dictionary words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    Word { text: &'a str, reading: Option<&'a str> },
+    /// Punctuation and entities, left outside the clickable spans
+    Other(&'a str),
+}
+
+/// Adds readings to the words of Chinese and Japanese text.
+#[derive(Debug, Clone)]
+pub struct Ruby {
+    dictionary: ReadingDictionary,
+    mode: RubyMode,
+}
+
+impl Ruby {
+    pub fn new(dictionary: ReadingDictionary, mode: RubyMode) -> Self {
+        Ruby { dictionary, mode }
+    }
+
+    /// True if `word` has Chinese or Japanese characters and so needs
+    /// splitting into dictionary words.
+    pub fn applies_to(word: &str) -> bool {
+        word.chars().any(|c| is_ideograph(c) || is_kana(c))
+    }
+
+    /// Splits a run of text without spaces into words, taking the longest
+    /// dictionary word at each point. Characters that start no dictionary
+    /// word are kept together, except that every unknown kanji or hanzi is a
+    /// word of its own.
+    pub fn split<'a>(&'a self, text: &'a str) -> Vec<Piece<'a>> {
+        let mut pieces = Vec::new();
+        let mut unknown_start = None;
+        let mut index = 0;
+
+        while index < text.len() {
+            let rest = &text[index..];
+            let c = rest.chars().next().unwrap();
+
+            let (length, piece) = if let Some(length) = entity_length(rest).or_else(|| (!c.is_alphanumeric()).then_some(c.len_utf8())) {
+                (length, Some(Piece::Other(&rest[..length])))
+            } else if let Some((length, reading)) = self.longest_match(rest) {
+                (length, Some(Piece::Word { text: &rest[..length], reading: self.show(&rest[..length], reading) }))
+            } else if is_ideograph(c) {
+                (c.len_utf8(), Some(Piece::Word { text: &rest[..c.len_utf8()], reading: None }))
+            } else {
+                unknown_start.get_or_insert(index);
+                (c.len_utf8(), None)
+            };
+
+            if piece.is_some() {
+                if let Some(start) = unknown_start.take() {
+                    pieces.push(Piece::Word { text: &text[start..index], reading: None });
+                }
+            }
+            pieces.extend(piece);
+            index += length;
+        }
+        if let Some(start) = unknown_start {
+            pieces.push(Piece::Word { text: &text[start..], reading: None });
+        }
+
+        pieces
+    }
+
+    /// Length in bytes and reading of the longest dictionary word `text` starts with.
+    fn longest_match<'a>(&'a self, text: &str) -> Option<(usize, &'a Reading)> {
+        let ends: Vec<usize> = text.char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .take(self.dictionary.longest)
+            .collect();
+        ends.into_iter().rev().find_map(|end| Some((end, self.dictionary.get(&text[..end])?)))
+    }
+
+    /// The reading to show over `word`, if the mode wants one there.
+    fn show<'a>(&self, word: &str, reading: &'a Reading) -> Option<&'a str> {
+        if reading.reading == word {
+            return None;
+        }
+        match self.mode {
+            RubyMode::Words => Some(&reading.reading),
+            RubyMode::RarerThan(rank) => (word.chars().any(is_ideograph) && reading.rank.is_none_or(|r| r > rank))
+                .then_some(&reading.reading),
+        }
+    }
+}
+
+/// Writes `word` with `reading` over it. Kana the word and its reading start
+/// or end with, like the る of 食べる (たべる), are left outside the ruby.
+pub fn annotate(word: &str, reading: &str, output: &mut String) {
+    let prefix = common_kana_prefix(word, reading);
+    let suffix = common_kana_prefix(&word[prefix..].chars().rev().collect::<String>(), &reading[prefix..].chars().rev().collect::<String>());
+    let (base, reading) = if prefix + suffix < word.len() && prefix + suffix < reading.len() {
+        (&word[prefix..word.len() - suffix], &reading[prefix..reading.len() - suffix])
+    } else {
+        (word, reading)
+    };
+    let (prefix, suffix) = if base.len() == word.len() { ("", "") } else { (&word[..prefix], &word[word.len() - suffix..]) };
+
+    output.push_str(prefix);
+    output.push_str("<ruby>");
+    output.push_str(base);
+    output.push_str("<rp>(</rp><rt>");
+    output.push_str(&package::escape_attribute(reading));
+    output.push_str("</rt><rp>)</rp></ruby>");
+    output.push_str(suffix);
+}
+
+/// Length in bytes of the kana both strings start with.
+fn common_kana_prefix(a: &str, b: &str) -> usize {
+    a.chars()
+        .zip(b.chars())
+        .take_while(|&(x, y)| x == y && is_kana(x))
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
+
+/// Length of the character or entity reference `text` starts with, if it is one.
+fn entity_length(text: &str) -> Option<usize> {
+    if !text.starts_with('&') {
+        return None;
+    }
+    text.char_indices().take(32).find(|&(_, c)| c == ';').map(|(i, _)| i + 1)
+}
+
+pub fn is_ideograph(c: char) -> bool {
+    matches!(c,
+        '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{2FA1F}'
+        | '々' | '〆')
+}
+
+pub fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3041}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}')
+}
@@ -0,0 +1,465 @@
+//! Splitting the markup inside a paragraph into words, spaces and tags.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// Markup between words, e.g. `<em>` or `</a>`
+    Tag(&'a str),
+    /// An existing `<span>...</span>` element, kept whole and clickable as one unit
+    Element(&'a str),
+    /// A ruby `<rt>` or `<rp>` element, kept whole and never clickable
+    Annotation(&'a str),
+    Word(&'a str),
+    Space(&'a str),
+}
+
+impl<'a> Token<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Token::Tag(text) | Token::Element(text) | Token::Annotation(text) | Token::Word(text) | Token::Space(text) => text,
+        }
+    }
+
+    /// Words and existing spans, the tokens that get made clickable.
+    pub fn is_word(&self) -> bool {
+        matches!(self, Token::Word(_) | Token::Element(_))
+    }
+}
+
+/// Splits the contents of a paragraph into tokens. Concatenating the tokens
+/// gives back the input exactly; unterminated markup at the end becomes a
+/// single tag.
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < text.len() {
+        let rest = &text[index..];
+        let c = rest.chars().next().unwrap();
+        let length = if c == '<' {
+            if let Some(length) = is_tag_named(rest, "span").then(|| find_element_end(rest, "span")).flatten() {
+                tokens.push(Token::Element(&rest[..length]));
+                length
+            } else if let Some(length) = ["rt", "rp"].into_iter()
+                .find(|name| is_tag_named(rest, name))
+                .and_then(|name| find_element_end(rest, name))
+            {
+                tokens.push(Token::Annotation(&rest[..length]));
+                length
+            } else {
+                let length = rest.find('>').map_or(rest.len(), |i| i + 1);
+                tokens.push(Token::Tag(&rest[..length]));
+                length
+            }
+        } else if let Some(length) = space_length(rest) {
+            tokens.push(Token::Space(&rest[..length]));
+            length
+        } else {
+            let length = rest.char_indices()
+                .find(|&(i, c)| c == '<' || space_length(&rest[i..]).is_some())
+                .map_or(rest.len(), |(i, _)| i);
+            tokens.push(Token::Word(&rest[..length]));
+            length
+        };
+        index += length;
+    }
+
+    tokens
+}
+
+/// Length of the whitespace `text` starts with, if it does. References to
+/// whitespace like `&nbsp;` count as whitespace too, so they separate words
+/// and are kept as they are.
+fn space_length(text: &str) -> Option<usize> {
+    let mut length = 0;
+    while let Some(c) = text[length..].chars().next() {
+        if c.is_whitespace() {
+            length += c.len_utf8();
+        } else if let Some((_, entity_length)) = entity_at(&text[length..]).filter(|(decoded, _)| decoded.is_whitespace()) {
+            length += entity_length;
+        } else {
+            break;
+        }
+    }
+    (length > 0).then_some(length)
+}
+
+/// Returns true if `markup` starts with an opening tag called `name`, so that
+/// `<p` does not also match `<pre>` or `<param>`.
+pub fn is_tag_named(markup: &str, name: &str) -> bool {
+    markup.strip_prefix('<')
+        .and_then(|rest| rest.strip_prefix(name))
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|c| c == '>' || c == '/' || c.is_whitespace())
+}
+
+/// Length of the element `name` that `markup` starts with, up to and
+/// including its matching closing tag. Nested elements of the same name are
+/// taken into account.
+pub fn find_element_end(markup: &str, name: &str) -> Option<usize> {
+    let closing = format!("</{}>", name);
+    let mut depth = 0;
+    let mut index = 0;
+    while let Some(found) = markup[index..].find('<').map(|i| i + index) {
+        let tag_end = markup[found..].find('>').map(|i| i + found + 1)?;
+        let tag = &markup[found..tag_end];
+        if tag == closing {
+            depth -= 1;
+        } else if is_tag_named(tag, name) && !tag.ends_with("/>") {
+            depth += 1;
+        }
+        if depth == 0 {
+            return Some(tag_end);
+        }
+        index = tag_end;
+    }
+    None
+}
+
+/// Punctuation that can come before or after a word without being part of it.
+pub const PUNCTUATION: &[char] = &[
+    '.', ',', ';', ':', '!', '?', '"', '\'', '(', ')', '[', ']', '«', '»', '“', '”', '„', '‘', '’',
+    '—', '–', '-', '…', '¿', '¡', '،', '؛', '؟', '।', '॥', '·', '\u{37e}', '\u{387}',
+];
+
+/// `word` without the punctuation around it.
+pub fn trim_punctuation(word: &str) -> &str {
+    word.trim_matches(PUNCTUATION)
+}
+
+/// Like [`trim_punctuation`] for a word as written in markup, where the `;`
+/// that ends a character reference is not punctuation.
+pub fn trim_punctuation_in_markup(word: &str) -> &str {
+    let trimmed = trim_punctuation(word);
+    let start = word.len() - word.trim_start_matches(PUNCTUATION).len();
+    let end = start + trimmed.len();
+    if word[end..].starts_with(';') {
+        let reference = trimmed.rfind('&').map(|amp| start + amp);
+        if reference.is_some_and(|amp| entity_at(&word[amp..]).is_some_and(|(_, length)| amp + length == end + 1)) {
+            return &word[start..end + 1];
+        }
+    }
+    trimmed
+}
+
+/// The form a word is counted under: without the punctuation around it and
+/// in lower case.
+pub fn normalize_word(word: &str) -> String {
+    trim_punctuation(word.trim()).to_lowercase()
+}
+
+/// What a run of text between spaces is, as far as looking it up goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordClass {
+    Word,
+    /// Starts with a capital letter, so it may be a name
+    Capitalized,
+    /// Years, page numbers, times, prices and ordinals like `1st`
+    Number,
+    /// Web and email addresses
+    Url,
+    /// `XIV`, but not the pronoun `I`, when it stands where a numeral would
+    RomanNumeral,
+    /// Punctuation and signs on their own, like `&` or `—`
+    Symbol,
+}
+
+impl WordClass {
+    /// Numbers, addresses, numerals and symbols are never worth looking up.
+    pub fn is_lookup(self) -> bool {
+        matches!(self, WordClass::Word | WordClass::Capitalized)
+    }
+}
+
+/// Words a Roman numeral follows when it numbers a chapter, a part or a
+/// century, in lower case.
+const NUMBERED: [&str; 39] = [
+    "chapter", "part", "book", "volume", "vol", "act", "scene", "canto", "section", "appendix",
+    "capítulo", "capitulo", "parte", "libro", "tomo", "acto", "escena", "siglo",
+    "chapitre", "partie", "livre", "tome", "acte", "scène", "siècle",
+    "kapitel", "teil", "buch", "band", "akt", "szene", "jahrhundert",
+    "capitolo", "secolo",
+    "глава", "часть", "том", "книга", "век",
+];
+
+/// Classifies the text content of a word token on its own, as when it is the
+/// whole text of a heading. See [`classify_in_context`] for words in running
+/// text.
+pub fn classify(word: &str) -> WordClass {
+    classify_in_context(word, None, true)
+}
+
+/// Classifies the text content of a word token, given the word before it and
+/// whether it is the only word of its paragraph. Words like `MIX` or `DIV`
+/// spell numerals too, so a word is only taken for a Roman numeral when it
+/// stands alone or follows a word like "Chapter" or "siglo".
+pub fn classify_in_context(word: &str, previous: Option<&str>, alone: bool) -> WordClass {
+    let word = word.trim();
+    let lower = word.to_ascii_lowercase();
+    if ["http://", "https://", "ftp://", "mailto:", "www."].iter().any(|prefix| lower.starts_with(prefix)) {
+        return WordClass::Url;
+    }
+    let core = trim_punctuation(word);
+    if core.contains('@') && core.rsplit('@').next().is_some_and(|domain| domain.contains('.')) {
+        return WordClass::Url;
+    }
+    if !core.chars().any(char::is_alphanumeric) {
+        return WordClass::Symbol;
+    }
+    if is_number(core) {
+        return WordClass::Number;
+    }
+    let numbered = previous.is_some_and(|previous| NUMBERED.contains(&normalize_word(previous).as_str()));
+    if (alone || numbered) && is_roman_numeral(core) {
+        return WordClass::RomanNumeral;
+    }
+    match core.chars().find(|c| c.is_alphabetic()) {
+        Some(first) if first.is_uppercase() && core.starts_with(first) => WordClass::Capitalized,
+        _ => WordClass::Word,
+    }
+}
+
+/// True for languages that capitalize every noun, such as German, where a
+/// capital letter says nothing about a word being a name.
+pub fn capitalizes_nouns(language: &str) -> bool {
+    let primary = language.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+    matches!(primary.as_str(), "de" | "lb" | "gsw" | "bar" | "nds")
+}
+
+/// Digits with separators, signs and units, optionally followed by a short
+/// suffix like `st`, `º` or `e`.
+fn is_number(word: &str) -> bool {
+    let digits_end = word.find(|c: char| !(c.is_numeric() || "+-−.,:/%°$€£¥'’".contains(c))).unwrap_or(word.len());
+    let suffix = &word[digits_end..];
+    word[..digits_end].chars().any(char::is_numeric)
+        && suffix.chars().count() <= 3
+        && suffix.chars().all(|c| c.is_alphabetic() || c == 'º' || c == 'ª')
+}
+
+fn is_roman_numeral(word: &str) -> bool {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "M"), (900, "CM"), (500, "D"), (400, "CD"), (100, "C"), (90, "XC"),
+        (50, "L"), (40, "XL"), (10, "X"), (9, "IX"), (5, "V"), (4, "IV"), (1, "I"),
+    ];
+    if word.is_empty() || word == "I" || !word.chars().all(|c| "IVXLCDM".contains(c)) {
+        return false;
+    }
+    // Parse leniently, then only accept the canonical spelling of the value
+    let values: Vec<u32> = word.chars()
+        .map(|c| NUMERALS.iter().find(|(_, numeral)| numeral.starts_with(c) && numeral.len() == 1).unwrap().0)
+        .collect();
+    let mut value = 0;
+    for (i, &digit) in values.iter().enumerate() {
+        if values.get(i + 1).is_some_and(|&next| next > digit) {
+            value -= digit as i64;
+        } else {
+            value += digit as i64;
+        }
+    }
+    if value <= 0 {
+        return false;
+    }
+    let mut canonical = String::new();
+    let mut rest = value as u32;
+    for (amount, numeral) in NUMERALS {
+        while rest >= amount {
+            canonical.push_str(numeral);
+            rest -= amount;
+        }
+    }
+    canonical == word
+}
+
+/// Value of the attribute `name` of an opening tag, as written.
+pub fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut from = 0;
+    while let Some(found) = tag[from..].find(name).map(|i| i + from) {
+        from = found + name.len();
+        let preceded_by_space = tag[..found].ends_with(char::is_whitespace);
+        let Some(rest) = tag[from..].strip_prefix('=') else {
+            continue;
+        };
+        let Some(quote) = rest.chars().next().filter(|&c| c == '"' || c == '\'') else {
+            continue;
+        };
+        if preceded_by_space {
+            return rest[1..].find(quote).map(|end| &rest[1..end + 1]);
+        }
+    }
+    None
+}
+
+/// Name of the element a tag opens or closes, and whether it closes it.
+pub fn tag_name(tag: &str) -> Option<(&str, bool)> {
+    let rest = tag.strip_prefix('<')?;
+    let (rest, closing) = match rest.strip_prefix('/') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(rest.len());
+    (end > 0).then(|| (&rest[..end], closing))
+}
+
+/// Ranges of tokens that make up each sentence, from its first word to its
+/// last. A sentence ends at a word ending in terminal punctuation, possibly
+/// followed by closing quotes or brackets.
+pub fn sentences(tokens: &[Token]) -> Vec<Range<usize>> {
+    let mut sentences = Vec::new();
+    let mut start = None;
+    let mut last_word = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        if !token.is_word() {
+            continue;
+        }
+        start.get_or_insert(i);
+        last_word = i;
+        if ends_sentence(&text_content(token.as_str())) {
+            sentences.push(start.take().unwrap()..i + 1);
+        }
+    }
+    if let Some(start) = start {
+        sentences.push(start..last_word + 1);
+    }
+
+    sentences
+}
+
+fn ends_sentence(word: &str) -> bool {
+    word.trim_end_matches(['"', '\'', '”', '’', '»', ')', ']'])
+        .ends_with(['.', '!', '?', '…', '。', '！', '？'])
+}
+
+/// True if every tag opened in `tokens` is also closed there, so that the
+/// tokens can be wrapped in an element.
+pub fn is_balanced(tokens: &[Token]) -> bool {
+    let mut depth = 0usize;
+    for token in tokens {
+        if let Token::Tag(tag) = token {
+            if tag.starts_with("</") {
+                match depth.checked_sub(1) {
+                    Some(d) => depth = d,
+                    None => return false,
+                }
+            } else if !tag.ends_with("/>") && !tag.starts_with("<!") && !tag.starts_with("<?") {
+                depth += 1;
+            }
+        }
+    }
+    depth == 0
+}
+
+/// Text of an HTML fragment with tags removed and entities decoded.
+pub fn text_content(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        rest = rest[start..].find('>').map_or("", |end| &rest[start + end + 1..]);
+    }
+    text.push_str(&decode_entities(rest));
+    text
+}
+
+/// Decodes the XML entities, `&nbsp;` and numeric character references.
+/// Anything else that looks like an entity is left as it is.
+pub fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match entity_at(rest) {
+            Some((c, length)) => {
+                decoded.push(c);
+                rest = &rest[length..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/// The character an entity or character reference at the start of `text`
+/// stands for, and the length of the reference.
+fn entity_at(text: &str) -> Option<(char, usize)> {
+    if !text.starts_with('&') {
+        return None;
+    }
+    // Entity names are short, so don't go looking for a far away semicolon
+    let end = text.char_indices().take(32).find(|&(_, c)| c == ';').map(|(i, _)| i)?;
+    Some((decode_entity(&text[1..end])?, end + 1))
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_words() {
+        let cases = [
+            ("casa", WordClass::Word),
+            ("«¿Dónde?»", WordClass::Capitalized),
+            ("Madrid,", WordClass::Capitalized),
+            ("1984", WordClass::Number),
+            ("12:30", WordClass::Number),
+            ("3,50€", WordClass::Number),
+            ("1st", WordClass::Number),
+            ("2º", WordClass::Number),
+            ("https://example.com/a", WordClass::Url),
+            ("www.example.com", WordClass::Url),
+            ("(ana@example.com)", WordClass::Url),
+            ("—", WordClass::Symbol),
+            ("&", WordClass::Symbol),
+            ("XIV", WordClass::RomanNumeral),
+            ("I", WordClass::Capitalized),
+            // Not the canonical spelling of any number
+            ("IIII", WordClass::Capitalized),
+            ("VX", WordClass::Capitalized),
+        ];
+        for (word, class) in cases {
+            assert_eq!(classify(word), class, "{}", word);
+        }
+    }
+
+    #[test]
+    fn roman_numerals_need_context() {
+        assert_eq!(classify_in_context("XIV.", Some("Chapter"), false), WordClass::RomanNumeral);
+        assert_eq!(classify_in_context("XIX", Some("siglo"), false), WordClass::RomanNumeral);
+        assert_eq!(classify_in_context("IV", None, true), WordClass::RomanNumeral);
+        for (word, previous) in [("MIX", Some("the")), ("DIV", None), ("CD", Some("a")), ("MIL", Some("EL")), ("LID", Some("Chapters"))] {
+            assert_eq!(classify_in_context(word, previous, false), WordClass::Capitalized, "{}", word);
+        }
+        assert!(!WordClass::RomanNumeral.is_lookup());
+        assert!(WordClass::Capitalized.is_lookup());
+    }
+}
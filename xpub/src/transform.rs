@@ -0,0 +1,48 @@
+use crate::epub::EpubDocument;
+use crate::error::{Error, ParseError};
+use crate::package::ManifestItem;
+
+/// A content document on its way through the pipeline.
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// Name of the entry in the archive
+    pub name: String,
+    pub item: ManifestItem,
+    /// Position in the reading order, `None` for documents outside the spine
+    pub spine_index: Option<usize>,
+    pub content: String,
+}
+
+impl Document {
+    /// Attaches the document's entry name to a parser error.
+    pub fn parse_error(&self, source: ParseError) -> Error {
+        Error::Parse { entry: self.name.clone(), source }
+    }
+}
+
+/// A pass over the book.
+///
+/// `begin` runs once before any document is rewritten and may read the whole
+/// book, `transform` runs for every content document in reading order, and
+/// `finish` runs once at the end and may add resources or edit the package.
+pub trait Transform {
+    fn begin(&mut self, _book: &mut EpubDocument) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn transform(&mut self, book: &EpubDocument, document: &mut Document) -> Result<(), Error>;
+
+    fn finish(&mut self, _book: &mut EpubDocument) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Closures can be used as passes that only look at one document at a time.
+impl<F> Transform for F
+where
+    F: FnMut(&EpubDocument, &mut Document) -> Result<(), Error>,
+{
+    fn transform(&mut self, book: &EpubDocument, document: &mut Document) -> Result<(), Error> {
+        self(book, document)
+    }
+}
@@ -0,0 +1,295 @@
+//! Romanization of Russian, Greek, Arabic, Hebrew, Korean and Hindi words for
+//! readers still learning the script.
+//!
+//! Every scheme is a table from letters, or short letter sequences, to Latin.
+//! Korean syllables are split into their jamo first, and Devanagari
+//! consonants get their inherent vowel unless a vowel sign or virama follows.
+
+use std::collections::HashMap;
+
+/// Letters and sequences of one script, with their romanizations.
+type Table = &'static [(&'static str, &'static str)];
+
+const RUSSIAN: Table = &[
+    ("а", "a"), ("б", "b"), ("в", "v"), ("г", "g"), ("д", "d"), ("е", "e"), ("ё", "yo"),
+    ("ж", "zh"), ("з", "z"), ("и", "i"), ("й", "y"), ("к", "k"), ("л", "l"), ("м", "m"),
+    ("н", "n"), ("о", "o"), ("п", "p"), ("р", "r"), ("с", "s"), ("т", "t"), ("у", "u"),
+    ("ф", "f"), ("х", "kh"), ("ц", "ts"), ("ч", "ch"), ("ш", "sh"), ("щ", "shch"), ("ъ", ""),
+    ("ы", "y"), ("ь", "'"), ("э", "e"), ("ю", "yu"), ("я", "ya"),
+];
+
+const GREEK: Table = &[
+    ("αι", "ai"), ("ει", "ei"), ("οι", "oi"), ("ου", "ou"), ("αυ", "av"), ("ευ", "ev"),
+    ("γγ", "ng"), ("γκ", "gk"), ("γξ", "nx"), ("γχ", "nch"),
+    ("α", "a"), ("ά", "a"), ("β", "v"), ("γ", "g"), ("δ", "d"), ("ε", "e"), ("έ", "e"),
+    ("ζ", "z"), ("η", "i"), ("ή", "i"), ("θ", "th"), ("ι", "i"), ("ί", "i"), ("ϊ", "i"),
+    ("ΐ", "i"), ("κ", "k"), ("λ", "l"), ("μ", "m"), ("ν", "n"), ("ξ", "x"), ("ο", "o"),
+    ("ό", "o"), ("π", "p"), ("ρ", "r"), ("σ", "s"), ("ς", "s"), ("τ", "t"), ("υ", "y"),
+    ("ύ", "y"), ("ϋ", "y"), ("ΰ", "y"), ("φ", "f"), ("χ", "ch"), ("ψ", "ps"), ("ω", "o"),
+    ("ώ", "o"),
+];
+
+const ARABIC: Table = &[
+    // The alif that carries tanwin is not pronounced
+    ("\u{64b}ا", "an"), ("ا\u{64b}", "an"),
+    ("ء", "'"), ("آ", "ā"), ("أ", "a"), ("ؤ", "'"), ("إ", "i"), ("ئ", "'"), ("ا", "ā"),
+    ("ب", "b"), ("ة", "h"), ("ت", "t"), ("ث", "th"), ("ج", "j"), ("ح", "ḥ"), ("خ", "kh"),
+    ("د", "d"), ("ذ", "dh"), ("ر", "r"), ("ز", "z"), ("س", "s"), ("ش", "sh"), ("ص", "ṣ"),
+    ("ض", "ḍ"), ("ط", "ṭ"), ("ظ", "ẓ"), ("ع", "ʿ"), ("غ", "gh"), ("ف", "f"), ("ق", "q"),
+    ("ك", "k"), ("ل", "l"), ("م", "m"), ("ن", "n"), ("ه", "h"), ("و", "w"), ("ى", "ā"),
+    ("ي", "y"), ("ـ", ""),
+    // Short vowels and other marks
+    ("\u{64b}", "an"), ("\u{64c}", "un"), ("\u{64d}", "in"), ("\u{64e}", "a"), ("\u{64f}", "u"),
+    ("\u{650}", "i"), ("\u{651}", ""), ("\u{652}", ""), ("\u{670}", "ā"),
+];
+
+const HEBREW: Table = &[
+    ("וֹ", "o"), ("וּ", "u"), ("בּ", "b"), ("כּ", "k"), ("ךּ", "k"), ("פּ", "p"), ("שׁ", "sh"),
+    ("שׂ", "s"),
+    ("א", "'"), ("ב", "v"), ("ג", "g"), ("ד", "d"), ("ה", "h"), ("ו", "v"), ("ז", "z"),
+    ("ח", "ch"), ("ט", "t"), ("י", "y"), ("כ", "kh"), ("ך", "kh"), ("ל", "l"), ("מ", "m"),
+    ("ם", "m"), ("נ", "n"), ("ן", "n"), ("ס", "s"), ("ע", "'"), ("פ", "f"), ("ף", "f"),
+    ("צ", "ts"), ("ץ", "ts"), ("ק", "k"), ("ר", "r"), ("ש", "sh"), ("ת", "t"),
+    // Niqqud
+    ("\u{5b0}", "e"), ("\u{5b1}", "e"), ("\u{5b2}", "a"), ("\u{5b3}", "o"), ("\u{5b4}", "i"),
+    ("\u{5b5}", "e"), ("\u{5b6}", "e"), ("\u{5b7}", "a"), ("\u{5b8}", "a"), ("\u{5b9}", "o"),
+    ("\u{5bb}", "u"), ("\u{5bc}", ""), ("\u{5c1}", ""), ("\u{5c2}", ""),
+];
+
+/// Revised Romanization of the initial, medial and final jamo of a syllable.
+const HANGUL_INITIALS: [&str; 19] = [
+    "g", "kk", "n", "d", "tt", "r", "m", "b", "pp", "s", "ss", "", "j", "jj", "ch", "k", "t", "p", "h",
+];
+const HANGUL_MEDIALS: [&str; 21] = [
+    "a", "ae", "ya", "yae", "eo", "e", "yeo", "ye", "o", "wa", "wae", "oe", "yo", "u", "wo", "we",
+    "wi", "yu", "eu", "ui", "i",
+];
+const HANGUL_FINALS: [&str; 28] = [
+    "", "k", "k", "k", "n", "n", "n", "t", "l", "k", "m", "l", "l", "l", "p", "l", "m", "p", "p",
+    "t", "t", "ng", "t", "t", "k", "t", "p", "t",
+];
+
+const DEVANAGARI_CONSONANTS: Table = &[
+    ("क़", "q"), ("ख़", "ḵh"), ("ग़", "ġ"), ("ज़", "z"), ("ड़", "ṛ"), ("ढ़", "ṛh"), ("फ़", "f"),
+    ("क", "k"), ("ख", "kh"), ("ग", "g"), ("घ", "gh"), ("ङ", "ṅ"), ("च", "c"), ("छ", "ch"),
+    ("ज", "j"), ("झ", "jh"), ("ञ", "ñ"), ("ट", "ṭ"), ("ठ", "ṭh"), ("ड", "ḍ"), ("ढ", "ḍh"),
+    ("ण", "ṇ"), ("त", "t"), ("थ", "th"), ("द", "d"), ("ध", "dh"), ("न", "n"), ("प", "p"),
+    ("फ", "ph"), ("ब", "b"), ("भ", "bh"), ("म", "m"), ("य", "y"), ("र", "r"), ("ल", "l"),
+    ("व", "v"), ("श", "ś"), ("ष", "ṣ"), ("स", "s"), ("ह", "h"),
+];
+
+/// Vowel signs and the virama, which replace a consonant's inherent vowel.
+const DEVANAGARI_SIGNS: Table = &[
+    ("ा", "ā"), ("ि", "i"), ("ी", "ī"), ("ु", "u"), ("ू", "ū"), ("ृ", "ṛ"), ("े", "e"),
+    ("ै", "ai"), ("ो", "o"), ("ौ", "au"), ("ॉ", "ŏ"), ("्", ""),
+];
+
+const DEVANAGARI_OTHERS: Table = &[
+    ("अ", "a"), ("आ", "ā"), ("इ", "i"), ("ई", "ī"), ("उ", "u"), ("ऊ", "ū"), ("ऋ", "ṛ"),
+    ("ए", "e"), ("ऐ", "ai"), ("ओ", "o"), ("औ", "au"), ("ऑ", "ŏ"), ("ं", "ṃ"), ("ँ", "ṁ"),
+    ("ः", "ḥ"), ("ऽ", "'"), ("०", "0"), ("१", "1"), ("२", "2"), ("३", "3"), ("४", "4"),
+    ("५", "5"), ("६", "6"), ("७", "7"), ("८", "8"), ("९", "9"),
+];
+
+/// Letters and sequences to their romanizations, looked up longest first.
+#[derive(Debug, Clone)]
+struct Letters {
+    map: HashMap<&'static str, &'static str>,
+    /// Longest key, in characters
+    longest: usize,
+}
+
+impl Letters {
+    fn new(tables: &[Table]) -> Letters {
+        let map: HashMap<_, _> = tables.iter().flat_map(|table| table.iter().copied()).collect();
+        let longest = map.keys().map(|key| key.chars().count()).max().unwrap_or(1);
+        Letters { map, longest }
+    }
+
+    /// The romanization and length in bytes of the longest entry `text` starts with.
+    fn longest_match(&self, text: &str) -> Option<(&'static str, usize)> {
+        let ends: Vec<usize> = text.char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .take(self.longest)
+            .collect();
+        ends.into_iter().rev().find_map(|end| Some((*self.map.get(&text[..end])?, end)))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Scheme {
+    /// One letter or sequence at a time, keeping the case of the original
+    Alphabet(Letters),
+    Hangul,
+    Devanagari { consonants: Letters, signs: Letters, others: Letters },
+}
+
+/// Turns words of one language into Latin letters.
+#[derive(Debug, Clone)]
+pub struct Transliterator {
+    scheme: Scheme,
+}
+
+impl Transliterator {
+    /// The scheme for a language tag like `ru` or `el-GR`, if there is one.
+    pub fn for_language(language: &str) -> Option<Transliterator> {
+        let primary = language.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+        let scheme = match primary.as_str() {
+            "ru" => Scheme::Alphabet(Letters::new(&[RUSSIAN])),
+            "el" => Scheme::Alphabet(Letters::new(&[GREEK])),
+            "ar" => Scheme::Alphabet(Letters::new(&[ARABIC])),
+            "he" | "iw" => Scheme::Alphabet(Letters::new(&[HEBREW])),
+            "ko" => Scheme::Hangul,
+            "hi" => Scheme::Devanagari {
+                consonants: Letters::new(&[DEVANAGARI_CONSONANTS]),
+                signs: Letters::new(&[DEVANAGARI_SIGNS]),
+                others: Letters::new(&[DEVANAGARI_OTHERS]),
+            },
+            _ => return None,
+        };
+        Some(Transliterator { scheme })
+    }
+
+    /// Romanizes `text`. Characters the scheme does not know are kept as they are.
+    pub fn transliterate(&self, text: &str) -> String {
+        match &self.scheme {
+            Scheme::Alphabet(letters) => transliterate_alphabet(letters, text),
+            Scheme::Hangul => transliterate_hangul(text),
+            Scheme::Devanagari { consonants, signs, others } => transliterate_devanagari(consonants, signs, others, text),
+        }
+    }
+}
+
+fn transliterate_alphabet(letters: &Letters, text: &str) -> String {
+    // The text is matched lowercased. Lowercasing can change a character's
+    // length, so each offset into `lowercase` that starts a character of
+    // `text` remembers that character and the length of its lowercase form.
+    let mut lowercase = String::with_capacity(text.len());
+    let mut originals: Vec<Option<(char, usize)>> = Vec::with_capacity(text.len() + 1);
+    for c in text.chars() {
+        let start = lowercase.len();
+        lowercase.extend(c.to_lowercase());
+        originals.push(Some((c, lowercase.len() - start)));
+        originals.resize(lowercase.len(), None);
+    }
+    let mut output = String::with_capacity(text.len() * 2);
+    let mut index = 0;
+
+    while index < lowercase.len() {
+        let rest = &lowercase[index..];
+        match letters.longest_match(rest) {
+            Some((latin, length)) => {
+                let uppercase = originals[index].is_some_and(|(c, _)| c.is_uppercase());
+                let mut chars = latin.chars();
+                if let (true, Some(first)) = (uppercase, chars.next()) {
+                    output.extend(first.to_uppercase());
+                    output.push_str(chars.as_str());
+                } else {
+                    output.push_str(latin);
+                }
+                index += length;
+            }
+            None => match originals[index] {
+                Some((c, length)) => {
+                    output.push(c);
+                    index += length;
+                }
+                None => {
+                    let c = rest.chars().next().unwrap();
+                    output.push(c);
+                    index += c.len_utf8();
+                }
+            },
+        }
+    }
+
+    output
+}
+
+fn transliterate_hangul(text: &str) -> String {
+    let mut output = String::with_capacity(text.len() * 2);
+    for c in text.chars() {
+        match (c as u32).checked_sub(0xAC00).filter(|&index| index < 11172) {
+            Some(index) => {
+                let index = index as usize;
+                output.push_str(HANGUL_INITIALS[index / 588]);
+                output.push_str(HANGUL_MEDIALS[index % 588 / 28]);
+                output.push_str(HANGUL_FINALS[index % 28]);
+            }
+            None => output.push(c),
+        }
+    }
+    output
+}
+
+fn transliterate_devanagari(consonants: &Letters, signs: &Letters, others: &Letters, text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut index = 0;
+    // Where the inherent vowel of the last consonant went, if it was added
+    let mut inherent_vowel: Option<usize> = None;
+
+    while index < text.len() {
+        let rest = &text[index..];
+        if let Some((latin, length)) = consonants.longest_match(rest) {
+            output.push_str(latin);
+            index += length;
+            match signs.longest_match(&text[index..]) {
+                Some((sign, length)) => {
+                    output.push_str(sign);
+                    index += length;
+                    inherent_vowel = None;
+                }
+                None => {
+                    inherent_vowel = Some(output.len());
+                    output.push('a');
+                }
+            }
+        } else if let Some((latin, length)) = others.longest_match(rest) {
+            output.push_str(latin);
+            index += length;
+            inherent_vowel = None;
+        } else {
+            let c = rest.chars().next().unwrap();
+            // Hindi drops the inherent vowel at the end of a word
+            if let Some(position) = inherent_vowel.take().filter(|&position| position > 1 && !c.is_alphanumeric()) {
+                output.remove(position);
+            }
+            output.push(c);
+            index += c.len_utf8();
+        }
+    }
+    if let Some(position) = inherent_vowel.filter(|&position| position > 1) {
+        output.remove(position);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transliterate(language: &str, text: &str) -> String {
+        Transliterator::for_language(language).unwrap().transliterate(text)
+    }
+
+    #[test]
+    fn table() {
+        for (language, text, expected) in [
+            ("ru", "Москва", "Moskva"),
+            ("ru", "ЩИ щи", "ShchI shchi"),
+            ("el", "Αθήνα", "Athina"),
+            ("ko", "한국어", "hangukeo"),
+            ("hi", "नमस्ते", "namaste"),
+            ("he", "שלום", "shlvm"),
+            // Letters whose lowercase form has another length
+            ("ru", "İẞ…", "İẞ…"),
+            ("ru", "İМосква ẞ", "İMoskva ẞ"),
+            ("el", "ẞΑθήνα", "ẞAthina"),
+        ] {
+            assert_eq!(transliterate(language, text), expected, "{} {}", language, text);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Speech synthesis through a local command such as espeak-ng or piper.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::audio::TempFile;
+use crate::error::Error;
+
+/// Turns text into speech.
+pub trait SpeechSynthesizer {
+    /// Returns the speech for `text` as a WAV file.
+    fn synthesize(&mut self, text: &str, language: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Runs a command line for every piece of text.
+///
+/// The template may use `{text}`, `{lang}` and `{output}`. Without `{text}`
+/// the text is written to the command's standard input, and without
+/// `{output}` the WAV is read from its standard output. For example:
+///
+/// - `espeak-ng -v {lang} -w {output} {text}`
+/// - `piper --model es_ES-davefx-medium.onnx --output_file {output}`
+pub struct CommandSynthesizer {
+    template: Vec<String>,
+}
+
+impl CommandSynthesizer {
+    pub fn new(template: &str) -> Result<Self, Error> {
+        let template = split_command_line(template);
+        if template.is_empty() {
+            return Err(Error::Command { command: String::new(), message: "empty TTS command".to_string() });
+        }
+        Ok(CommandSynthesizer { template })
+    }
+
+    /// The command line for one piece of text, with the placeholders filled in.
+    fn arguments(&self, text: &str, language: &str, output: &str) -> Vec<String> {
+        self.template.iter()
+            .map(|arg| arg.replace("{lang}", language).replace("{output}", output).replace("{text}", text))
+            .collect()
+    }
+}
+
+impl SpeechSynthesizer for CommandSynthesizer {
+    fn synthesize(&mut self, text: &str, language: &str) -> Result<Vec<u8>, Error> {
+        let output = TempFile::new("wav");
+        let output_path = output.path.to_string_lossy();
+        let uses_text = self.template.iter().any(|arg| arg.contains("{text}"));
+        let uses_output = self.template.iter().any(|arg| arg.contains("{output}"));
+
+        let args = self.arguments(text, language, &output_path);
+        let command_error = |message: String| Error::Command { command: self.template[0].clone(), message };
+
+        let mut child = Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(if uses_text { Stdio::null() } else { Stdio::piped() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| command_error(err.to_string()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let result = child.wait_with_output()?;
+        if !result.status.success() {
+            return Err(command_error(String::from_utf8_lossy(&result.stderr).trim().to_string()));
+        }
+
+        if uses_output {
+            Ok(std::fs::read(&output.path)?)
+        } else {
+            Ok(result.stdout)
+        }
+    }
+}
+
+/// Splits a command line on whitespace, keeping quoted parts together.
+fn split_command_line(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    for c in command.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            None if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_command_lines() {
+        assert_eq!(split_command_line("espeak-ng  -v {lang}\t-w {output} {text}"), ["espeak-ng", "-v", "{lang}", "-w", "{output}", "{text}"]);
+        assert_eq!(split_command_line(r#"piper --model "my voice.onnx" --speaker '' -x"#), ["piper", "--model", "my voice.onnx", "--speaker", "", "-x"]);
+        assert_eq!(split_command_line("say \"unclosed quote"), ["say", "unclosed quote"]);
+        assert!(split_command_line("   ").is_empty());
+        assert!(CommandSynthesizer::new(" ").is_err());
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let synthesizer = CommandSynthesizer::new("tts --lang={lang} -o {output} \"{text}\"").unwrap();
+        // The text is one argument however it is spaced, and never read as a placeholder
+        assert_eq!(
+            synthesizer.arguments("Hola, {lang} mundo", "es", "/tmp/a.wav"),
+            ["tts", "--lang=es", "-o", "/tmp/a.wav", "Hola, {lang} mundo"],
+        );
+    }
+}
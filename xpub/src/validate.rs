@@ -0,0 +1,442 @@
+//! Structural checks on a finished EPUB.
+//!
+//! These are not a full epubcheck, just the problems xpub itself could cause:
+//! a misplaced or compressed mimetype, a missing container or package
+//! document, manifest and spine entries that point nowhere, XHTML that is no
+//! longer well-formed, links to files or ids that do not exist, and
+//! obfuscated fonts that no longer match the book's identifier.
+
+use flate2::read::DeflateDecoder;
+use quick_xml::escape::resolve_xml_entity;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::encoding;
+use crate::encryption::{self, ObfuscationKeys, ENCRYPTION_PATH};
+use crate::error::Error;
+use crate::package::{self, Package};
+
+/// A problem found in the book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// Archive entry the problem is in, if it is tied to one
+    pub entry: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.entry {
+            Some(entry) => write!(f, "{}: {}", entry, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Links and ids found in one content document.
+#[derive(Default)]
+struct References {
+    ids: HashSet<String>,
+    /// (href as written, byte offset)
+    links: Vec<(String, usize)>,
+}
+
+/// Checks the EPUB at `path` and returns every problem found. Errors are only
+/// returned if the file cannot be opened as a zip archive at all.
+pub fn validate(path: impl AsRef<Path>) -> Result<Vec<Issue>, Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut issues = Vec::new();
+
+    check_mimetype(&mut archive, &mut issues)?;
+
+    let Some(opf_path) = find_package_document(&mut archive, &mut issues) else {
+        return Ok(issues);
+    };
+    let Some(opf_source) = read_text(&mut archive, &opf_path, &mut issues) else {
+        return Ok(issues);
+    };
+    let package = match Package::parse(&opf_source) {
+        Ok(package) => package,
+        Err(err) => {
+            issues.push(issue(&opf_path, err.to_string()));
+            return Ok(issues);
+        }
+    };
+
+    check_package(&archive, &opf_path, &package, &mut issues);
+    let encrypted = check_encryption(&mut archive, &package, &mut issues);
+
+    let mut references: HashMap<String, References> = HashMap::new();
+    for item in package.manifest.iter().filter(|item| item.media_type == "application/xhtml+xml") {
+        let name = package::resolve_href(&opf_path, &item.href);
+        if archive.index_for_name(&name).is_none() || encrypted.contains(&name) {
+            continue;
+        }
+        if let Some(content) = read_text(&mut archive, &name, &mut issues) {
+            match check_well_formed(&content) {
+                Ok(found) => {
+                    references.insert(name, found);
+                }
+                Err(message) => issues.push(issue(&name, message)),
+            }
+        }
+    }
+
+    check_links(&archive, &references, &mut issues);
+
+    Ok(issues)
+}
+
+fn issue(entry: &str, message: impl Into<String>) -> Issue {
+    Issue { entry: Some(entry.to_string()), message: message.into() }
+}
+
+/// Checks that everything `encryption.xml` lists is there and that
+/// obfuscated fonts can be read with the key from the book's identifier.
+/// Returns the entries that are really encrypted.
+fn check_encryption(archive: &mut ZipArchive<File>, package: &Package, issues: &mut Vec<Issue>) -> HashSet<String> {
+    let mut encrypted = HashSet::new();
+    if archive.index_for_name(ENCRYPTION_PATH).is_none() {
+        return encrypted;
+    }
+    let Some(xml) = read_text(archive, ENCRYPTION_PATH, issues) else {
+        return encrypted;
+    };
+    let entries = match encryption::parse(&xml) {
+        Ok(entries) => entries,
+        Err(err) => {
+            issues.push(issue(ENCRYPTION_PATH, err.to_string()));
+            return encrypted;
+        }
+    };
+
+    let keys = ObfuscationKeys::for_package(package);
+    for entry in entries {
+        let Ok(mut file) = archive.by_name(&entry.name) else {
+            issues.push(issue(ENCRYPTION_PATH, format!("lists {}, which is not in the archive", entry.name)));
+            continue;
+        };
+        if !entry.algorithm.is_obfuscation() {
+            encrypted.insert(entry.name);
+            continue;
+        }
+        let mut font = Vec::new();
+        if file.read_to_end(&mut font).is_err() {
+            continue;
+        }
+        let deobfuscated = keys.deobfuscate(&entry.algorithm, &mut font);
+        if entry.compressed {
+            // Fonts deflated before they were obfuscated only look like fonts once inflated
+            let mut head = Vec::new();
+            let _ = DeflateDecoder::new(font.as_slice()).take(4).read_to_end(&mut head);
+            font = head;
+        }
+        if !deobfuscated || !encryption::looks_like_font(&font) {
+            issues.push(issue(&entry.name, "obfuscated with a key that does not match the book's identifier"));
+        }
+    }
+    encrypted
+}
+
+fn check_mimetype(archive: &mut ZipArchive<File>, issues: &mut Vec<Issue>) -> Result<(), Error> {
+    if archive.index_for_name("mimetype").is_none() {
+        issues.push(issue("mimetype", "missing"));
+        return Ok(());
+    }
+
+    let mut file = archive.by_index(0)?;
+    if file.name() != "mimetype" {
+        issues.push(issue("mimetype", "is not the first entry in the archive"));
+        return Ok(());
+    }
+    if file.compression() != CompressionMethod::Stored {
+        issues.push(issue("mimetype", "is compressed, it must be stored"));
+    }
+    let mut contents = String::new();
+    if file.read_to_string(&mut contents).is_err() || contents != "application/epub+zip" {
+        issues.push(issue("mimetype", "does not contain application/epub+zip"));
+    }
+    Ok(())
+}
+
+fn find_package_document(archive: &mut ZipArchive<File>, issues: &mut Vec<Issue>) -> Option<String> {
+    const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+    if archive.index_for_name(CONTAINER_PATH).is_none() {
+        issues.push(issue(CONTAINER_PATH, "missing"));
+        return None;
+    }
+    let container = read_text(archive, CONTAINER_PATH, issues)?;
+    match package::parse_container(&container) {
+        Ok(Some(opf_path)) if archive.index_for_name(&opf_path).is_some() => Some(opf_path),
+        Ok(Some(opf_path)) => {
+            issues.push(issue(CONTAINER_PATH, format!("package document {} does not exist", opf_path)));
+            None
+        }
+        Ok(None) => {
+            issues.push(issue(CONTAINER_PATH, "no rootfile for the package document"));
+            None
+        }
+        Err(err) => {
+            issues.push(issue(CONTAINER_PATH, err.to_string()));
+            None
+        }
+    }
+}
+
+fn check_package(archive: &ZipArchive<File>, opf_path: &str, package: &Package, issues: &mut Vec<Issue>) {
+    let mut ids = HashSet::new();
+    let mut paths = HashSet::new();
+    for item in &package.manifest {
+        if !ids.insert(item.id.as_str()) {
+            issues.push(issue(opf_path, format!("manifest id {} is used more than once", item.id)));
+        }
+        let name = package::resolve_href(opf_path, &item.href);
+        if archive.index_for_name(&name).is_none() {
+            issues.push(issue(opf_path, format!("manifest item {} points at missing file {}", item.id, name)));
+        }
+        if !paths.insert(name.clone()) {
+            issues.push(issue(opf_path, format!("{} is listed in the manifest more than once", name)));
+        }
+    }
+
+    if package.spine.items.is_empty() {
+        issues.push(issue(opf_path, "spine is empty"));
+    }
+    for itemref in &package.spine.items {
+        match package.item(&itemref.idref) {
+            None => issues.push(issue(opf_path, format!("spine refers to unknown manifest id {}", itemref.idref))),
+            Some(item) if !item.is_content_document() => issues.push(issue(
+                opf_path,
+                format!("spine item {} is {}, not a content document", item.id, item.media_type),
+            )),
+            Some(_) => {}
+        }
+    }
+
+    if package.version.starts_with('3') && package.nav_item().is_none() {
+        issues.push(issue(opf_path, "EPUB 3 package has no navigation document"));
+    }
+}
+
+/// Parses a document as XML and collects its ids and internal links.
+fn check_well_formed(content: &str) -> Result<References, String> {
+    let mut reader = Reader::from_str(content);
+    let mut references = References::default();
+    let mut open: Vec<Vec<u8>> = Vec::new();
+    let mut roots = 0;
+    // Named entities like &nbsp; are only defined if there is a DTD
+    let mut has_doctype = false;
+
+    loop {
+        let position = reader.buffer_position() as usize;
+        let event = reader.read_event()
+            .map_err(|err| format!("byte {}: {}", reader.error_position(), err))?;
+        let element = match &event {
+            Event::Start(e) | Event::Empty(e) => e,
+            Event::End(_) => {
+                open.pop();
+                continue;
+            }
+            Event::DocType(_) => {
+                has_doctype = true;
+                continue;
+            }
+            Event::Text(e) => {
+                e.unescape_with(|entity| resolve_xml_entity(entity).or(has_doctype.then_some("")))
+                    .map_err(|err| format!("byte {}: {}", position, err))?;
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        if open.is_empty() {
+            roots += 1;
+        }
+        if let Some(id) = package::attribute(element, b"id") {
+            if !references.ids.insert(id.clone()) {
+                return Err(format!("byte {}: id {} is used more than once", position, id));
+            }
+        }
+        for name in [&b"href"[..], b"src"] {
+            if let Some(href) = package::attribute(element, name) {
+                references.links.push((href, position));
+            }
+        }
+        if let Event::Start(e) = &event {
+            open.push(e.name().as_ref().to_vec());
+        }
+    }
+
+    if let Some(name) = open.last() {
+        return Err(format!("<{}> is never closed", String::from_utf8_lossy(name)));
+    }
+    if roots != 1 {
+        return Err(format!("expected one root element, found {}", roots));
+    }
+    Ok(references)
+}
+
+fn check_links(archive: &ZipArchive<File>, references: &HashMap<String, References>, issues: &mut Vec<Issue>) {
+    let mut names: Vec<&String> = references.keys().collect();
+    names.sort();
+
+    for name in names {
+        for (href, position) in &references[name].links {
+            if is_external(href) {
+                continue;
+            }
+            let target = if href.starts_with('#') {
+                name.clone()
+            } else {
+                package::resolve_href(name, href)
+            };
+            if archive.index_for_name(&target).is_none() {
+                issues.push(issue(name, format!("byte {}: link to missing file {}", position, href)));
+                continue;
+            }
+            let Some(fragment) = href.split_once('#').map(|(_, fragment)| fragment) else {
+                continue;
+            };
+            if fragment.is_empty() {
+                continue;
+            }
+            if let Some(target_references) = references.get(&target) {
+                if !target_references.ids.contains(fragment) {
+                    issues.push(issue(name, format!("byte {}: link to missing id {}", position, href)));
+                }
+            }
+        }
+    }
+}
+
+/// True for hrefs with a scheme, like `https:` or `mailto:`, and for data URLs.
+fn is_external(href: &str) -> bool {
+    match href.find(':') {
+        Some(colon) => !href[..colon].contains(['/', '#', '?']),
+        None => false,
+    }
+}
+
+fn read_text(archive: &mut ZipArchive<File>, name: &str, issues: &mut Vec<Issue>) -> Option<String> {
+    let mut data = Vec::new();
+    let read = archive.by_name(name)
+        .map_err(|err| err.to_string())
+        .and_then(|mut file| file.read_to_end(&mut data).map_err(|err| err.to_string()));
+    if let Err(message) = read {
+        issues.push(issue(name, message));
+        return None;
+    }
+    match encoding::decode(&data) {
+        Some(text) => Some(text),
+        None => {
+            issues.push(issue(name, format!("not valid {}", encoding::detect(&data).name())));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn messages(entries: &[(String, String)]) -> Vec<String> {
+        let file = fixtures::archive(entries);
+        validate(&file.path).unwrap().iter().map(Issue::to_string).collect()
+    }
+
+    fn replace(entries: &mut [(String, String)], name: &str, from: &str, to: &str) {
+        let (_, contents) = entries.iter_mut().find(|(entry, _)| entry == name).unwrap();
+        assert!(contents.contains(from), "{} has no {}", name, from);
+        *contents = contents.replace(from, to);
+    }
+
+    #[test]
+    fn accepts_well_formed_books() {
+        assert!(messages(&fixtures::book_entries(&["<p>Uno</p>", "<p>Dos</p>"])).is_empty());
+    }
+
+    #[test]
+    fn checks_the_mimetype() {
+        let mut entries = fixtures::book_entries(&["<p>Uno</p>"]);
+        let file = fixtures::archive_with(&entries, |_| false);
+        let issues: Vec<String> = validate(&file.path).unwrap().iter().map(Issue::to_string).collect();
+        assert_eq!(issues, ["mimetype: is compressed, it must be stored"]);
+
+        entries.rotate_left(1);
+        assert_eq!(messages(&entries), ["mimetype: is not the first entry in the archive"]);
+
+        entries.pop();
+        entries.retain(|(name, _)| name != "mimetype");
+        assert_eq!(messages(&entries)[0], "mimetype: missing");
+    }
+
+    #[test]
+    fn needs_a_container() {
+        let mut entries = fixtures::book_entries(&["<p>Uno</p>"]);
+        entries.retain(|(name, _)| name != "META-INF/container.xml");
+        assert_eq!(messages(&entries), ["META-INF/container.xml: missing"]);
+    }
+
+    #[test]
+    fn finds_dangling_manifest_and_spine_entries() {
+        let mut entries = fixtures::book_entries(&["<p>Uno</p>"]);
+        replace(
+            &mut entries,
+            "OEBPS/content.opf",
+            "</manifest>",
+            "<item id=\"gone\" href=\"gone.xhtml\" media-type=\"application/xhtml+xml\"/></manifest>",
+        );
+        replace(&mut entries, "OEBPS/content.opf", "</spine>", "<itemref idref=\"nope\"/></spine>");
+        assert_eq!(messages(&entries), [
+            "OEBPS/content.opf: manifest item gone points at missing file OEBPS/gone.xhtml",
+            "OEBPS/content.opf: spine refers to unknown manifest id nope",
+        ]);
+    }
+
+    #[test]
+    fn finds_unclosed_elements() {
+        let mut entries = fixtures::book_entries(&["<div><p>Uno</p>"]);
+        let chapter = &entries.iter().find(|(name, _)| name == "OEBPS/c1.xhtml").unwrap().1;
+        let position = chapter.find("</body>").unwrap();
+        assert_eq!(messages(&entries), [format!(
+            "OEBPS/c1.xhtml: byte {}: ill-formed document: expected `</div>`, but `</body>` was found",
+            position,
+        )]);
+
+        replace(&mut entries, "OEBPS/c1.xhtml", "<div><p>Uno</p></body></html>", "<p>Uno</p></body>");
+        assert_eq!(messages(&entries), ["OEBPS/c1.xhtml: <html> is never closed"]);
+    }
+
+    #[test]
+    fn finds_missing_link_targets() {
+        let entries = fixtures::book_entries(&[
+            "<p id=\"a\"><a href=\"c2.xhtml#b\">b</a> <a href=\"c2.xhtml#missing\">missing</a> \
+             <a href=\"gone.xhtml\">gone</a> <a href=\"https://example.com/\">web</a> <a href=\"#a\">a</a></p>",
+            "<p id=\"b\">Dos</p>",
+        ]);
+        let chapter = &entries.iter().find(|(name, _)| name == "OEBPS/c1.xhtml").unwrap().1;
+        let position = |href: &str| chapter.find(&format!("<a href=\"{}\"", href)).unwrap();
+        assert_eq!(messages(&entries), [
+            format!("OEBPS/c1.xhtml: byte {}: link to missing id c2.xhtml#missing", position("c2.xhtml#missing")),
+            format!("OEBPS/c1.xhtml: byte {}: link to missing file gone.xhtml", position("gone.xhtml")),
+        ]);
+    }
+
+    #[test]
+    fn tells_external_links() {
+        for href in ["https://example.com/a.xhtml", "mailto:someone@example.com", "data:image/png;base64,AAAA"] {
+            assert!(is_external(href), "{}", href);
+        }
+        for href in ["c1.xhtml", "#a", "text/a:b.xhtml", "c1.xhtml#a:b", "c1.xhtml?q=a:b"] {
+            assert!(!is_external(href), "{}", href);
+        }
+    }
+}